@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use dar::archive::{read_end_record, read_header, read_index_entries_at, read_local_header};
+use libfuzzer_sys::fuzz_target;
+
+// Archive structures are parsed from untrusted bytes and must fail with an
+// error, never panic or allocate without bound
+fuzz_target!(|data: &[u8]| {
+    let mut file = Cursor::new(data);
+    let _ = read_header(&mut file);
+    let _ = read_end_record(&mut file, data.len() as u64);
+    let _ = read_index_entries_at(&mut file, 0, None);
+    let _ = read_local_header(data, None);
+});
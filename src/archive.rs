@@ -1,108 +1,272 @@
-use eyre::{Result, eyre};
+use filetime::{FileTime, set_file_mtime};
 use std::{
-    fs::{File, metadata},
+    fs::{File, create_dir_all, metadata},
     io::{Read, Seek, SeekFrom, Write},
     path::Path,
-    time::SystemTime,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::codec::codec;
+use crate::crypto::{
+    ArchiveKey, DATA_AAD, INDEX_AAD, LOCAL_AAD, NONCE_SIZE, TAG_SIZE, entry_data_aad,
+};
+use crate::error::{Error, Result, io_context, read_failed};
 use crate::models::archive::{
-    ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry, CompressionAlgorithm,
+    ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry, CompressionAlgorithm, IndexTrailer,
+    LocalEntryHeader, SignatureBlock,
+};
+use crate::parser::{
+    ParseError, check_entry_count, check_entry_length, check_path, parse_end_record, parse_entry,
+    parse_header,
 };
+use crate::progress::{Event, ProgressSink};
 
 const CHUNK_SIZE: usize = 512 * 1024; // 512KB
 
+/// Keys applied to entry data while writing: encryption key and checksum MAC key
+#[derive(Clone, Copy, Default)]
+pub struct EntryKeys<'a> {
+    pub cipher: Option<&'a ArchiveKey>,
+    pub mac: Option<&'a [u8; 32]>,
+}
+
+/// Ownership, permissions and modification time stored with an entry, and
+/// its compression (picked by the extension of the entry name when None)
+#[derive(Clone, Copy)]
+pub struct EntryMetadata {
+    pub modification_time: u64,
+    pub uid: u8,
+    pub gid: u8,
+    pub permissions: u16,
+    pub compression: Option<CompressionAlgorithm>,
+}
+
+impl EntryMetadata {
+    /// Metadata of a file on disk
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let fs_meta = metadata(path)?;
+        // Files dated before the epoch are stored as modified at the epoch
+        let modification_time = fs_meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        #[cfg(unix)]
+        let (uid, gid, permissions) = {
+            use std::os::unix::fs::MetadataExt;
+            (
+                (fs_meta.uid() % 256) as u8,
+                (fs_meta.gid() % 256) as u8,
+                (fs_meta.mode() & 0o777) as u16,
+            )
+        };
+
+        #[cfg(not(unix))]
+        let (uid, gid, permissions) = (0u8, 0u8, 0o644u16);
+
+        Ok(Self {
+            modification_time,
+            uid,
+            gid,
+            permissions,
+            compression: None,
+        })
+    }
+}
+
+impl Default for EntryMetadata {
+    /// Regular file modified now, owned by uid/gid 0
+    fn default() -> Self {
+        Self {
+            modification_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            uid: 0,
+            gid: 0,
+            permissions: 0o644,
+            compression: None,
+        }
+    }
+}
+
 /// Read and parse archive header
-pub fn read_header(file: &mut File) -> (Option<ArchiveHeader>, Result<()>) {
+pub fn read_header<R: Read + Seek>(file: &mut R) -> (Option<ArchiveHeader>, Result<()>) {
     file.seek(SeekFrom::Start(0)).ok();
 
-    let mut buf = vec![0u8; 512];
-    match file.read_exact(&mut buf) {
-        Ok(()) => {
-            // Check magic and version
-            if &buf[0..3] != b"DAR" || &buf[4..8] != ArchiveHeader::VERSION {
-                (None, Err(eyre!("Invalid header magic or version")))
-            } else {
-                // Parse header fields (big-endian)
-                let data_section_start = u64::from_be_bytes(buf[8..16].try_into().unwrap());
-                let index_section_start = u64::from_be_bytes(buf[16..24].try_into().unwrap());
-                let total_files = u32::from_be_bytes(buf[24..28].try_into().unwrap());
-
-                let mut archive_checksum = [0u8; 32];
-                archive_checksum.copy_from_slice(&buf[36..68]);
-
-                let header = ArchiveHeader {
-                    data_section_start,
-                    index_section_start,
-                    total_files,
-                    created_timestamp: 0, // Not needed for validation
-                    archive_checksum,
-                };
-
-                (Some(header), Ok(()))
-            }
-        }
-        Err(e) => (None, Err(eyre!("Cannot read header: {}", e))),
+    let mut buf = vec![0u8; ArchiveHeader::SIZE];
+    if let Err(e) = file.read_exact(&mut buf) {
+        return (None, Err(read_failed(e, "header")));
+    }
+
+    match parse_header(&buf) {
+        Ok(header) => (Some(header), Ok(())),
+        Err(e) => (None, Err(e.into())),
     }
 }
 
 /// Read and parse end record
-pub fn read_end_record(file: &mut File, file_size: u64) -> (Option<ArchiveEndRecord>, Result<()>) {
-    if file_size < 64 {
-        return (None, Err(eyre!("File too small for end record")));
-    }
-
-    file.seek(SeekFrom::Start(file_size - 64)).ok();
-
-    let mut buf = vec![0u8; 64];
-    match file.read_exact(&mut buf) {
-        Ok(()) => {
-            // Check magic
-            if &buf[0..4] != b"DEND" {
-                (None, Err(eyre!("Invalid end record magic")))
-            } else {
-                let index_offset = u64::from_be_bytes([
-                    buf[4], buf[5], buf[6], buf[7], buf[8], buf[9], buf[10], buf[11],
-                ]);
-                let index_length = u64::from_be_bytes([
-                    buf[12], buf[13], buf[14], buf[15], buf[16], buf[17], buf[18], buf[19],
-                ]);
-
-                let mut archive_checksum = [0u8; 32];
-                archive_checksum.copy_from_slice(&buf[20..52]);
-
-                let end_record = ArchiveEndRecord {
-                    index_offset,
-                    index_length,
-                    archive_checksum,
-                };
-
-                (Some(end_record), Ok(()))
+pub fn read_end_record<R: Read + Seek>(
+    file: &mut R,
+    file_size: u64,
+) -> (Option<ArchiveEndRecord>, Result<()>) {
+    if file_size < ArchiveEndRecord::SIZE as u64 {
+        return (
+            None,
+            Err(ParseError::Truncated {
+                field: "end record",
+            }
+            .into()),
+        );
+    }
+
+    file.seek(SeekFrom::Start(file_size - ArchiveEndRecord::SIZE as u64))
+        .ok();
+
+    let mut buf = vec![0u8; ArchiveEndRecord::SIZE];
+    if let Err(e) = file.read_exact(&mut buf) {
+        return (None, Err(read_failed(e, "end record")));
+    }
+
+    match parse_end_record(&buf, file_size) {
+        Ok(end_record) => (Some(end_record), Ok(())),
+        Err(e) => (None, Err(e.into())),
+    }
+}
+
+/// Current index of an archive, see `read_tail`
+pub struct ArchiveTail {
+    /// End record of the archive, or one describing the last complete index
+    /// when the tail is torn
+    pub end_record: ArchiveEndRecord,
+    /// An interrupted append left incomplete data after the last complete index
+    pub torn: bool,
+}
+
+impl ArchiveTail {
+    pub fn index_end(&self) -> u64 {
+        self.end_record.index_offset + self.end_record.index_length
+    }
+
+    /// An append stopped before completing: the tail is torn, or the header was
+    /// not moved to the new index yet, or the checksum (stored last) is missing
+    pub fn is_interrupted(&self, header: &ArchiveHeader) -> bool {
+        self.torn
+            || header.index_section_start != self.end_record.index_offset
+            || self.end_record.archive_checksum == [0u8; 32]
+    }
+}
+
+/// Find the current index through the end record. Appends write the end record
+/// only once everything before it is on disk and move the header to the new
+/// index last, so when the end record is missing or broken the header still
+/// points to the last complete index.
+pub fn read_tail<R: Read + Seek>(
+    file: &mut R,
+    header: &ArchiveHeader,
+    file_size: u64,
+) -> Result<ArchiveTail> {
+    if let (Some(end_record), Ok(())) = read_end_record(file, file_size)
+        && end_record.index_offset >= header.data_section_start
+    {
+        return Ok(ArchiveTail {
+            end_record,
+            torn: false,
+        });
+    }
+
+    let index_offset = header.index_section_start;
+    let index_length =
+        index_extent(file, index_offset, header.is_encrypted(), file_size).map_err(|e| {
+            Error::Inconsistent(format!(
+                "End record is missing and the index is incomplete: {}",
+                e
+            ))
+        })?;
+
+    let mut end_record = ArchiveEndRecord::new(index_offset, index_length);
+    end_record.archive_checksum = header.archive_checksum;
+    Ok(ArchiveTail {
+        end_record,
+        torn: true,
+    })
+}
+
+/// Length of the index section at `offset` including its trailer, without
+/// decrypting it; fails when the index runs past `limit`
+fn index_extent<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    sealed: bool,
+    limit: u64,
+) -> Result<u64> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut end = if sealed {
+        let mut buf = [0u8; 8];
+        file.read_exact(&mut buf)?;
+        (offset + 8).saturating_add(u64::from_be_bytes(buf))
+    } else {
+        let mut buf = [0u8; 4];
+        file.read_exact(&mut buf)?;
+        let mut end = offset + 4;
+        for _ in 0..u32::from_be_bytes(buf) {
+            if end + 4 > limit {
+                break;
             }
+            file.seek(SeekFrom::Start(end))?;
+            file.read_exact(&mut buf)?;
+            end += 4 + u32::from_be_bytes(buf) as u64;
         }
-        Err(e) => (None, Err(eyre!("Cannot read end record: {}", e))),
+        end
+    };
+
+    if end > limit {
+        return Err(Error::CorruptIndex {
+            entry: None,
+            reason: format!("index at {} runs past the end of the file", offset),
+        });
     }
+
+    // Indexes written by older versions have no trailer
+    if end + IndexTrailer::SIZE as u64 <= limit {
+        let mut buf = [0u8; IndexTrailer::SIZE];
+        file.seek(SeekFrom::Start(end))?;
+        file.read_exact(&mut buf)?;
+        if IndexTrailer::from_bytes(&buf).is_some() {
+            end += IndexTrailer::SIZE as u64;
+        }
+    }
+
+    Ok(end - offset)
 }
 
 /// Calculate archive checksum (BLAKE3 of entire file excluding checksum fields)
-pub fn calculate_archive_checksum(
-    file: &mut File,
-    _header: &ArchiveHeader,
+/// With a MAC key (archives flagged FLAG_MAC) BLAKE3 keyed mode is used instead
+/// The recovery section is not covered: it protects the checksummed bytes itself
+pub fn calculate_archive_checksum<R: Read + Seek>(
+    file: &mut R,
+    header: &ArchiveHeader,
     file_size: u64,
+    mac_key: Option<&[u8; 32]>,
 ) -> Result<[u8; 32]> {
     file.seek(SeekFrom::Start(0))?;
 
-    let mut hasher = blake3::Hasher::new();
+    let mut hasher = new_hasher(mac_key);
 
-    // Read header (but skip checksum field at offset 36-68)
+    // Read header (but skip checksum and signature fields)
     let mut buf = vec![0u8; 512];
     file.read_exact(&mut buf)?;
-    hasher.update(&buf[0..36]); // up to checksum
-    hasher.update(&[0u8; 32]); // skip checksum
-    hasher.update(&buf[68..]); // after checksum
+    hash_header(&mut hasher, &buf);
 
-    // Read data and index sections (exclude end record which is the last 64 bytes)
-    let remaining = file_size - 512 - 64; // exclude header and end record
+    // Read data and index sections (exclude recovery section and end record which is the last 64 bytes)
+    let protected_end = if header.recovery_offset > 0 {
+        header.recovery_offset
+    } else {
+        file_size - 64
+    };
+    let remaining = protected_end.saturating_sub(512); // exclude header and end record
     let mut buf = vec![0u8; 65536]; // 64KB chunks
     let mut total_read = 0u64;
 
@@ -130,152 +294,390 @@ pub fn calculate_archive_checksum(
     Ok(result)
 }
 
-/// Parse single index entry
-pub fn parse_index_entry(file: &mut File) -> Result<ArchiveIndexEntry> {
-    let mut buf = [0u8; 4];
-    file.read_exact(&mut buf)?;
-    let entry_length = u32::from_be_bytes(buf) as usize;
+/// Calculate checksum of an archive built in memory (header up to end record included)
+/// and store it in both the header and the end record
+pub fn write_archive_checksum(
+    archive_bytes: &mut [u8],
+    end_record_offset: usize,
+    mac_key: Option<&[u8; 32]>,
+) -> [u8; 32] {
+    // Skip bytes 36-67 in header (where archive_checksum is stored) and the signature block
+    let mut hasher = new_hasher(mac_key);
+    hash_header(&mut hasher, &archive_bytes[0..ArchiveHeader::SIZE]); // without checksum and signature
+    hasher.update(&archive_bytes[ArchiveHeader::SIZE..end_record_offset]); // rest up to end record
 
-    let mut entry_buf = vec![0u8; entry_length];
-    file.read_exact(&mut entry_buf)?;
+    // Also skip checksum in end record (bytes 20-51 within the 64-byte end record)
+    hasher.update(&archive_bytes[end_record_offset..end_record_offset + 20]); // magic + offsets
+    hasher.update(&[0u8; 32]); // skip checksum in end record
+    hasher
+        .update(&archive_bytes[end_record_offset + 52..end_record_offset + ArchiveEndRecord::SIZE]); // rest of end record
+
+    let mut checksum = [0u8; 32];
+    checksum.copy_from_slice(hasher.finalize().as_bytes());
 
-    let mut offset = 0;
+    // Bytes 36-68: archive_checksum in header
+    archive_bytes[36..68].copy_from_slice(&checksum);
+    // Checksum in end record is at offset 20-52 within the 64-byte end record
+    archive_bytes[end_record_offset + 20..end_record_offset + 52].copy_from_slice(&checksum);
 
-    // Path length and path
-    let path_len = u32::from_be_bytes(entry_buf[offset..offset + 4].try_into().unwrap()) as usize;
-    offset += 4;
+    checksum
+}
 
-    let path = String::from_utf8(entry_buf[offset..offset + path_len].to_vec())?;
-    offset += path_len;
+/// Position reader at the start of the index section (entry count)
+/// Encrypted indexes are decrypted into memory first
+pub fn open_index<'a, R: Read + Seek>(
+    file: &'a mut R,
+    index_offset: u64,
+    key: Option<&ArchiveKey>,
+) -> Result<Box<dyn Read + 'a>> {
+    file.seek(SeekFrom::Start(index_offset))?;
 
-    // Metadata
-    let data_offset = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-    offset += 8;
+    match key {
+        Some(key) => {
+            let mut buf = [0u8; 8];
+            file.read_exact(&mut buf)?;
+            let sealed_length = u64::from_be_bytes(buf);
+            if !fits_in_file(file, index_offset + 8, sealed_length)? {
+                return Err(Error::CorruptIndex {
+                    entry: None,
+                    reason: format!(
+                        "sealed index of {} bytes at {} runs past the end of the archive",
+                        sealed_length, index_offset
+                    ),
+                });
+            }
 
-    let uncompressed_size = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-    offset += 8;
+            let mut sealed = vec![0u8; sealed_length as usize];
+            file.read_exact(&mut sealed)?;
 
-    let compressed_size = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-    offset += 8;
+            let index = key.open(&sealed, INDEX_AAD).map_err(|_| Error::Decrypt {
+                what: "index".to_string(),
+            })?;
+            Ok(Box::new(std::io::Cursor::new(index)))
+        }
+        None => Ok(Box::new(file)),
+    }
+}
 
-    let compression_algorithm = CompressionAlgorithm::try_from(entry_buf[offset]).unwrap();
-    offset += 1;
+/// Read entry count at the start of an index, checked against its sanity limit
+pub fn read_entry_count<R: Read + ?Sized>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|e| Error::CorruptIndex {
+            entry: None,
+            reason: format!("cannot read entry count: {}", e),
+        })?;
+    check_entry_count(u32::from_be_bytes(buf)).map_err(|e| Error::CorruptIndex {
+        entry: None,
+        reason: e.to_string(),
+    })
+}
 
-    let modification_time = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-    offset += 8;
+/// Whether `length` bytes starting at `offset` are within the archive, checked
+/// before allocating a buffer of an untrusted length
+fn fits_in_file<R: Read + Seek>(file: &mut R, offset: u64, length: u64) -> Result<bool> {
+    let position = file.stream_position()?;
+    let file_size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(position))?;
 
-    let uid = entry_buf[offset];
-    offset += 1;
-    let gid = entry_buf[offset];
-    offset += 1;
+    Ok(offset.saturating_add(length) <= file_size)
+}
 
-    let permissions = u16::from_be_bytes([entry_buf[offset], entry_buf[offset + 1]]);
-    offset += 2;
+/// Read all entries of the index section at given offset (current or superseded index)
+pub fn read_index_entries_at<R: Read + Seek>(
+    file: &mut R,
+    index_offset: u64,
+    key: Option<&ArchiveKey>,
+) -> Result<Vec<ArchiveIndexEntry>> {
+    let mut index = open_index(file, index_offset, key)?;
+    let entry_count = read_entry_count(&mut index)?;
 
-    let mut checksum = [0u8; 32];
-    checksum.copy_from_slice(&entry_buf[offset..offset + 32]);
-
-    Ok(ArchiveIndexEntry {
-        path,
-        data_offset,
-        uncompressed_size,
-        compressed_size,
-        compression_algorithm,
-        modification_time,
-        uid,
-        gid,
-        permissions,
-        checksum,
-    })
+    (0..entry_count as usize)
+        .map(|entry| {
+            parse_index_entry(&mut index).map_err(|e| Error::CorruptIndex {
+                entry: Some(entry),
+                reason: e.to_string(),
+            })
+        })
+        .collect()
 }
 
-pub fn add_file(
-    path: &Path,
-    archive_bytes: &mut Vec<u8>,
-    progress: bool,
-) -> Result<ArchiveIndexEntry> {
-    let fs_meta = metadata(path)?;
-    let file_size = fs_meta.len() as usize;
-    let algorithm = get_compression_algorithm(path);
-    let mut data = Vec::new();
-    let timestamp = fs_meta
-        .modified()?
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
+/// Index section of one generation of the archive (creation or an append)
+pub struct Generation {
+    pub index_offset: u64,
+    pub index_length: u64,
+    pub created: u64,
+}
 
-    #[cfg(unix)]
-    let (uid, gid, perm) = {
-        use std::os::unix::fs::MetadataExt;
-        (
-            (fs_meta.uid() % 256) as u8,
-            (fs_meta.gid() % 256) as u8,
-            (fs_meta.mode() & 0o777) as u16,
-        )
-    };
+/// All index generations, oldest first, found by following index trailers back
+/// from the current index. An oldest index without trailer (written by an older
+/// version) is dated with the archive's creation time.
+pub fn read_generations<R: Read + Seek>(
+    file: &mut R,
+    header: &ArchiveHeader,
+    end_record: &ArchiveEndRecord,
+) -> Result<Vec<Generation>> {
+    let mut generations = Vec::new();
+    let (mut offset, mut length) = (end_record.index_offset, end_record.index_length);
 
-    #[cfg(not(unix))]
-    let (uid, gid, perm) = (0u8, 0u8, 0o644u16);
+    loop {
+        let trailer = if length >= IndexTrailer::SIZE as u64 {
+            let mut buf = [0u8; IndexTrailer::SIZE];
+            file.seek(SeekFrom::Start(offset + length - IndexTrailer::SIZE as u64))?;
+            file.read_exact(&mut buf)
+                .map_err(|e| read_failed(e, "index trailer"))?;
+            IndexTrailer::from_bytes(&buf)
+        } else {
+            None
+        };
 
-    if file_size > CHUNK_SIZE {
-        // Large file: read in chunks, calculate checksum, compress, then write
-        let mut file = File::open(path)?;
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let mut bytes_read_total = 0usize;
+        let Some(trailer) = trailer else {
+            generations.push(Generation {
+                index_offset: offset,
+                index_length: length,
+                created: header.created_timestamp,
+            });
+            break;
+        };
 
-        loop {
-            let bytes_read = std::io::Read::read(&mut file, &mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
+        generations.push(Generation {
+            index_offset: offset,
+            index_length: length,
+            created: trailer.created,
+        });
 
-            data.extend_from_slice(&buffer[..bytes_read]);
-            bytes_read_total += bytes_read;
-
-            if progress {
-                let percentage = (bytes_read_total as f64 / file_size as f64) * 100.0;
-                eprint!(
-                    "\r  {}: {:.1}% ({}/{}B)",
-                    path.display(),
-                    percentage,
-                    bytes_read_total,
-                    file_size
-                );
+        if trailer.previous_offset == 0 {
+            break;
+        }
+        // Earlier indexes always precede later ones, anything else is damage
+        if trailer.previous_length > offset.saturating_sub(trailer.previous_offset) {
+            return Err(ParseError::OutOfBounds {
+                field: "Previous index offset",
+                value: trailer.previous_offset,
             }
+            .into());
         }
+        (offset, length) = (trailer.previous_offset, trailer.previous_length);
+    }
 
-        if progress {
-            eprintln!(); // newline after progress
+    generations.reverse();
+    Ok(generations)
+}
+
+/// Write index section, sealing it when the archive is encrypted
+pub fn write_index(
+    index: &[u8],
+    archive_bytes: &mut Vec<u8>,
+    key: Option<&ArchiveKey>,
+) -> Result<()> {
+    match key {
+        Some(key) => {
+            let sealed = key.seal(index, INDEX_AAD)?;
+            archive_bytes.write_all(&(sealed.len() as u64).to_be_bytes())?;
+            archive_bytes.write_all(&sealed)?;
         }
-    } else {
-        // Small file: read all at once
-        data = std::fs::read(path)?;
+        None => archive_bytes.write_all(index)?,
     }
+    Ok(())
+}
 
-    let hash = blake3::hash(&data);
-    let mut checksum = [0u8; 32];
-    checksum.copy_from_slice(hash.as_bytes());
+/// Write local entry header, sealed like the index when the archive is encrypted
+pub fn write_local_header(
+    entry: &ArchiveIndexEntry,
+    buf: &mut Vec<u8>,
+    key: Option<&ArchiveKey>,
+) -> Result<()> {
+    let mut body = Vec::new();
+    entry.write_to(&mut body)?;
+    if let Some(key) = key {
+        body = key.seal(&body, LOCAL_AAD)?;
+    }
+
+    buf.write_all(LocalEntryHeader::MAGIC)?;
+    buf.write_all(&(body.len() as u32).to_be_bytes())?;
+    buf.write_all(&body)?;
+    buf.write_all(blake3::hash(&body).as_bytes())?;
+    Ok(())
+}
+
+/// Parse local entry header at the start of `bytes`, returns entry and header size
+pub fn read_local_header(
+    bytes: &[u8],
+    key: Option<&ArchiveKey>,
+) -> Result<(ArchiveIndexEntry, usize)> {
+    if bytes.len() < LocalEntryHeader::FIXED_SIZE || &bytes[0..4] != LocalEntryHeader::MAGIC {
+        return Err(Error::InvalidMagic {
+            structure: "local entry header",
+        });
+    }
+
+    let body_len = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let size = LocalEntryHeader::FIXED_SIZE + body_len;
+    if bytes.len() < size {
+        return Err(ParseError::Truncated {
+            field: "local entry header",
+        }
+        .into());
+    }
+
+    let body = &bytes[8..8 + body_len];
+    if blake3::hash(body).as_bytes() != &bytes[8 + body_len..size] {
+        return Err(Error::ChecksumMismatch {
+            path: "local entry header".to_string(),
+        });
+    }
+
+    let body = match key {
+        Some(key) => key.open(body, LOCAL_AAD).map_err(|_| Error::Decrypt {
+            what: "local entry header".to_string(),
+        })?,
+        None => body.to_vec(),
+    };
+    let entry = parse_index_entry(&mut body.as_slice())?;
+
+    Ok((entry, size))
+}
+
+/// Checksum hasher: plain BLAKE3, or keyed BLAKE3 when archive uses MACs
+pub fn new_hasher(mac_key: Option<&[u8; 32]>) -> blake3::Hasher {
+    match mac_key {
+        Some(key) => blake3::Hasher::new_keyed(key),
+        None => blake3::Hasher::new(),
+    }
+}
+
+/// Checksum of entry's uncompressed data
+pub fn hash_data(data: &[u8], mac_key: Option<&[u8; 32]>) -> [u8; 32] {
+    let hash = match mac_key {
+        Some(key) => blake3::keyed_hash(key, data),
+        None => blake3::hash(data),
+    };
+    *hash.as_bytes()
+}
+
+/// Feed header into archive checksum with the checksum field (36-68) and
+/// the signature block zeroed, so both can be filled in after hashing
+pub fn hash_header(hasher: &mut blake3::Hasher, header: &[u8]) {
+    let signature_end = SignatureBlock::OFFSET + SignatureBlock::SIZE;
+
+    hasher.update(&header[0..36]); // up to checksum
+    hasher.update(&[0u8; 32]); // skip checksum
+    hasher.update(&header[68..SignatureBlock::OFFSET]); // up to signature
+    hasher.update(&[0u8; SignatureBlock::SIZE]); // skip signature
+    hasher.update(&header[signature_end..ArchiveHeader::SIZE]);
+}
+
+/// Parse single index entry
+pub fn parse_index_entry<R: Read + ?Sized>(reader: &mut R) -> Result<ArchiveIndexEntry> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    let entry_length = check_entry_length(u32::from_be_bytes(buf))?;
+
+    let mut entry_buf = vec![0u8; entry_length];
+    reader.read_exact(&mut entry_buf)?;
+
+    Ok(parse_entry(&entry_buf)?)
+}
+
+/// Read file to memory, reporting progress of large files
+fn read_file(path: &Path, name: &str, sink: &mut dyn ProgressSink) -> Result<Vec<u8>> {
+    let file_size = metadata(path)?.len() as usize;
+    if file_size <= CHUNK_SIZE {
+        return Ok(std::fs::read(path)?);
+    }
+
+    // Large file: read in chunks
+    let mut data = Vec::with_capacity(file_size);
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..bytes_read]);
+
+        sink.event(&Event::BytesProcessed {
+            name,
+            done: data.len() as u64,
+            size: file_size as u64,
+        });
+    }
 
-    let compressed_data = compress_data(&data, algorithm)?;
-    let compressed_size = compressed_data.len() as u64;
+    Ok(data)
+}
+
+/// Add data as a complete entry (local header, length and stored data) to `buf`
+/// `current_offset` is the data section offset at which the entry starts
+pub fn encode_entry(
+    name: &str,
+    data: &[u8],
+    entry_metadata: &EntryMetadata,
+    buf: &mut Vec<u8>,
+    current_offset: u64,
+    keys: EntryKeys,
+) -> Result<ArchiveIndexEntry> {
+    // Readers reject paths that could leave the extraction directory
+    check_path(name)?;
 
-    archive_bytes.write_all(&compressed_size.to_be_bytes())?;
-    archive_bytes.write_all(&compressed_data)?;
+    let algorithm = entry_metadata
+        .compression
+        .unwrap_or_else(|| get_compression_algorithm(Path::new(name)));
 
-    Ok(ArchiveIndexEntry {
-        path: "".to_string(),
+    // Compress first: ciphertext is incompressible
+    let compressed = compress_data(data, algorithm)?;
+    let seal_overhead = match keys.cipher {
+        Some(_) => NONCE_SIZE + TAG_SIZE,
+        None => 0,
+    };
+
+    let mut entry = ArchiveIndexEntry {
+        path: name.to_string(),
         data_offset: 0,
-        uncompressed_size: file_size as u64,
-        compressed_size: compressed_size,
+        uncompressed_size: data.len() as u64,
+        compressed_size: (compressed.len() + seal_overhead) as u64,
         compression_algorithm: algorithm,
-        modification_time: timestamp,
-        uid: uid,
-        gid: gid,
-        permissions: perm,
-        checksum: checksum,
-    })
+        modification_time: entry_metadata.modification_time,
+        uid: entry_metadata.uid,
+        gid: entry_metadata.gid,
+        permissions: entry_metadata.permissions,
+        checksum: hash_data(data, keys.mac),
+        flags: 0,
+        data_aad: None,
+    };
+
+    // Local header goes first, data offset points past it to the entry length
+    // The AAD has a fixed size, so the header size is known before it is made
+    if keys.cipher.is_some() {
+        entry.data_aad = Some(entry_data_aad(name, 0, 0, 0));
+    }
+    entry.data_offset =
+        current_offset + LocalEntryHeader::size(&entry, keys.cipher.is_some()) as u64;
+    let stored = match keys.cipher {
+        Some(key) => {
+            let aad = entry_data_aad(
+                name,
+                entry.data_offset,
+                entry.uncompressed_size,
+                entry.compressed_size,
+            );
+            let sealed = key.seal(&compressed, &aad)?;
+            entry.data_aad = Some(aad);
+            sealed
+        }
+        None => compressed,
+    };
+    write_local_header(&entry, buf, keys.cipher)?;
+    buf.write_all(&entry.compressed_size.to_be_bytes())?;
+    buf.write_all(&stored)?;
+
+    Ok(entry)
 }
 
-fn get_compression_algorithm(path: &Path) -> CompressionAlgorithm {
+/// Compression suited to a file, by its extension
+pub fn get_compression_algorithm(path: &Path) -> CompressionAlgorithm {
     if let Some(ext) = path.extension() {
         let ext = ext.to_string_lossy().to_lowercase();
         match ext.as_str() {
@@ -309,62 +711,370 @@ fn get_compression_algorithm(path: &Path) -> CompressionAlgorithm {
     }
 }
 
-pub fn compress_data(data: &Vec<u8>, algorithm: CompressionAlgorithm) -> Result<Vec<u8>> {
-    return match algorithm {
-        CompressionAlgorithm::None => Ok(data.clone()),
-        CompressionAlgorithm::Brotli => Ok(compress_brotli(data)?),
-        CompressionAlgorithm::Zstandard => Ok(compress_zstandard(data)?),
-        CompressionAlgorithm::Lzma => Ok(compress_lzma(data)?),
+/// Add file as a complete data entry named `name` to `buf`, reporting to `sink`
+/// `current_offset` is the data section offset at which the entry starts
+pub fn write_entry(
+    path: &Path,
+    name: &str,
+    buf: &mut Vec<u8>,
+    current_offset: u64,
+    keys: EntryKeys,
+    compression: Option<CompressionAlgorithm>,
+    sink: &mut dyn ProgressSink,
+) -> Result<ArchiveIndexEntry> {
+    let result = store_file(path, name, buf, current_offset, keys, compression, sink);
+    if let Err(ref error) = result {
+        sink.event(&Event::Error { name, error });
+    }
+    result
+}
+
+fn store_file(
+    path: &Path,
+    name: &str,
+    buf: &mut Vec<u8>,
+    current_offset: u64,
+    keys: EntryKeys,
+    compression: Option<CompressionAlgorithm>,
+    sink: &mut dyn ProgressSink,
+) -> Result<ArchiveIndexEntry> {
+    let entry_metadata = EntryMetadata {
+        compression,
+        ..EntryMetadata::from_path(path)?
     };
+    sink.event(&Event::FileStarted {
+        name,
+        source: Some(path),
+        size: metadata(path)?.len(),
+    });
+
+    let data = read_file(path, name, sink)?;
+    let entry = encode_entry(name, &data, &entry_metadata, buf, current_offset, keys)?;
+
+    sink.event(&Event::FileFinished {
+        name,
+        source: Some(path),
+        size: entry.uncompressed_size,
+        stored_size: entry.compressed_size,
+        algorithm: entry.compression_algorithm,
+    });
+
+    Ok(entry)
 }
 
-fn compress_brotli(data: &[u8]) -> Result<Vec<u8>> {
+/// Compress with the registered codec of `algorithm`
+pub fn compress_data(data: &[u8], algorithm: CompressionAlgorithm) -> Result<Vec<u8>> {
+    let codec = codec(algorithm)?;
     let mut output = Vec::new();
-    let mut params = brotli::enc::BrotliEncoderParams::default();
-    params.quality = 11; // Maximum quality
-    params.lgwin = 24; // Larger window size for better compression
-    brotli::BrotliCompress(&mut std::io::Cursor::new(data), &mut output, &params)
-        .map_err(|e| eyre!("Brotli compression error: {}", e))?;
+    codec
+        .encode(&mut { data }, &mut output)
+        .map_err(|e| io_context(e, format!("{} compression", codec.name())))?;
     Ok(output)
 }
 
-fn compress_zstandard(data: &[u8]) -> Result<Vec<u8>> {
-    zstd::encode_all(std::io::Cursor::new(data), 19) // Level 19 for better compression
-        .map_err(|e| eyre!("Zstandard compression error: {}", e))
+/// Read, decrypt and decompress data of an entry
+pub fn read_entry_data<R: Read + Seek>(
+    archive_file: &mut R,
+    data_section_start: u64,
+    entry: &ArchiveIndexEntry,
+    key: Option<&ArchiveKey>,
+) -> Result<Vec<u8>> {
+    let corrupt = |reason: String| Error::CorruptEntry {
+        path: entry.path.clone(),
+        reason,
+    };
+
+    // Read compressed data from archive
+    archive_file
+        .seek(SeekFrom::Start(data_section_start + entry.data_offset))
+        .map_err(|e| io_context(e, format!("Failed to seek to data of {}", entry.path)))?;
+
+    if !fits_in_file(
+        archive_file,
+        data_section_start + entry.data_offset + 8,
+        entry.compressed_size,
+    )? {
+        return Err(corrupt(format!(
+            "its {} bytes at {} run past the end of the archive",
+            entry.compressed_size, entry.data_offset
+        )));
+    }
+
+    // Read entry length prefix (8 bytes)
+    let mut entry_size_buf = [0u8; 8];
+    archive_file
+        .read_exact(&mut entry_size_buf)
+        .map_err(|e| io_context(e, format!("Failed to read data size of {}", entry.path)))?;
+    let stored_size = u64::from_be_bytes(entry_size_buf);
+    if stored_size != entry.compressed_size {
+        return Err(corrupt(format!(
+            "stored size is {} but its index entry says {}",
+            stored_size, entry.compressed_size
+        )));
+    }
+
+    // Read compressed data
+    let mut compressed_data = vec![0u8; entry.compressed_size as usize];
+    archive_file
+        .read_exact(&mut compressed_data)
+        .map_err(|e| io_context(e, format!("Failed to read data of {}", entry.path)))?;
+
+    // Decrypt and decompress data
+    let compressed_data = unseal_data(compressed_data, entry, key).map_err(|_| Error::Decrypt {
+        what: entry.path.clone(),
+    })?;
+    let uncompressed_data = decompress_data(compressed_data, entry)?;
+
+    // Verify uncompressed size matches
+    if uncompressed_data.len() as u64 != entry.uncompressed_size {
+        return Err(corrupt(format!(
+            "decompressed size is {} but its index entry says {}",
+            uncompressed_data.len(),
+            entry.uncompressed_size
+        )));
+    }
+
+    Ok(uncompressed_data)
 }
 
-fn compress_lzma(data: &[u8]) -> Result<Vec<u8>> {
-    use std::io::Write;
-    let mut output = Vec::new();
-    let mut encoder = xz2::write::XzEncoder::new(&mut output, 9); // Maximum compression
-    encoder.write_all(data)?;
-    encoder.finish()?;
-    Ok(output)
+/// Check data of an entry against its checksum (keyed with `mac_key` if given)
+pub fn verify_entry_data(
+    data: &[u8],
+    entry: &ArchiveIndexEntry,
+    mac_key: Option<&[u8; 32]>,
+) -> Result<()> {
+    if hash_data(data, mac_key) != entry.checksum {
+        return Err(Error::ChecksumMismatch {
+            path: entry.path.clone(),
+        });
+    }
+    Ok(())
 }
 
-pub fn decompress_data(compressed_data: Vec<u8>, entry: &ArchiveIndexEntry) -> Result<Vec<u8>> {
-    return match entry.compression_algorithm {
-        CompressionAlgorithm::None => Ok(compressed_data),
-        CompressionAlgorithm::Brotli => {
-            let mut decompressed = Vec::new();
-            brotli::BrotliDecompress(
-                &mut std::io::Cursor::new(&compressed_data),
-                &mut decompressed,
+/// Write extracted entry below output directory and restore its modification time
+pub fn restore_file(out_dir: &str, entry: &ArchiveIndexEntry, data: &[u8]) -> Result<()> {
+    // Construct output file path
+    let output_file_path = Path::new(out_dir).join(&entry.path);
+
+    // Create parent directories
+    if let Some(parent) = output_file_path.parent() {
+        create_dir_all(parent).map_err(|e| {
+            io_context(
+                e,
+                format!("Failed to create directories for {}", entry.path),
             )
-            .map_err(|e| eyre!("Failed to decompress {} with Brotli: {}", entry.path, e))?;
-            return Ok(decompressed);
+        })?;
+    }
+
+    // Write file
+    let mut output_file = File::create(&output_file_path)
+        .map_err(|e| io_context(e, format!("Failed to create output file {}", entry.path)))?;
+    output_file
+        .write_all(data)
+        .map_err(|e| io_context(e, format!("Failed to write to output file {}", entry.path)))?;
+
+    // Set modification time using filetime
+    #[cfg(unix)]
+    {
+        if let Some(mtime) = UNIX_EPOCH.checked_add(Duration::from_secs(entry.modification_time)) {
+            let filetime = FileTime::from_system_time(mtime);
+            let _ = set_file_mtime(&output_file_path, filetime);
         }
-        CompressionAlgorithm::Zstandard => {
-            let decompressed = zstd::decode_all(std::io::Cursor::new(&compressed_data))
-                .map_err(|e| eyre!("Failed to decompress {} with Zstandard: {}", entry.path, e))?;
-            return Ok(decompressed);
+    }
+
+    Ok(())
+}
+
+/// Decrypt stored data of `entry` if the archive is encrypted
+pub fn unseal_data(
+    stored: Vec<u8>,
+    entry: &ArchiveIndexEntry,
+    key: Option<&ArchiveKey>,
+) -> Result<Vec<u8>> {
+    match key {
+        Some(key) => Ok(key.open(&stored, entry.data_aad.as_deref().unwrap_or(DATA_AAD))?),
+        None => Ok(stored),
+    }
+}
+
+/// Decompress data of an entry with the registered codec it was stored with
+pub fn decompress_data(compressed_data: Vec<u8>, entry: &ArchiveIndexEntry) -> Result<Vec<u8>> {
+    let codec = codec(entry.compression_algorithm)?;
+    // A small payload can decode to any size, stop at the one the index records
+    let mut decompressed = CappedBuffer {
+        data: Vec::new(),
+        limit: entry.uncompressed_size,
+    };
+    codec
+        .decode(&mut compressed_data.as_slice(), &mut decompressed)
+        .map_err(|e| Error::Decompress {
+            algo: entry.compression_algorithm,
+            path: entry.path.clone(),
+            reason: e.to_string(),
+        })?;
+    Ok(decompressed.data)
+}
+
+/// Output of a decoder that fails once it grows past `limit` bytes
+struct CappedBuffer {
+    data: Vec<u8>,
+    limit: u64,
+}
+
+impl Write for CappedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.data.len() as u64 + buf.len() as u64 > self.limit {
+            return Err(std::io::Error::other(format!(
+                "data is larger than its recorded size of {} bytes",
+                self.limit
+            )));
         }
-        CompressionAlgorithm::Lzma => {
-            let mut decompressed = Vec::new();
-            xz2::read::XzDecoder::new(std::io::Cursor::new(&compressed_data))
-                .read_to_end(&mut decompressed)
-                .map_err(|e| eyre!("Failed to decompress {} with LZMA: {}", entry.path, e))?;
-            return Ok(decompressed);
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::crypto::{KeySource, require_mac_key};
+    use crate::writer::ArchiveWriter;
+
+    /// Index with no entries at 512 whose trailer links to `previous`
+    fn archive_with_trailer(previous_offset: u64, previous_length: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; 512];
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        IndexTrailer::new(previous_offset, previous_length)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn generations(bytes: Vec<u8>, index_length: u64) -> Result<Vec<Generation>> {
+        let header = ArchiveHeader::new(512, 512, 0);
+        let end_record = ArchiveEndRecord::new(512, index_length);
+        read_generations(&mut Cursor::new(bytes), &header, &end_record)
+    }
+
+    #[test]
+    fn first_generation_has_no_previous_index() {
+        let bytes = archive_with_trailer(0, 0);
+        let length = bytes.len() as u64 - 512;
+        assert_eq!(generations(bytes, length).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn oversized_previous_index_is_malformed() {
+        for (offset, length) in [(100, u64::MAX), (100, 413), (600, 10), (u64::MAX, 1)] {
+            let bytes = archive_with_trailer(offset, length);
+            let index_length = bytes.len() as u64 - 512;
+            assert!(
+                matches!(generations(bytes, index_length), Err(Error::Malformed(_))),
+                "{offset} {length}"
+            );
         }
-    };
+    }
+
+    #[test]
+    fn truncated_index_trailer_is_malformed() {
+        let mut bytes = archive_with_trailer(0, 0);
+        let index_length = bytes.len() as u64 - 512;
+        bytes.truncate(bytes.len() - 10);
+        assert!(matches!(
+            generations(bytes, index_length),
+            Err(Error::Malformed(ParseError::Truncated { .. }))
+        ));
+    }
+
+    #[test]
+    fn truncated_header_and_end_record_are_malformed() {
+        let mut bytes = Vec::new();
+        ArchiveHeader::new(512, 512, 0)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes.truncate(300);
+        let (header, result) = read_header(&mut Cursor::new(&bytes));
+        assert!(header.is_none());
+        assert!(matches!(result, Err(Error::Malformed(_))));
+
+        let (end_record, result) = read_end_record(&mut Cursor::new(&bytes[..40]), 40);
+        assert!(end_record.is_none());
+        assert!(matches!(result, Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn data_larger_than_recorded_size_is_rejected() {
+        let data = vec![0u8; 1 << 20];
+        let mut entry = ArchiveIndexEntry::tombstone("zeros.bin".to_string());
+        entry.compression_algorithm = CompressionAlgorithm::Zstandard;
+        let compressed = compress_data(&data, entry.compression_algorithm).unwrap();
+
+        entry.uncompressed_size = data.len() as u64;
+        assert_eq!(decompress_data(compressed.clone(), &entry).unwrap(), data);
+
+        entry.uncompressed_size = 1000;
+        assert!(matches!(
+            decompress_data(compressed, &entry),
+            Err(Error::Decompress { .. })
+        ));
+    }
+
+    /// Archive with keyed checksums and a single entry
+    fn mac_archive(mac_key: [u8; 32]) -> Vec<u8> {
+        let mut writer = ArchiveWriter::builder().mac_key(mac_key).build().unwrap();
+        writer
+            .add_bytes("a.txt", b"keyed contents", EntryMetadata::default())
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn mac_archive_checksum_needs_its_key() {
+        let bytes = mac_archive([1u8; 32]);
+        let mut file = Cursor::new(&bytes);
+        let header = read_header(&mut file).0.unwrap();
+        assert!(header.is_authenticated());
+
+        let size = bytes.len() as u64;
+        let checksum = |key: Option<&[u8; 32]>| {
+            calculate_archive_checksum(&mut Cursor::new(&bytes), &header, size, key).unwrap()
+        };
+        assert_eq!(checksum(Some(&[1u8; 32])), header.archive_checksum);
+        assert_ne!(checksum(Some(&[2u8; 32])), header.archive_checksum);
+        assert_ne!(checksum(None), header.archive_checksum);
+    }
+
+    #[test]
+    fn mac_entry_checksum_needs_its_key() {
+        let bytes = mac_archive([1u8; 32]);
+        let mut file = Cursor::new(&bytes);
+        let header = read_header(&mut file).0.unwrap();
+        let entries = read_index_entries_at(&mut file, header.index_section_start, None).unwrap();
+        let data =
+            read_entry_data(&mut file, header.data_section_start, &entries[0], None).unwrap();
+
+        assert!(verify_entry_data(&data, &entries[0], Some(&[1u8; 32])).is_ok());
+        for key in [Some(&[2u8; 32]), None] {
+            assert!(matches!(
+                verify_entry_data(&data, &entries[0], key),
+                Err(Error::ChecksumMismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn mac_archive_requires_a_key() {
+        let bytes = mac_archive([1u8; 32]);
+        let header = read_header(&mut Cursor::new(&bytes)).0.unwrap();
+        assert!(matches!(
+            require_mac_key(&KeySource::default(), header.flags),
+            Err(Error::Key(_))
+        ));
+    }
 }
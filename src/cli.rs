@@ -1,4 +1,4 @@
-use clap::{Arg, ArgAction, Command, arg};
+use clap::{Arg, ArgAction, Command, value_parser};
 
 pub fn build_cli() -> Command {
     Command::new("dar")
@@ -8,6 +8,27 @@ pub fn build_cli() -> Command {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .args(vec![
+            Arg::new("wait")
+                .long("wait")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-wait")
+                .help("Waits while another dar process uses the archive"),
+            Arg::new("no-wait")
+                .long("no-wait")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .overrides_with("wait")
+                .help("Fails right away when another dar process uses the archive (default)"),
+            Arg::new("format")
+                .long("format")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(["text", "json", "ndjson"])
+                .default_value("text")
+                .help("Output of list, validate, create, extract and diff: text, json (one document) or ndjson (one record per line)"),
+        ])
         .subcommands(vec![
             Command::new("create")
                 .short_flag('c')
@@ -32,6 +53,64 @@ pub fn build_cli() -> Command {
                         .action(ArgAction::SetTrue)
                         .conflicts_with("verbose")
                         .help("Enables progress bar"),
+                    Arg::new("encrypt")
+                        .short('e')
+                        .long("encrypt")
+                        .action(ArgAction::SetTrue)
+                        .help("Encrypts archive contents and index with a passphrase"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("recipient")
+                        .short('r')
+                        .long("recipient")
+                        .action(ArgAction::Append)
+                        .num_args(1)
+                        .conflicts_with_all(["encrypt", "passphrase-file"])
+                        .help("Encrypts archive to X25519 public key (hex or key file), repeatable"),
+                    Arg::new("sign")
+                        .short('s')
+                        .long("sign")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Signs archive with Ed25519 signing key file"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("recovery")
+                        .long("recovery")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_name("PERCENT")
+                        .help("Adds Reed-Solomon recovery record of given size, e.g. 5%"),
+                    Arg::new("since")
+                        .long("since")
+                        .action(ArgAction::Append)
+                        .num_args(1)
+                        .value_name("BASE")
+                        .help("Stores only changes against base archive (repeat for a chain, oldest first)"),
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .action(ArgAction::SetTrue)
+                        .requires("since")
+                        .help("Compares file contents with base archive checksums, not only size and mtime"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .requires("since")
+                        .help("Identity file to decrypt base archive encrypted to recipients"),
+                    Arg::new("volume-size")
+                        .long("volume-size")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_name("SIZE")
+                        .help("Splits archive into volumes <FILE>.001, .002... of at most SIZE, e.g. 4G"),
                     Arg::new("content")
                         .num_args(1..)
                         .required(true)
@@ -50,10 +129,22 @@ pub fn build_cli() -> Command {
                     Arg::new("file")
                         .short('f')
                         .long("file")
-                        .action(ArgAction::Set)
+                        .action(ArgAction::Append)
                         .num_args(1)
                         .required(true)
-                        .help("Name of the archive to extract"),
+                        .help("Name of the archive to extract (repeat for a chain of incrementals, oldest first)"),
+                    Arg::new("chain")
+                        .long("chain")
+                        .action(ArgAction::SetTrue)
+                        .help("Restores chain ending with the archive, finding base archives in its directory"),
+                    Arg::new("generation")
+                        .long("generation")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_name("N")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("chain")
+                        .help("Restores archive as it was at generation N (1 is the oldest)"),
                     Arg::new("out")
                         .short('C')
                         .long("out")
@@ -73,6 +164,22 @@ pub fn build_cli() -> Command {
                         .action(ArgAction::SetTrue)
                         .conflicts_with("verbose")
                         .help("Enables progress bar"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
                     Arg::new("entries")
                         .num_args(0..)
                         .action(ArgAction::Append)
@@ -94,6 +201,49 @@ pub fn build_cli() -> Command {
                         .num_args(1)
                         .required(true)
                         .help("Name of the archive to list"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("generations")
+                        .long("generations")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("generation")
+                        .help("Lists index generations (creation and each append) instead of entries"),
+                    Arg::new("generation")
+                        .long("generation")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_name("N")
+                        .value_parser(value_parser!(usize))
+                        .help("Lists archive as it was at generation N (1 is the oldest)"),
+                    Arg::new("long")
+                        .short('l')
+                        .long("long")
+                        .action(ArgAction::SetTrue)
+                        .help("Shows permissions, owner, modification time, algorithm and ratio of each entry"),
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .action(ArgAction::SetTrue)
+                        .requires("long")
+                        .help("Adds the first 12 hex digits of each entry's BLAKE3 checksum to the long listing"),
+                    Arg::new("sort")
+                        .long("sort")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_parser(["name", "size", "ratio", "mtime", "offset"])
+                        .help("Sorts entries by name, size, ratio, mtime or data offset; largest, least compressed and newest first (archive order by default)"),
+                    Arg::new("tree")
+                        .long("tree")
+                        .action(ArgAction::SetTrue)
+                        .help("Groups entries by directory with per-directory totals"),
                     Arg::new("help")
                         .short('h')
                         .long("help")
@@ -102,7 +252,7 @@ pub fn build_cli() -> Command {
                 ]),
             Command::new("append")
                 .short_flag('r')
-                .about("Appends data to existing archive")
+                .about("Appends files to existing archive, replacing entries with the same path")
                 .args(vec![
                     Arg::new("file")
                         .short('f')
@@ -110,17 +260,107 @@ pub fn build_cli() -> Command {
                         .action(ArgAction::Set)
                         .num_args(1)
                         .required(true)
-                        .help("Name of the archive to list"),
+                        .help("Name of the archive to append to"),
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("progress")
+                        .help("Enables verbose output"),
+                    Arg::new("progress")
+                        .short('p')
+                        .long("progress")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("verbose")
+                        .help("Enables progress bar"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
                     Arg::new("help")
                         .short('h')
                         .long("help")
                         .action(ArgAction::Help)
                         .help("Shows help of the command"),
-                    arg!(<ENTRY> "Entry (file or folder) to append"),
+                    Arg::new("content")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true)
+                        .help("Files/folders to append to archive"),
+                ]),
+            Command::new("update")
+                .short_flag('u')
+                .about("Appends files that are new or changed since they were archived")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to update"),
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("progress")
+                        .help("Enables verbose output"),
+                    Arg::new("progress")
+                        .short('p')
+                        .long("progress")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("verbose")
+                        .help("Enables progress bar"),
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .action(ArgAction::SetTrue)
+                        .help("Compares file contents with archived checksums, not only size and mtime"),
+                    Arg::new("delete-missing")
+                        .long("delete-missing")
+                        .action(ArgAction::SetTrue)
+                        .help("Removes entries whose file no longer exists"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                    Arg::new("content")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true)
+                        .help("Files and folders the archive was created from"),
                 ]),
             Command::new("defragment")
                 .short_flag('d')
-                .about("Removes obsolete metadata from the archive (possibly reducing its size)")
+                .about("Rewrites the archive without data and indexes superseded by append, update or delete")
                 .args(vec![
                     Arg::new("file")
                         .short('f')
@@ -129,6 +369,22 @@ pub fn build_cli() -> Command {
                         .num_args(1)
                         .required(true)
                         .help("Name of the archive to defragment"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
                     Arg::new("help")
                         .short('h')
                         .long("help")
@@ -161,6 +417,284 @@ pub fn build_cli() -> Command {
                         .long("verbose")
                         .action(ArgAction::SetTrue)
                         .help("Enables verbose output"),
+                    Arg::new("progress")
+                        .short('p')
+                        .long("progress")
+                        .action(ArgAction::SetTrue)
+                        .requires("slow")
+                        .help("Enables progress bar of checking entries (with --slow)"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("verify-key")
+                        .long("verify-key")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Verifies archive signature with Ed25519 public key (hex or key file)"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                ]),
+            Command::new("sign")
+                .short_flag('s')
+                .about("Signs archive checksum with Ed25519 key")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to sign"),
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Ed25519 signing key file"),
+                    Arg::new("sidecar")
+                        .long("sidecar")
+                        .action(ArgAction::SetTrue)
+                        .help("Writes detached signature to <FILE>.sig instead of embedding it"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("repair")
+                .about("Repairs damaged archive using its recovery record")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to repair"),
+                    Arg::new("out")
+                        .short('o')
+                        .long("out")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Writes repaired archive to this file instead of in place"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("recover")
+                .about("Removes what an interrupted append left at the end of an archive")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to recover"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("salvage")
+                .about("Recovers entries from archive with damaged index using local entry headers")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the damaged archive"),
+                    Arg::new("out")
+                        .short('C')
+                        .long("out")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .default_value(".")
+                        .help("Target directory to extract recovered entries"),
+                    Arg::new("reindex")
+                        .long("reindex")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .conflicts_with("out")
+                        .help("Writes recovered entries into a new archive instead of extracting them"),
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .help("Enables verbose output"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("delete")
+                .about("Deletes entries from archive by path or glob")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to delete entries from"),
+                    Arg::new("purge")
+                        .long("purge")
+                        .action(ArgAction::SetTrue)
+                        .help("Rewrites archive so the deleted data is physically removed"),
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .help("Enables verbose output"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("entries")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true)
+                        .help("Paths (a directory selects everything below it) or globs of entries to delete"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("diff")
+                .about("Compares archive with a directory or another archive, exits with 1 if they differ and 2 on errors")
+                .args(vec![
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .action(ArgAction::Append)
+                        .num_args(1)
+                        .required(true)
+                        .help("Name of the archive to compare (repeat to compare two archives, older first)"),
+                    Arg::new("directory")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .default_value(".")
+                        .help("Directory to compare the archive with"),
+                    Arg::new("content")
+                        .long("content")
+                        .action(ArgAction::SetTrue)
+                        .help("Prints unified diffs of changed text entries when comparing two archives"),
+                    Arg::new("passphrase-file")
+                        .long("passphrase-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Read passphrase of encrypted archive from file (or set DAR_PASSPHRASE)"),
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Identity file to decrypt archive encrypted to recipients"),
+                    Arg::new("mac-key-file")
+                        .long("mac-key-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Shared secret file for keyed BLAKE3 (MAC) checksums"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
+                ]),
+            Command::new("keygen")
+                .short_flag('k')
+                .about("Generates X25519 identity for recipient encryption or Ed25519 signing key")
+                .args(vec![
+                    Arg::new("out")
+                        .short('o')
+                        .long("out")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true)
+                        .help("Key file to write"),
+                    Arg::new("sign")
+                        .short('s')
+                        .long("sign")
+                        .action(ArgAction::SetTrue)
+                        .help("Generates Ed25519 signing key instead"),
+                    Arg::new("help")
+                        .short('h')
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Shows help of the command"),
                 ]),
         ])
 }
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::error::{Error, Result};
+use crate::models::archive::CompressionAlgorithm;
+
+/// Compression of entry data, identified in the index by its id byte
+///
+/// Ids 0-3 are taken by the built-in codecs; codecs of library users pick
+/// one of 4-255 and must be registered to read archives using them.
+pub trait Codec: Send + Sync {
+    /// Id byte stored in index entries, unique among registered codecs
+    fn id(&self) -> u8;
+
+    /// Short name shown to users
+    fn name(&self) -> &str;
+
+    /// Compress everything `input` yields into `output`
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+
+    /// Decompress everything `input` yields into `output`
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Stored as is
+pub struct Store;
+
+impl Codec for Store {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(input, output).map(|_| ())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(input, output).map(|_| ())
+    }
+}
+
+pub struct Brotli;
+
+impl Codec for Brotli {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "brotli"
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let params = brotli::enc::BrotliEncoderParams {
+            quality: 11, // Maximum quality
+            lgwin: 24,   // Larger window size for better compression
+            ..Default::default()
+        };
+        brotli::BrotliCompress(&mut { input }, &mut { output }, &params).map(|_| ())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        brotli::BrotliDecompress(&mut { input }, &mut { output })
+    }
+}
+
+pub struct Zstandard;
+
+impl Codec for Zstandard {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        zstd::stream::copy_encode(input, output, 19) // Level 19 for better compression
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        zstd::stream::copy_decode(input, output)
+    }
+}
+
+pub struct Xz;
+
+impl Codec for Xz {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn name(&self) -> &str {
+        "xz"
+    }
+
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = xz2::write::XzEncoder::new(output, 9); // Maximum compression
+        io::copy(input, &mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut xz2::read::XzDecoder::new(input), output).map(|_| ())
+    }
+}
+
+/// Codecs by id
+#[derive(Clone)]
+pub struct CodecRegistry {
+    codecs: BTreeMap<u8, Arc<dyn Codec>>,
+}
+
+impl Default for CodecRegistry {
+    /// Registry of the built-in codecs
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        for codec in [
+            Arc::new(Store) as Arc<dyn Codec>,
+            Arc::new(Brotli),
+            Arc::new(Zstandard),
+            Arc::new(Xz),
+        ] {
+            registry.codecs.insert(codec.id(), codec);
+        }
+        registry
+    }
+}
+
+impl CodecRegistry {
+    /// Registry without any codec, not even storing as is
+    pub fn empty() -> Self {
+        Self {
+            codecs: BTreeMap::new(),
+        }
+    }
+
+    /// Add `codec`, failing if its id is taken
+    pub fn register(&mut self, codec: Arc<dyn Codec>) -> Result<()> {
+        let id = codec.id();
+        if let Some(existing) = self.codecs.get(&id) {
+            return Err(Error::CodecIdTaken {
+                id,
+                name: existing.name().to_string(),
+            });
+        }
+        self.codecs.insert(id, codec);
+        Ok(())
+    }
+
+    /// Codec with id `algorithm`
+    pub fn get(&self, algorithm: CompressionAlgorithm) -> Result<Arc<dyn Codec>> {
+        self.codecs
+            .get(&algorithm.as_byte())
+            .cloned()
+            .ok_or(Error::CodecUnavailable {
+                id: algorithm.as_byte(),
+            })
+    }
+
+    /// Codec called `name`
+    pub fn by_name(&self, name: &str) -> Option<Arc<dyn Codec>> {
+        self.codecs
+            .values()
+            .find(|codec| codec.name() == name)
+            .cloned()
+    }
+
+    /// Registered codecs, by id
+    pub fn codecs(&self) -> impl Iterator<Item = &Arc<dyn Codec>> {
+        self.codecs.values()
+    }
+}
+
+/// Registry the writer and reader consult, starting out with the built-in codecs
+pub fn registry() -> &'static RwLock<CodecRegistry> {
+    static REGISTRY: OnceLock<RwLock<CodecRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(CodecRegistry::default()))
+}
+
+/// Make `codec` available for writing and reading archives in this process
+pub fn register_codec(codec: Arc<dyn Codec>) -> Result<()> {
+    registry()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .register(codec)
+}
+
+/// Look up the codec of `algorithm` in the process registry
+pub fn codec(algorithm: CompressionAlgorithm) -> Result<Arc<dyn Codec>> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(algorithm)
+}
@@ -1,6 +1,60 @@
 use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::collections::HashMap;
 
-pub fn call(matches: &ArgMatches) {
-    let file = matches.get_one::<String>("file").expect("File required");
-    println!("Appending entry to archive {}...", file)
+use crate::commands::access_options;
+use crate::models::archive::ArchiveIndexEntry;
+use crate::progress::cli_sink;
+use crate::rewrite::ArchiveAppender;
+use crate::terminal::success;
+use crate::utils::{collect_files, total_size};
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+
+    let verbose = matches.get_flag("verbose");
+    let progress = matches.get_flag("progress");
+    let content = matches.get_many::<String>("content").unwrap();
+
+    let mut appender = ArchiveAppender::open(file, "append", &access_options(matches))?;
+    let files = collect_files(content)?;
+    let mut sink = cli_sink(verbose, progress.then(|| total_size(&files)));
+
+    println!("Appending to archive {}...", file);
+
+    // Entries with an appended path are replaced in place, new paths go last
+    let mut entries: Vec<ArchiveIndexEntry> = std::mem::take(&mut appender.entries);
+    let mut positions: HashMap<String, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.path.clone(), i))
+        .collect();
+    let (mut added, mut replaced) = (0u32, 0u32);
+
+    for (path, root) in &files {
+        let entry = appender.add_file(path, root, &mut sink)?;
+        match positions.get(&entry.path) {
+            Some(&i) => {
+                entries[i] = entry;
+                replaced += 1;
+            }
+            None => {
+                positions.insert(entry.path.clone(), entries.len());
+                entries.push(entry);
+                added += 1;
+            }
+        }
+    }
+
+    // Progress bar finishes its line before the summary
+    drop(sink);
+    println!("  {} added, {} replaced", added, replaced);
+
+    appender.commit(&entries)?;
+
+    success(&format!("Appended {} files to {}!", added + replaced, file));
+
+    Ok(())
 }
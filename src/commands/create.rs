@@ -1,14 +1,23 @@
 use clap::ArgMatches;
 use eyre::{Result, eyre};
-use ignore::WalkBuilder;
-use std::fs::{File, canonicalize, metadata};
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
 use std::io::Write;
-use std::path::{Path, PathBuf};
-
-use crate::archive::add_file;
-use crate::models::archive::{ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::commands::{access_options, key_source, warn_if_interrupted};
+use crate::crypto::{parse_public_key, read_mac_key, read_passphrase};
+use crate::incremental::{is_unchanged, load_chain};
+use crate::output::{OutputFormat, Report, ReportSink};
+use crate::progress::cli_sink;
+use crate::signature::read_signing_key;
 use crate::terminal::success;
-use crate::utils::calculate_archive_path;
+use crate::utils::{calculate_archive_path, collect_files, total_size};
+use crate::volume::{existing_volume, parse_volume_size, write_volumes};
+use crate::writer::ArchiveWriter;
 
 pub fn call(matches: &ArgMatches) -> Result<()> {
     let file = matches
@@ -17,162 +26,198 @@ pub fn call(matches: &ArgMatches) -> Result<()> {
 
     let verbose = matches.get_flag("verbose");
     let progress = matches.get_flag("progress");
+    let encrypt = matches.get_flag("encrypt");
+    let recipients = matches
+        .get_many::<String>("recipient")
+        .map(|values| {
+            values
+                .map(|v| parse_public_key(v))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let mac_key = read_mac_key(&key_source(matches))?;
+    let signing_key = matches
+        .get_one::<String>("sign")
+        .map(|path| read_signing_key(path))
+        .transpose()?;
+    let recovery = matches
+        .get_one::<String>("recovery")
+        .map(|value| parse_recovery_percent(value))
+        .transpose()?;
+    let volume_size = matches
+        .get_one::<String>("volume-size")
+        .map(|value| parse_volume_size(value))
+        .transpose()?;
+    let compare_checksum = matches.get_flag("checksum");
     let content = matches.get_many::<String>("content").unwrap();
 
     if Path::new(file).exists() {
         return Err(eyre!("File {} already exists", file));
     }
+    if volume_size.is_some()
+        && let Some(volume) = existing_volume(file)
+    {
+        return Err(eyre!("File {} already exists", volume));
+    }
+
+    // Base archives of an incremental archive, oldest first
+    let base = matches
+        .get_many::<String>("since")
+        .map(|paths| {
+            load_chain(
+                &paths.cloned().collect::<Vec<_>>(),
+                "create",
+                &access_options(matches),
+            )
+        })
+        .transpose()?;
+    if let Some(chain) = &base {
+        chain.archives.iter().for_each(warn_if_interrupted);
+    }
+
+    let mut builder = ArchiveWriter::builder();
+    if !recipients.is_empty() {
+        builder = builder.recipients(recipients);
+    } else if encrypt {
+        builder = builder.passphrase(&read_passphrase(&key_source(matches), true)?);
+    }
+    if let Some(mac_key) = mac_key {
+        builder = builder.mac_key(mac_key);
+    }
+    if let Some(ref base) = base {
+        builder = builder.base_checksum(base.checksum());
+    }
+    if let Some(percent) = recovery {
+        builder = builder.recovery(percent);
+    }
+    if let Some(signing_key) = signing_key {
+        builder = builder.signing_key(signing_key);
+    }
+
+    // Machine-readable output replaces the text on stdout
+    let format = OutputFormat::from_matches(matches);
+    let report = (!format.is_text()).then(|| {
+        Rc::new(RefCell::new(Report::new(
+            format,
+            "archive",
+            "entry",
+            "entries",
+            json!({ "archive": file }),
+        )))
+    });
+    if report.is_none() {
+        println!("Creating new archive {}...", file);
+    }
+
+    // Collect files first, incremental archives need the complete list to find deletions
+    let files = collect_files(content)?;
+    let total = progress.then(|| total_size(&files));
+    let mut sink = cli_sink(verbose && report.is_none(), total);
+    if let Some(ref report) = report {
+        sink.push(Box::new(ReportSink::new(report.clone(), "added")));
+    }
+    let mut writer = builder.progress_sink(Box::new(sink)).build()?;
+
+    let mut walked: HashSet<String> = HashSet::new();
+    let mut unchanged = 0u32;
 
-    println!("Creating new archive {}...", file);
-
-    // Reserve space for header (512 bytes)
-    let mut archive_bytes: Vec<u8> = Vec::new();
-    let header_offset = archive_bytes.len();
-    let dummy_header = ArchiveHeader::new(0, 0, 0);
-    dummy_header.write_to(&mut archive_bytes)?;
-
-    // Data section starts after header
-    let data_section_start = archive_bytes.len() as u64;
-    let mut index_entries: Vec<ArchiveIndexEntry> = Vec::new();
-    let mut file_count = 0u32;
-
-    for item in content {
-        let relative_path = Path::new(item);
-        let absolute_path = canonicalize(relative_path)
-            .map_err(|e| eyre!("Couldn't get absolute path for {:?}: {}", relative_path, e))?;
-
-        if absolute_path.is_dir() {
-            let walker = WalkBuilder::new(&absolute_path)
-                .git_ignore(true)
-                .hidden(false)
-                .build();
-            for entry in walker {
-                let entry = entry?;
-                if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
-                    let file_meta = add_entry(
-                        &entry.path(),
-                        &progress,
-                        &verbose,
-                        &mut archive_bytes,
-                        &absolute_path,
-                        &data_section_start,
-                    )?;
-                    index_entries.push(file_meta);
-                    file_count += 1;
-                }
+    for (path, root) in &files {
+        let archive_path = calculate_archive_path(path, root);
+
+        if let Some(ref base) = base {
+            let found = base.entries.get(&archive_path);
+            walked.insert(archive_path.clone());
+
+            if let Some(found) = found
+                && is_unchanged(path, found, base, compare_checksum, mac_key.as_ref())?
+            {
+                writer.skip(&archive_path, found.entry.uncompressed_size);
+                unchanged += 1;
+                continue;
             }
-        } else if absolute_path.is_file() {
-            let file_meta = add_entry(
-                absolute_path.as_path(),
-                &progress,
-                &verbose,
-                &mut archive_bytes,
-                &absolute_path,
-                &data_section_start,
-            )?;
-
-            index_entries.push(file_meta);
-            file_count += 1;
-        } else {
-            println!("Skipping (not file/dir): {:?}", absolute_path);
         }
+
+        writer.add_path(path, &archive_path, None)?;
     }
 
-    // Index section starts after data
-    let index_section_start = archive_bytes.len() as u64;
-    let index_start_len = archive_bytes.len();
+    // Paths of the base that are gone get tombstones
+    if let Some(ref base) = base {
+        for path in base.entries.keys().filter(|path| !walked.contains(*path)) {
+            match report {
+                Some(ref report) => report
+                    .borrow_mut()
+                    .item(json!({ "path": path, "status": "deleted" })),
+                None if verbose => println!("  Deleted: {}", path),
+                None => {}
+            }
+            writer.add_tombstone(path);
+        }
+    }
 
-    // Write index entry count
-    archive_bytes.write_all(&file_count.to_be_bytes())?;
+    let stored = writer.entries().len();
+    let archive_bytes = writer.finish()?;
+    if base.is_some() && report.is_none() {
+        println!(
+            "  {} unchanged files skipped, {} entries stored",
+            unchanged, stored
+        );
+    }
 
-    // Write each index entry
-    for entry in index_entries {
-        entry.write_to(&mut archive_bytes)?;
+    if let Some(volume_size) = volume_size {
+        let volumes = write_volumes(file, &archive_bytes, volume_size)?;
+        if let Some(report) = report {
+            report.borrow_mut().finish(json!({
+                "entries": stored,
+                "unchanged": unchanged,
+                "size": archive_bytes.len(),
+                "volumes": volumes,
+            }));
+            return Ok(());
+        }
+        success(&format!(
+            "Archive {} successfully created in {} volumes ({} to {})!",
+            file,
+            volumes.len(),
+            volumes.first().unwrap(),
+            volumes.last().unwrap()
+        ));
+        return Ok(());
     }
 
-    let index_length = (archive_bytes.len() - index_start_len) as u64;
-    // End record section starts after index
-    let end_record_offset = archive_bytes.len() as u64;
-
-    // Write end record with placeholder checksum
-    let end_record = ArchiveEndRecord::new(index_section_start, index_length);
-    end_record.write_to(&mut archive_bytes)?;
-
-    // Update header with correct offsets and file count (BEFORE checksum calculation)
-    // Bytes 8-15: data_section_start
-    archive_bytes[header_offset + 8..header_offset + 16]
-        .copy_from_slice(&data_section_start.to_be_bytes());
-    // Bytes 16-23: index_section_start
-    archive_bytes[header_offset + 16..header_offset + 24]
-        .copy_from_slice(&index_section_start.to_be_bytes());
-    // Bytes 24-27: total_files
-    archive_bytes[header_offset + 24..header_offset + 28]
-        .copy_from_slice(&file_count.to_be_bytes());
-
-    // NOW calculate archive checksum (everything except the checksum fields themselves)
-    // Skip bytes 36-67 in header (where archive_checksum is stored)
-    let mut hasher = blake3::Hasher::new();
-    hasher.update(&archive_bytes[0..36]); // header up to checksum field
-    hasher.update(&[0u8; 32]); // skip checksum field in header
-    hasher.update(&archive_bytes[68..end_record_offset as usize]); // rest up to end record
-
-    // Also skip checksum in end record (bytes 20-51 within the 64-byte end record)
-    let end_record_start = end_record_offset as usize;
-    hasher.update(&archive_bytes[end_record_start..end_record_start + 20]); // magic + offsets
-    hasher.update(&[0u8; 32]); // skip checksum in end record
-    hasher.update(&archive_bytes[end_record_start + 52..]); // rest of end record
-    let archive_hash = hasher.finalize();
-
-    // Update both checksums
-    // Bytes 36-68: archive_checksum in header
-    archive_bytes[header_offset + 36..header_offset + 68].copy_from_slice(archive_hash.as_bytes());
-
-    // Checksum in end record is at offset 20-52 within the 64-byte end record
-    archive_bytes[end_record_offset as usize + 20..end_record_offset as usize + 52]
-        .copy_from_slice(archive_hash.as_bytes());
-
-    let mut archive_file = File::create(file)?;
+    // Never replace an archive that appeared while this one was being built
+    let mut archive_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(file)
+        .map_err(|e| eyre!("Failed to create archive {}: {}", file, e))?;
     archive_file.write_all(&archive_bytes)?;
     archive_file.flush()?;
 
-    success(&format!("Archive {} successfully created!", file));
+    match report {
+        Some(report) => report.borrow_mut().finish(json!({
+            "entries": stored,
+            "unchanged": unchanged,
+            "size": archive_bytes.len(),
+        })),
+        None => success(&format!("Archive {} successfully created!", file)),
+    }
 
     Ok(())
 }
 
-fn add_entry(
-    path: &Path,
-    progress: &bool,
-    verbose: &bool,
-    mut archive_bytes: &mut Vec<u8>,
-    absolute_path: &PathBuf,
-    data_section_start: &u64,
-) -> Result<ArchiveIndexEntry> {
-    let current_offset = (archive_bytes.len() - *data_section_start as usize) as u64;
-    let file_size = metadata(path)?.len();
-
-    let mut file_meta = add_file(path, &mut archive_bytes, *progress)?;
-    let archive_path = calculate_archive_path(path, &absolute_path);
-
-    file_meta.path = archive_path.clone();
-    file_meta.data_offset = current_offset;
-
-    if *verbose {
-        let ratio = if file_meta.compressed_size > 0 {
-            (file_meta.compressed_size as f64 / file_size as f64) * 100.0
-        } else {
-            0.0
-        };
-        println!(
-            "  Added: {:?} -> {} ({}B -> {}B, {:.1}%, {:?})",
-            path,
-            file_meta.path,
-            file_size,
-            file_meta.compressed_size,
-            ratio,
-            file_meta.compression_algorithm
-        );
-    }
-
-    return Ok(file_meta);
+/// Parse recovery record size given as "5%" or "5"
+fn parse_recovery_percent(value: &str) -> Result<u32> {
+    let percent = value
+        .trim()
+        .trim_end_matches('%')
+        .parse::<u32>()
+        .map_err(|_| {
+            eyre!(
+                "Invalid recovery size {}, expected percentage like 5%",
+                value
+            )
+        })?;
+
+    Ok(percent)
 }
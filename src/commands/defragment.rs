@@ -1,6 +1,30 @@
 use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::metadata;
 
-pub fn call(matches: &ArgMatches) {
-    let file = matches.get_one::<String>("file").expect("File required");
-    println!("Defragmenting archive {}...", file)
+use crate::commands::access_options;
+use crate::commands::delete::purge_entries;
+use crate::rewrite::ArchiveAppender;
+use crate::terminal::success;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+
+    let mut appender = ArchiveAppender::open(file, "defragment", &access_options(matches))?;
+    let size = metadata(file)?.len();
+
+    println!("Defragmenting archive {}...", file);
+
+    let entries = std::mem::take(&mut appender.entries);
+    purge_entries(file, appender, entries)?;
+
+    let reclaimed = size.saturating_sub(metadata(file)?.len());
+    success(&format!(
+        "Defragmented {}, {} bytes reclaimed!",
+        file, reclaimed
+    ));
+
+    Ok(())
 }
@@ -0,0 +1,139 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use globset::{Glob, GlobMatcher};
+use std::fs::{File, rename};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::commands::access_options;
+use crate::models::archive::{ArchiveIndexEntry, SignatureBlock};
+use crate::rewrite::{ArchiveAppender, ArchiveRewriter, note_stale_signatures};
+use crate::terminal::success;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file_path = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+    let patterns: Vec<String> = matches
+        .get_many::<String>("entries")
+        .ok_or_else(|| eyre!("Entries to delete required"))?
+        .cloned()
+        .collect();
+    let purge = matches.get_flag("purge");
+    let verbose = matches.get_flag("verbose");
+
+    let selectors = patterns
+        .iter()
+        .map(|pattern| Selector::new(pattern))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut appender = ArchiveAppender::open(file_path, "delete", &access_options(matches))?;
+
+    let (deleted, kept): (Vec<ArchiveIndexEntry>, Vec<ArchiveIndexEntry>) =
+        std::mem::take(&mut appender.entries)
+            .into_iter()
+            .partition(|entry| selectors.iter().any(|s| s.matches(&entry.path)));
+
+    // Like tar, refuse to guess when a name is misspelled
+    for selector in &selectors {
+        if !deleted.iter().any(|entry| selector.matches(&entry.path)) {
+            return Err(eyre!("{} not found in archive", selector.pattern));
+        }
+    }
+
+    println!("Deleting from archive {}...", file_path);
+    if verbose {
+        for entry in &deleted {
+            println!("  Deleted: {}", entry.path);
+        }
+    }
+
+    if purge {
+        purge_entries(file_path, appender, kept)?;
+        success(&format!(
+            "Deleted {} entries, their data is purged from {}!",
+            deleted.len(),
+            file_path
+        ));
+    } else {
+        appender.commit(&kept)?;
+        success(&format!(
+            "Deleted {} entries from {} (data remains in the file until purged)!",
+            deleted.len(),
+            file_path
+        ));
+    }
+
+    Ok(())
+}
+
+/// Path or glob given on the command line; plain paths also select everything below them
+struct Selector {
+    pattern: String,
+    glob: GlobMatcher,
+}
+
+impl Selector {
+    fn new(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim_end_matches('/').to_string();
+        let glob = Glob::new(&pattern)
+            .map_err(|e| eyre!("Invalid pattern {}: {}", pattern, e))?
+            .compile_matcher();
+        Ok(Self { pattern, glob })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.glob.is_match(path)
+            || path
+                .strip_prefix(&self.pattern)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// Rewrite archive without the deleted entries' data, replacing the original file
+pub(crate) fn purge_entries(
+    file_path: &str,
+    appender: ArchiveAppender,
+    kept: Vec<ArchiveIndexEntry>,
+) -> Result<()> {
+    let bytes = std::fs::read(file_path)?;
+    let header = &appender.header;
+    let keys = appender.keys();
+
+    let mut rewriter = ArchiveRewriter::new(
+        &bytes,
+        Some(header),
+        header.data_section_start as usize,
+        keys.mac.is_some(),
+    )?;
+
+    let mut file = File::open(file_path)?;
+    for entry in kept {
+        let mut stored = Vec::new();
+        if !entry.is_deleted() {
+            file.seek(SeekFrom::Start(
+                header.data_section_start + entry.data_offset,
+            ))?;
+            let mut buf = [0u8; 8];
+            file.read_exact(&mut buf)?;
+            stored = vec![0u8; u64::from_be_bytes(buf) as usize];
+            file.read_exact(&mut stored)?;
+        }
+        rewriter.add(entry, stored, keys.cipher)?;
+    }
+
+    let archive_bytes = rewriter.finish(keys, appender.recovery())?;
+    let signed = SignatureBlock::from_bytes(&bytes[SignatureBlock::OFFSET..]).is_some();
+
+    // Replace atomically, the original stays intact if anything fails before
+    let temp_path = format!("{}.purge", file_path);
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&archive_bytes)?;
+    // The replacement keeps the original's permissions, not the umask's
+    temp_file.set_permissions(std::fs::metadata(file_path)?.permissions())?;
+    temp_file.sync_all()?;
+    rename(&temp_path, file_path)?;
+
+    note_stale_signatures(file_path, signed);
+
+    Ok(())
+}
@@ -0,0 +1,514 @@
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use eyre::{Result, eyre};
+use ignore::WalkBuilder;
+use serde_json::json;
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::fs::{File, canonicalize, metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::archive::new_hasher;
+use crate::commands::{access_options, key_source, warn_if_interrupted};
+use crate::crypto::require_mac_key;
+use crate::incremental::{ChainState, discover_chain, load_chain};
+use crate::models::archive::ArchiveIndexEntry;
+use crate::output::{OutputFormat, Report};
+use crate::terminal::success;
+use crate::utils::calculate_archive_path;
+
+/// Compare as asked, returning whether differences were found
+pub fn call(matches: &ArgMatches) -> Result<bool> {
+    let files: Vec<String> = matches
+        .get_many::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?
+        .cloned()
+        .collect();
+    let directory = matches
+        .get_one::<String>("directory")
+        .ok_or_else(|| eyre!("Directory required"))?;
+    let format = OutputFormat::from_matches(matches);
+    let content = matches.get_flag("content");
+
+    let (changes, head, identical) = match files.as_slice() {
+        [archive] => {
+            if content {
+                return Err(eyre!("--content compares two archives, give a second -f"));
+            }
+            (
+                diff_directory(archive, directory, matches)?,
+                json!({ "archive": archive, "directory": directory }),
+                format!("Directory {} matches archive {}!", directory, archive),
+            )
+        }
+        [old, new] => {
+            if matches.value_source("directory") == Some(ValueSource::CommandLine) {
+                return Err(eyre!(
+                    "Give either a directory or a second archive, not both"
+                ));
+            }
+            (
+                diff_archives(old, new, content, matches)?,
+                json!({ "old": old, "new": new }),
+                format!("Archives {} and {} have the same contents!", old, new),
+            )
+        }
+        _ => {
+            return Err(eyre!(
+                "diff compares an archive with a directory or two archives, got {} archives",
+                files.len()
+            ));
+        }
+    };
+
+    if !format.is_text() {
+        let mut report = Report::new(format, "diff", "change", "changes", head);
+        for change in &changes {
+            report.item(change.to_json());
+        }
+        report.finish(json!({
+            "identical": changes.is_empty(),
+            "changes": changes.len(),
+        }));
+    } else if changes.is_empty() {
+        success(&identical);
+    } else {
+        for change in &changes {
+            println!("{}", change);
+            if let Some(ref diff) = change.diff {
+                print!("{}", diff);
+            }
+        }
+        println!("  {} paths differ", changes.len());
+    }
+
+    Ok(!changes.is_empty())
+}
+
+/// Compare archive with the files below directory
+fn diff_directory(file_path: &str, directory: &str, matches: &ArgMatches) -> Result<Vec<Change>> {
+    if !metadata(directory).map(|m| m.is_dir()).unwrap_or(false) {
+        return Err(eyre!(
+            "{} is not a directory, give a second archive with another -f",
+            directory
+        ));
+    }
+
+    let state = load_state(file_path, matches)?;
+    // Entry checksums of MAC archives can only be reproduced with the same key
+    let mac_keys = state
+        .archives
+        .iter()
+        .map(|archive| require_mac_key(&key_source(matches), archive.header().flags))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let root = canonicalize(directory)
+        .map_err(|e| eyre!("Couldn't get absolute path for {}: {}", directory, e))?;
+    let files = walk_directory(&root, canonicalize(file_path).ok().as_deref())?;
+
+    let mut changes = Vec::new();
+    for (path, chain_entry) in &state.entries {
+        match files.get(path) {
+            Some(fs_path) => {
+                let mac_key = mac_keys[chain_entry.archive].as_ref();
+                if let Some(change) = compare_file(fs_path, &chain_entry.entry, mac_key)? {
+                    changes.push(change);
+                }
+            }
+            None => changes.push(Change::new(path, ChangeKind::Removed)),
+        }
+    }
+    for path in files
+        .keys()
+        .filter(|path| !state.entries.contains_key(*path))
+    {
+        changes.push(Change::new(path, ChangeKind::Added));
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changes)
+}
+
+/// Compare indexes of two archives; data is only read for --content
+fn diff_archives(
+    old_path: &str,
+    new_path: &str,
+    content: bool,
+    matches: &ArgMatches,
+) -> Result<Vec<Change>> {
+    let old = load_state(old_path, matches)
+        .map_err(|e| eyre!("Cannot read archive {}: {}", old_path, e))?;
+    let new = load_state(new_path, matches)
+        .map_err(|e| eyre!("Cannot read archive {}: {}", new_path, e))?;
+
+    // Keyed and plain checksums of the same content never match
+    if is_authenticated(&old) != is_authenticated(&new) {
+        return Err(eyre!(
+            "Only one of {} and {} has keyed MAC checksums, their contents cannot be compared",
+            old_path,
+            new_path
+        ));
+    }
+
+    let mut changes = Vec::new();
+    for (path, old_entry) in &old.entries {
+        let Some(new_entry) = new.entries.get(path) else {
+            changes.push(Change::new(path, ChangeKind::Removed));
+            continue;
+        };
+
+        if let Some(mut change) = compare_entries(&old_entry.entry, &new_entry.entry) {
+            if content && change.kind == ChangeKind::ContentChanged {
+                let old_data = old.archives[old_entry.archive].open_entry(&old_entry.entry)?;
+                let new_data = new.archives[new_entry.archive].open_entry(&new_entry.entry)?;
+                change.diff = Some(text_diff(path, &old_data, &new_data));
+            }
+            changes.push(change);
+        }
+    }
+    for path in new
+        .entries
+        .keys()
+        .filter(|path| !old.entries.contains_key(*path))
+    {
+        changes.push(Change::new(path, ChangeKind::Added));
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changes)
+}
+
+/// Files as of the archive, resolving the base chain of incremental archives
+fn load_state(path: &str, matches: &ArgMatches) -> Result<ChainState> {
+    let paths = discover_chain(path)?;
+    let state = load_chain(&paths, "diff", &access_options(matches))?;
+    state.archives.iter().for_each(warn_if_interrupted);
+    Ok(state)
+}
+
+/// Whether entry checksums of the chain are keyed MACs
+fn is_authenticated(state: &ChainState) -> bool {
+    state
+        .archives
+        .iter()
+        .any(|archive| archive.header().is_authenticated())
+}
+
+/// Kind of difference between an archive and a directory or a newer archive
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+    ContentChanged,
+}
+
+impl ChangeKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Modified => "modified",
+            Self::ContentChanged => "content",
+        }
+    }
+}
+
+/// Attribute that differs, with its value in the archive and its new value
+pub struct Detail {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+/// Difference found for a single path
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub details: Vec<Detail>,
+    pub diff: Option<String>, // unified diff of the contents, only with --content
+}
+
+impl Change {
+    pub fn new(path: &str, kind: ChangeKind) -> Self {
+        Self {
+            path: path.to_string(),
+            kind,
+            details: Vec::new(),
+            diff: None,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = json!({
+            "path": self.path,
+            "change": self.kind.label(),
+            "details": self
+                .details
+                .iter()
+                .map(|d| json!({ "field": d.field, "old": d.old, "new": d.new }))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(ref diff) = self.diff {
+            value["diff"] = json!(diff);
+        }
+        value
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<9} {}", self.kind.label(), self.path)?;
+        if !self.details.is_empty() {
+            let details: Vec<String> = self
+                .details
+                .iter()
+                .map(|d| format!("{} {} -> {}", d.field, d.old, d.new))
+                .collect();
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Files below root by archive path, as create would store them
+fn walk_directory(root: &Path, skip: Option<&Path>) -> Result<BTreeMap<String, PathBuf>> {
+    let mut files = BTreeMap::new();
+
+    let walker = WalkBuilder::new(root)
+        .git_ignore(true)
+        .hidden(false)
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            continue;
+        }
+        // The archive itself may live in the compared directory
+        if skip == Some(entry.path()) {
+            continue;
+        }
+        files.insert(
+            calculate_archive_path(entry.path(), root),
+            entry.path().to_path_buf(),
+        );
+    }
+
+    Ok(files)
+}
+
+/// Compare file on disk with its entry, hashing content only when sizes match
+fn compare_file(
+    path: &Path,
+    entry: &ArchiveIndexEntry,
+    mac_key: Option<&[u8; 32]>,
+) -> Result<Option<Change>> {
+    let fs_meta = metadata(path)?;
+    let modification_time = fs_meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    let mut change = Change::new(&entry.path, ChangeKind::Modified);
+
+    if fs_meta.len() != entry.uncompressed_size {
+        change.kind = ChangeKind::ContentChanged;
+        change.details.push(Detail {
+            field: "size",
+            old: entry.uncompressed_size.to_string(),
+            new: fs_meta.len().to_string(),
+        });
+    } else {
+        let mut hasher = new_hasher(mac_key);
+        hasher.update_reader(File::open(path)?)?;
+        if *hasher.finalize().as_bytes() != entry.checksum {
+            change.kind = ChangeKind::ContentChanged;
+            change.details.push(Detail {
+                field: "checksum",
+                old: hex::encode(entry.checksum),
+                new: hasher.finalize().to_hex().to_string(),
+            });
+        }
+    }
+
+    if modification_time != entry.modification_time {
+        change.details.push(Detail {
+            field: "mtime",
+            old: entry.modification_time.to_string(),
+            new: modification_time.to_string(),
+        });
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let permissions = (fs_meta.mode() & 0o777) as u16;
+        if permissions != entry.permissions {
+            change.details.push(Detail {
+                field: "permissions",
+                old: format!("{:o}", entry.permissions),
+                new: format!("{:o}", permissions),
+            });
+        }
+    }
+
+    if change.details.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(change))
+    }
+}
+
+/// Compare two index entries of the same path, without touching their data
+fn compare_entries(old: &ArchiveIndexEntry, new: &ArchiveIndexEntry) -> Option<Change> {
+    let mut change = Change::new(&new.path, ChangeKind::Modified);
+
+    if old.uncompressed_size != new.uncompressed_size {
+        change.kind = ChangeKind::ContentChanged;
+        change.details.push(Detail {
+            field: "size",
+            old: old.uncompressed_size.to_string(),
+            new: new.uncompressed_size.to_string(),
+        });
+    } else if old.checksum != new.checksum {
+        change.kind = ChangeKind::ContentChanged;
+        change.details.push(Detail {
+            field: "checksum",
+            old: hex::encode(old.checksum),
+            new: hex::encode(new.checksum),
+        });
+    }
+
+    let fields: [(&'static str, String, String); 4] = [
+        (
+            "mtime",
+            old.modification_time.to_string(),
+            new.modification_time.to_string(),
+        ),
+        (
+            "permissions",
+            format!("{:o}", old.permissions),
+            format!("{:o}", new.permissions),
+        ),
+        ("uid", old.uid.to_string(), new.uid.to_string()),
+        ("gid", old.gid.to_string(), new.gid.to_string()),
+    ];
+    for (field, old, new) in fields {
+        if old != new {
+            change.details.push(Detail { field, old, new });
+        }
+    }
+
+    if change.details.is_empty() {
+        None
+    } else {
+        Some(change)
+    }
+}
+
+/// Unified diff of two versions of an entry, if both are text
+fn text_diff(path: &str, old: &[u8], new: &[u8]) -> String {
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) if !old.contains('\0') && !new.contains('\0') => {
+            TextDiff::from_lines(old, new)
+                .unified_diff()
+                .header(&format!("a/{}", path), &format!("b/{}", path))
+                .to_string()
+        }
+        _ => format!("Binary files a/{} and b/{} differ\n", path, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::{FileTime, set_file_mtime};
+    use tempfile::TempDir;
+
+    use crate::archive::hash_data;
+
+    fn entry(data: &[u8]) -> ArchiveIndexEntry {
+        let mut entry = ArchiveIndexEntry::tombstone("a.txt".to_string());
+        entry.flags = 0;
+        entry.uncompressed_size = data.len() as u64;
+        entry.checksum = hash_data(data, None);
+        entry.modification_time = 1_700_000_000;
+        entry.permissions = 0o644;
+        entry
+    }
+
+    fn fields(change: &Change) -> Vec<&str> {
+        change.details.iter().map(|d| d.field).collect()
+    }
+
+    #[test]
+    fn identical_entries_have_no_change() {
+        assert!(compare_entries(&entry(b"same"), &entry(b"same")).is_none());
+    }
+
+    #[test]
+    fn size_or_checksum_change_is_a_content_change() {
+        let change = compare_entries(&entry(b"old"), &entry(b"longer")).unwrap();
+        assert!(change.kind == ChangeKind::ContentChanged);
+        assert_eq!(fields(&change), ["size"]);
+
+        let change = compare_entries(&entry(b"old"), &entry(b"new")).unwrap();
+        assert!(change.kind == ChangeKind::ContentChanged);
+        assert_eq!(fields(&change), ["checksum"]);
+    }
+
+    #[test]
+    fn metadata_change_is_a_modification() {
+        let mut new = entry(b"same");
+        new.modification_time += 1;
+        new.permissions = 0o600;
+        new.uid = 1;
+
+        let change = compare_entries(&entry(b"same"), &new).unwrap();
+        assert!(change.kind == ChangeKind::Modified);
+        assert_eq!(fields(&change), ["mtime", "permissions", "uid"]);
+        assert_eq!(
+            change.to_string(),
+            "modified  a.txt (mtime 1700000000 -> 1700000001, permissions 644 -> 600, uid 0 -> 1)"
+        );
+    }
+
+    #[test]
+    fn file_is_compared_with_its_entry() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, b"same").unwrap();
+        set_file_mtime(&path, FileTime::from_unix_time(1_700_000_000, 0)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        assert!(
+            compare_file(&path, &entry(b"same"), None)
+                .unwrap()
+                .is_none()
+        );
+
+        let change = compare_file(&path, &entry(b"diff"), None).unwrap().unwrap();
+        assert!(change.kind == ChangeKind::ContentChanged);
+        assert_eq!(fields(&change), ["checksum"]);
+
+        // Keyed checksums only match when hashed with the same key
+        let mut keyed = entry(b"same");
+        keyed.checksum = hash_data(b"same", Some(&[1u8; 32]));
+        assert!(
+            compare_file(&path, &keyed, Some(&[1u8; 32]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(compare_file(&path, &keyed, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn only_text_gets_a_unified_diff() {
+        let diff = text_diff("a.txt", b"one\ntwo\n", b"one\nthree\n");
+        assert!(diff.contains("-two\n") && diff.contains("+three\n"));
+
+        let diff = text_diff("a.bin", b"\0\x01", b"\0\x02");
+        assert_eq!(diff, "Binary files a/a.bin and b/a.bin differ\n");
+    }
+}
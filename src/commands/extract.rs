@@ -1,146 +1,222 @@
 use clap::ArgMatches;
 use eyre::{Result, eyre};
-use filetime::{FileTime, set_file_mtime};
-use std::fs::{File, create_dir_all};
-use std::io::{Read, Seek, Write};
-use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH};
-
-use crate::archive::{decompress_data, parse_index_entry};
-use crate::models::archive::{ArchiveHeader, ArchiveIndexEntry};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::fs::create_dir_all;
+use std::rc::Rc;
+
+use crate::archive::{restore_file, verify_entry_data};
+use crate::commands::{access_options, key_source, warn_if_interrupted};
+use crate::crypto::require_mac_key;
+use crate::incremental::{discover_chain, load_chain};
+use crate::models::archive::ArchiveIndexEntry;
+use crate::output::{OutputFormat, Report, ReportSink};
+use crate::progress::{Event, ProgressSink, cli_sink};
+use crate::reader::ArchiveReader;
 use crate::terminal::success;
 
 pub fn call(matches: &ArgMatches) -> Result<()> {
-    let file_path = matches.get_one::<String>("file").expect("File required");
+    let files: Vec<String> = matches
+        .get_many::<String>("file")
+        .expect("File required")
+        .cloned()
+        .collect();
     let out_dir = matches
         .get_one::<String>("out")
         .expect("Output directory required");
     let verbose = matches.get_flag("verbose");
-    let _progress = matches.get_flag("progress");
+    let progress = matches.get_flag("progress");
 
-    let mut archive_file =
-        File::open(file_path).map_err(|e| eyre!("Failed to open archive {}: {}", file_path, e))?;
-
-    // Read and parse header
-    let mut header_buf = [0u8; ArchiveHeader::SIZE];
-    archive_file
-        .read_exact(&mut header_buf)
-        .map_err(|e| eyre!("Failed to read archive header: {}", e))?;
-
-    // Verify magic
-    if &header_buf[0..4] != ArchiveHeader::MAGIC {
-        return Err(eyre!("Invalid archive format: wrong magic number"));
+    // Full archive followed by incrementals, given or discovered from the newest one
+    if matches.get_flag("chain") {
+        if files.len() != 1 {
+            return Err(eyre!("--chain takes only the newest archive of the chain"));
+        }
+        let paths = discover_chain(&files[0])?;
+        return extract_chain(&paths, out_dir, verbose, progress, matches);
     }
-
-    // Verify version
-    if &header_buf[4..8] != ArchiveHeader::VERSION {
-        return Err(eyre!("Unsupported archive version"));
+    if files.len() > 1 {
+        if matches.contains_id("generation") {
+            return Err(eyre!("--generation applies to a single archive"));
+        }
+        return extract_chain(&files, out_dir, verbose, progress, matches);
+    }
+    let file_path = &files[0];
+
+    // Superseded indexes restore the archive as it was before later appends
+    let reader = ArchiveReader::open_generation(
+        file_path,
+        "extract",
+        &access_options(matches),
+        matches.get_one::<usize>("generation").copied(),
+    )?;
+    warn_if_interrupted(&reader);
+    let mac_key = require_mac_key(&key_source(matches), reader.header().flags)?;
+
+    let report = extract_report(matches, json!({ "archive": file_path, "out": out_dir }));
+    if report.is_none() {
+        println!("Extracting archive {}...", file_path);
     }
-
-    // Parse header fields
-    let data_section_start = u64::from_be_bytes(header_buf[8..16].try_into().unwrap());
-    let index_section_start = u64::from_be_bytes(header_buf[16..24].try_into().unwrap());
-
-    println!("Extracting archive {}...", file_path);
 
     // Create output directory if it doesn't exist
     create_dir_all(out_dir).map_err(|e| eyre!("Failed to create output directory: {}", e))?;
 
-    // Seek to index section and read all entries
-    archive_file
-        .seek(std::io::SeekFrom::Start(index_section_start))
-        .map_err(|e| eyre!("Failed to seek to index section: {}", e))?;
+    let mut sink = cli_sink(
+        false,
+        progress.then(|| {
+            reader
+                .live_entries()
+                .map(|entry| entry.uncompressed_size)
+                .sum()
+        }),
+    );
+    if let Some(ref report) = report {
+        sink.push(Box::new(ReportSink::new(report.clone(), "extracted")));
+    }
 
-    // Read entry count
-    let mut entry_count_buf = [0u8; 4];
-    archive_file
-        .read_exact(&mut entry_count_buf)
-        .map_err(|e| eyre!("Failed to read entry count: {}", e))?;
-    let entry_count = u32::from_be_bytes(entry_count_buf);
+    // Tombstones only matter when restoring a chain
+    for entry in reader.live_entries() {
+        extract_entry(entry, out_dir, mac_key.as_ref(), &mut sink, || {
+            reader.open_entry(entry)
+        })?;
 
-    let mut entries: Vec<ArchiveIndexEntry> = Vec::new();
+        if verbose && report.is_none() {
+            println!(
+                "  Extracted: {} ({} bytes)",
+                entry.path, entry.uncompressed_size
+            );
+        }
+    }
 
-    (0..entry_count).for_each(|_| {
-        // Read entry length
-        let entry = parse_index_entry(&mut archive_file).unwrap();
-        entries.push(entry);
-    });
+    drop(sink);
+    match report {
+        Some(report) => report.borrow_mut().finish(json!({
+            "extracted": reader.live_entries().count(),
+        })),
+        None => success(&format!(
+            "Archive {} successfully extracted to {}!",
+            file_path, out_dir
+        )),
+    }
 
-    // Now process all entries
-    for entry in entries {
-        // Construct output file path
-        let output_file_path = Path::new(out_dir).join(&entry.path);
+    Ok(())
+}
 
-        // Create parent directories
-        if let Some(parent) = output_file_path.parent() {
-            create_dir_all(parent)
-                .map_err(|e| eyre!("Failed to create directories for {}: {}", entry.path, e))?;
-        }
+/// Restore tree as of the newest archive of a chain, newest entry of each path wins
+fn extract_chain(
+    paths: &[String],
+    out_dir: &str,
+    verbose: bool,
+    progress: bool,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let chain = load_chain(paths, "extract", &access_options(matches))?;
+    chain.archives.iter().for_each(warn_if_interrupted);
+    let mac_keys = chain
+        .archives
+        .iter()
+        .map(|archive| require_mac_key(&key_source(matches), archive.header().flags))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let report = extract_report(matches, json!({ "archives": paths, "out": out_dir }));
+    if report.is_none() {
+        println!(
+            "Extracting chain of {} archives ({} to {})...",
+            paths.len(),
+            paths.first().unwrap(),
+            paths.last().unwrap()
+        );
+    }
 
-        // Read compressed data from archive
-        archive_file
-            .seek(std::io::SeekFrom::Start(
-                data_section_start + entry.data_offset,
-            ))
-            .map_err(|e| eyre!("Failed to seek to data offset for {}: {}", entry.path, e))?;
-
-        // Read entry length prefix (8 bytes)
-        let mut entry_size_buf = [0u8; 8];
-        archive_file.read_exact(&mut entry_size_buf).map_err(|e| {
-            eyre!(
-                "Failed to read compressed data size for {}: {}",
-                entry.path,
-                e
-            )
-        })?;
-        let _actual_compressed_size = u64::from_be_bytes(entry_size_buf);
-
-        // Read compressed data
-        let mut compressed_data = vec![0u8; entry.compressed_size as usize];
-        archive_file
-            .read_exact(&mut compressed_data)
-            .map_err(|e| eyre!("Failed to read compressed data for {}: {}", entry.path, e))?;
-
-        // Decompress data
-        let uncompressed_data = decompress_data(compressed_data, &entry).unwrap();
-
-        // Verify uncompressed size matches
-        if uncompressed_data.len() as u64 != entry.uncompressed_size {
-            return Err(eyre!(
-                "Decompressed size mismatch for {}: expected {}, got {}",
-                entry.path,
-                entry.uncompressed_size,
-                uncompressed_data.len()
-            ));
-        }
+    create_dir_all(out_dir).map_err(|e| eyre!("Failed to create output directory: {}", e))?;
 
-        // Write file
-        let mut output_file = File::create(&output_file_path)
-            .map_err(|e| eyre!("Failed to create output file {}: {}", entry.path, e))?;
-        output_file
-            .write_all(&uncompressed_data)
-            .map_err(|e| eyre!("Failed to write to output file {}: {}", entry.path, e))?;
-
-        // Set modification time using filetime
-        #[cfg(unix)]
-        {
-            let mtime = UNIX_EPOCH + Duration::from_secs(entry.modification_time);
-            let filetime = FileTime::from_system_time(mtime);
-            let _ = set_file_mtime(&output_file_path, filetime);
-        }
+    let mut sink = cli_sink(
+        false,
+        progress.then(|| {
+            chain
+                .entries
+                .values()
+                .map(|found| found.entry.uncompressed_size)
+                .sum()
+        }),
+    );
+    if let Some(ref report) = report {
+        sink.push(Box::new(ReportSink::new(report.clone(), "extracted")));
+    }
 
-        if verbose {
+    // Each file is read from the archive that changed it last
+    for found in chain.entries.values() {
+        let mac_key = mac_keys[found.archive].as_ref();
+        extract_entry(&found.entry, out_dir, mac_key, &mut sink, || {
+            chain.archives[found.archive].open_entry(&found.entry)
+        })?;
+
+        if verbose && report.is_none() {
             println!(
-                "  Extracted: {} ({} bytes)",
-                entry.path, entry.uncompressed_size
+                "  Extracted: {} ({} bytes, from {})",
+                found.entry.path, found.entry.uncompressed_size, paths[found.archive]
             );
         }
     }
 
-    success(&format!(
-        "Archive {} successfully extracted to {}!",
-        file_path, out_dir
-    ));
+    drop(sink);
+    match report {
+        Some(report) => report.borrow_mut().finish(json!({
+            "extracted": chain.entries.len(),
+        })),
+        None => success(&format!(
+            "Chain ending with {} successfully extracted to {}!",
+            paths.last().unwrap(),
+            out_dir
+        )),
+    }
 
     Ok(())
 }
+
+/// Restore data `read` returns for `entry` once it matches its checksum,
+/// reporting it to `sink`
+fn extract_entry(
+    entry: &ArchiveIndexEntry,
+    out_dir: &str,
+    mac_key: Option<&[u8; 32]>,
+    sink: &mut dyn ProgressSink,
+    read: impl FnOnce() -> crate::error::Result<Vec<u8>>,
+) -> Result<()> {
+    sink.event(&Event::FileStarted {
+        name: &entry.path,
+        source: None,
+        size: entry.uncompressed_size,
+    });
+
+    let result = read().and_then(|data| {
+        verify_entry_data(&data, entry, mac_key)?;
+        restore_file(out_dir, entry, &data)
+    });
+    if let Err(ref error) = result {
+        sink.event(&Event::Error {
+            name: &entry.path,
+            error,
+        });
+    }
+    result?;
+
+    sink.event(&Event::FileFinished {
+        name: &entry.path,
+        source: None,
+        size: entry.uncompressed_size,
+        stored_size: entry.compressed_size,
+        algorithm: entry.compression_algorithm,
+    });
+    Ok(())
+}
+
+/// Report of extracted entries, None when the output is text
+fn extract_report(matches: &ArgMatches, head: Value) -> Option<Rc<RefCell<Report>>> {
+    let format = OutputFormat::from_matches(matches);
+    (!format.is_text()).then(|| {
+        Rc::new(RefCell::new(Report::new(
+            format, "extract", "entry", "entries", head,
+        )))
+    })
+}
@@ -0,0 +1,58 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use x25519_dalek::PublicKey;
+
+use crate::crypto::{PUBLIC_KEY_COMMENT, new_identity};
+use crate::signature::new_signing_key;
+use crate::terminal::success;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file = matches
+        .get_one::<String>("out")
+        .ok_or_else(|| eyre!("Output file required"))?;
+
+    if Path::new(file).exists() {
+        return Err(eyre!("File {} already exists", file));
+    }
+
+    let signing = matches.get_flag("sign");
+
+    let (description, secret, public) = if signing {
+        let key = new_signing_key();
+        (
+            "dar signing key (Ed25519 secret key)",
+            hex::encode(key.to_bytes()),
+            hex::encode(key.verifying_key().to_bytes()),
+        )
+    } else {
+        let identity = new_identity();
+        (
+            "dar identity (X25519 secret key)",
+            hex::encode(identity.to_bytes()),
+            hex::encode(PublicKey::from(&identity).as_bytes()),
+        )
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut key_file = options
+        .open(file)
+        .map_err(|e| eyre!("Failed to create key file {}: {}", file, e))?;
+    writeln!(key_file, "# {}, keep it private", description)?;
+    writeln!(key_file, "{} {}", PUBLIC_KEY_COMMENT, public)?;
+    writeln!(key_file, "{}", secret)?;
+
+    println!("Public key: {}", public);
+    success(&format!("Key written to {}!", file));
+
+    Ok(())
+}
@@ -1,170 +1,461 @@
 use clap::ArgMatches;
 use eyre::Result;
-use std::fs::File;
-use std::io::{Read, Seek, Write};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-use crate::models::archive::ArchiveHeader;
+use serde_json::json;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::commands::{access_options, warn_if_interrupted};
+use crate::models::archive::ArchiveIndexEntry;
+use crate::output::{OutputFormat, Report, algorithm_name, entry_json};
 use crate::pager::PagerWriter;
+use crate::reader::ArchiveReader;
+use crate::terminal;
+use crate::utils::format_size;
 
 pub fn call(matches: &ArgMatches) -> Result<()> {
     let file_path = matches.get_one::<String>("file").expect("File required");
 
-    let mut file = File::open(file_path)?;
-
-    // Read and parse header
-    let mut header_buf = [0u8; ArchiveHeader::SIZE];
-    file.read_exact(&mut header_buf)?;
+    let format = OutputFormat::from_matches(matches);
 
-    // Verify magic
-    if &header_buf[0..4] != ArchiveHeader::MAGIC {
-        eyre::bail!("Invalid archive format: wrong magic number");
+    if matches.get_flag("generations") {
+        let reader = ArchiveReader::open(file_path, "list", &access_options(matches))?;
+        warn_if_interrupted(&reader);
+        return list_generations(&reader, format);
     }
 
-    // Verify version
-    if &header_buf[4..8] != ArchiveHeader::VERSION {
-        eyre::bail!("Unsupported archive version");
-    }
+    // Superseded indexes show the archive as it was before later appends
+    let reader = ArchiveReader::open_generation(
+        file_path,
+        "list",
+        &access_options(matches),
+        matches.get_one::<usize>("generation").copied(),
+    )?;
+    warn_if_interrupted(&reader);
+    let header = reader.header();
 
-    // Parse header fields
-    let index_section_start = u64::from_be_bytes(header_buf[16..24].try_into().unwrap());
-    let created_timestamp = u64::from_be_bytes(header_buf[28..36].try_into().unwrap());
+    let mut entries: Vec<&ArchiveIndexEntry> = reader.entries().iter().collect();
+    if let Some(key) = matches.get_one::<String>("sort") {
+        sort_entries(&mut entries, key);
+    }
 
-    // Seek to index section
-    file.seek(std::io::SeekFrom::Start(index_section_start))?;
+    if !format.is_text() {
+        let mut report = Report::new(
+            format,
+            "archive",
+            "entry",
+            "entries",
+            json!({
+                "archive": file_path,
+                "created": header.created_timestamp,
+                "base_checksum": header.base_checksum.map(hex::encode),
+                "interrupted": reader.is_interrupted(),
+            }),
+        );
+        for entry in &entries {
+            report.item(entry_json(entry));
+        }
+        let live = || reader.live_entries();
+        report.finish(json!({
+            "entries": reader.entries().len(),
+            "files": live().count(),
+            "size": live().map(|entry| entry.uncompressed_size).sum::<u64>(),
+            "compressed_size": live().map(|entry| entry.compressed_size).sum::<u64>(),
+        }));
+        return Ok(());
+    }
 
-    // Read entry count
-    let mut entry_count_buf = [0u8; 4];
-    file.read_exact(&mut entry_count_buf)?;
-    let entry_count = u32::from_be_bytes(entry_count_buf);
+    let columns = Columns {
+        long: matches.get_flag("long"),
+        checksum: matches.get_flag("checksum"),
+        width: terminal::width(),
+    };
 
     // Create pager writer
     let mut output = PagerWriter::new()?;
 
     // Display archive header info
-    let created_datetime = UNIX_EPOCH + Duration::from_secs(created_timestamp);
-    let created_str = humanize_time(created_datetime);
+    let created_str = terminal::local_time(header.created_timestamp);
 
     writeln!(output, "Archive: {}", file_path)?;
     writeln!(output, "Created: {}", created_str)?;
-    writeln!(output, "Total Files: {}", entry_count)?;
-    writeln!(output, "{:-<80}", "")?;
-    writeln!(output, "{:<60} {:>8} {:>10}", "Path", "Size", "Compressed")?;
-    writeln!(output, "{:-<80}", "")?;
+    writeln!(output, "Total Files: {}", reader.entries().len())?;
+    if let Some(base) = header.base_checksum {
+        writeln!(output, "Incremental, base checksum: {}", hex::encode(base))?;
+    }
+    let rule = columns.rule_width();
+    writeln!(output, "{:-<rule$}", "")?;
+    columns.write_heading(&mut output)?;
+    writeln!(output, "{:-<rule$}", "")?;
+
+    // Display each index entry
+    if matches.get_flag("tree") {
+        let mut root = Directory::default();
+        for entry in &entries {
+            root.insert(&entry.path, entry);
+        }
+        root.write(&mut output, &columns, 0)?;
+    } else {
+        for entry in &entries {
+            columns.write_entry(&mut output, entry, &entry.path)?;
+        }
+    }
 
-    // Parse and display each index entry
-    let mut total_uncompressed = 0u64;
-    let mut total_compressed = 0u64;
+    writeln!(output, "{:-<rule$}", "")?;
+    columns.write_total(
+        &mut output,
+        "TOTAL",
+        reader
+            .live_entries()
+            .map(|entry| entry.uncompressed_size)
+            .sum(),
+        reader
+            .live_entries()
+            .map(|entry| entry.compressed_size)
+            .sum(),
+    )?;
 
-    for _ in 0..entry_count {
-        // Read entry length
-        let mut entry_len_buf = [0u8; 4];
-        file.read_exact(&mut entry_len_buf)?;
-        let entry_len = u32::from_be_bytes(entry_len_buf) as usize;
+    Ok(())
+}
 
-        // Read entire entry
-        let mut entry_buf = vec![0u8; entry_len];
-        file.read_exact(&mut entry_buf)?;
+/// Orders entries by a `--sort` key: largest, least compressed and newest first,
+/// names and data offsets ascending
+fn sort_entries(entries: &mut [&ArchiveIndexEntry], key: &str) {
+    match key {
+        "name" => entries.sort_by(|a, b| a.path.cmp(&b.path)),
+        "size" => entries.sort_by_key(|entry| Reverse(entry.uncompressed_size)),
+        "ratio" => entries.sort_by(|a, b| ratio(b).total_cmp(&ratio(a))),
+        "mtime" => entries.sort_by_key(|entry| Reverse(entry.modification_time)),
+        "offset" => entries.sort_by_key(|entry| entry.data_offset),
+        _ => {}
+    }
+}
 
-        // Parse path length and path
-        let path_len = u32::from_be_bytes(entry_buf[0..4].try_into().unwrap()) as usize;
+/// Compressed size relative to the original one, 0 for empty files
+fn ratio(entry: &ArchiveIndexEntry) -> f64 {
+    if entry.uncompressed_size == 0 {
+        0.0
+    } else {
+        entry.compressed_size as f64 / entry.uncompressed_size as f64
+    }
+}
 
-        let path = String::from_utf8(entry_buf[4..4 + path_len].to_vec())?;
-        let mut offset = 4 + path_len;
+fn format_ratio(size: u64, compressed: u64) -> String {
+    if size == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", compressed as f64 / size as f64 * 100.0)
+    }
+}
 
-        // Parse metadata
-        let _data_offset = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
+/// Permission bits as "-rwxr-xr-x", with setuid, setgid and sticky shown as
+/// ls does; entries are always regular files, and files added from disk store
+/// only the 0o777 bits
+fn mode_string(permissions: u16) -> String {
+    let mut mode = String::from("-");
+    for (shift, special, mark) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = permissions >> shift;
+        mode.push(if bits & 4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 2 != 0 { 'w' } else { '-' });
+        mode.push(match (permissions & special != 0, bits & 1 != 0) {
+            (true, true) => mark,
+            (true, false) => mark.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    mode
+}
 
-        let uncompressed_size =
-            u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
+/// Layout of the entry table: path, size and compressed size by default, with
+/// `--long` every metadata column before the path, which gets the rest of the
+/// terminal width
+struct Columns {
+    long: bool,
+    checksum: bool,
+    /// Terminal width, None when the output is not a terminal
+    width: Option<usize>,
+}
 
-        let compressed_size = u64::from_be_bytes(entry_buf[offset..offset + 8].try_into().unwrap());
+impl Columns {
+    /// Width of the long columns before the path
+    fn prefix_width(&self) -> usize {
+        // mode, uid, gid, size, compressed, ratio, algorithm, mtime with separators
+        let width = 11 + 5 + 5 + 9 + 11 + 8 + 8 + 17;
+        if self.checksum { width + 13 } else { width }
+    }
 
-        // Display entry
-        let display_path = truncate_path(&path, 60);
+    /// Width paths are truncated to, None for no truncation
+    fn path_width(&self) -> Option<usize> {
+        if self.long {
+            self.width
+                .map(|width| width.saturating_sub(self.prefix_width()).max(20))
+        } else {
+            Some(60)
+        }
+    }
 
-        writeln!(
-            output,
-            "{:<60} {:>8} {:>10}",
-            display_path,
-            format_size(uncompressed_size),
-            format_size(compressed_size)
-        )?;
+    fn rule_width(&self) -> usize {
+        if self.long {
+            self.width.unwrap_or(self.prefix_width() + 40)
+        } else {
+            80
+        }
+    }
 
-        total_uncompressed += uncompressed_size;
-        total_compressed += compressed_size;
+    fn path(&self, path: &str) -> String {
+        match self.path_width() {
+            Some(width) => truncate_path(path, width),
+            None => path.to_string(),
+        }
     }
 
-    writeln!(output, "{:-<80}", "")?;
-    writeln!(
-        output,
-        "{:<60} {:>8} {:>10}",
-        "TOTAL",
-        format_size(total_uncompressed),
-        format_size(total_compressed)
-    )?;
+    #[allow(clippy::too_many_arguments)]
+    fn long_prefix(
+        &self,
+        mode: &str,
+        uid: &str,
+        gid: &str,
+        size: &str,
+        compressed: &str,
+        ratio: &str,
+        algorithm: &str,
+        mtime: &str,
+        checksum: &str,
+    ) -> String {
+        let mut prefix = format!(
+            "{:<10} {:>4} {:>4} {:>8} {:>10} {:>7} {:<7} {:<16} ",
+            mode, uid, gid, size, compressed, ratio, algorithm, mtime
+        );
+        if self.checksum {
+            prefix.push_str(&format!("{:<12} ", checksum));
+        }
+        prefix
+    }
 
-    Ok(())
-}
+    fn write_heading(&self, output: &mut impl Write) -> io::Result<()> {
+        if self.long {
+            let prefix = self.long_prefix(
+                "Mode",
+                "Uid",
+                "Gid",
+                "Size",
+                "Compressed",
+                "Ratio",
+                "Algo",
+                "Modified",
+                "Checksum",
+            );
+            writeln!(output, "{}Path", prefix)
+        } else {
+            writeln!(output, "{:<60} {:>8} {:>10}", "Path", "Size", "Compressed")
+        }
+    }
 
-fn format_size(bytes: u64) -> String {
-    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
-    let mut size = bytes as f64;
-    let mut unit_idx = 0;
+    /// Row of `entry` showing `path`, which may be shortened for the tree view
+    fn write_entry(
+        &self,
+        output: &mut impl Write,
+        entry: &ArchiveIndexEntry,
+        path: &str,
+    ) -> io::Result<()> {
+        let path = self.path(path);
+
+        if !self.long {
+            if entry.is_deleted() {
+                return writeln!(output, "{:<60} {:>19}", path, "deleted");
+            }
+            return writeln!(
+                output,
+                "{:<60} {:>8} {:>10}",
+                path,
+                format_size(entry.uncompressed_size),
+                format_size(entry.compressed_size)
+            );
+        }
+
+        if entry.is_deleted() {
+            let width = self.prefix_width();
+            return writeln!(output, "{:<width$}{}", "deleted", path);
+        }
 
-    while size >= 1024.0 && unit_idx < UNITS.len() - 1 {
-        size /= 1024.0;
-        unit_idx += 1;
+        let checksum = hex::encode(entry.checksum);
+        let prefix = self.long_prefix(
+            &mode_string(entry.permissions),
+            &entry.uid.to_string(),
+            &entry.gid.to_string(),
+            &format_size(entry.uncompressed_size),
+            &format_size(entry.compressed_size),
+            &format_ratio(entry.uncompressed_size, entry.compressed_size),
+            &algorithm_name(entry.compression_algorithm),
+            &terminal::local_time(entry.modification_time),
+            &checksum[..12],
+        );
+        writeln!(output, "{}{}", prefix, path)
     }
 
-    if unit_idx == 0 {
-        format!("{}{}", bytes, UNITS[unit_idx])
-    } else {
-        format!("{:.1}{}", size, UNITS[unit_idx])
+    /// Row of totals, of a directory or the whole archive
+    fn write_total(
+        &self,
+        output: &mut impl Write,
+        label: &str,
+        size: u64,
+        compressed: u64,
+    ) -> io::Result<()> {
+        let label = self.path(label);
+
+        if !self.long {
+            return writeln!(
+                output,
+                "{:<60} {:>8} {:>10}",
+                label,
+                format_size(size),
+                format_size(compressed)
+            );
+        }
+
+        let prefix = self.long_prefix(
+            "",
+            "",
+            "",
+            &format_size(size),
+            &format_size(compressed),
+            &format_ratio(size, compressed),
+            "",
+            "",
+            "",
+        );
+        writeln!(output, "{}{}", prefix, label)
     }
 }
 
-fn humanize_time(time: SystemTime) -> String {
-    let duration = time.duration_since(UNIX_EPOCH);
+/// Entries grouped by directory for `--tree`, with totals of live files below
+#[derive(Default)]
+struct Directory<'a> {
+    directories: BTreeMap<&'a str, Directory<'a>>,
+    /// Entries directly in this directory, by name, in listing order
+    entries: Vec<(&'a str, &'a ArchiveIndexEntry)>,
+    files: usize,
+    size: u64,
+    compressed: u64,
+}
 
-    match duration {
-        Ok(_) => {
-            let secs = duration.unwrap().as_secs();
-            let days_since_epoch = secs / 86400;
-            let seconds_today = secs % 86400;
+impl<'a> Directory<'a> {
+    /// Add `entry` at `path` relative to this directory
+    fn insert(&mut self, path: &'a str, entry: &'a ArchiveIndexEntry) {
+        if !entry.is_deleted() {
+            self.files += 1;
+            self.size += entry.uncompressed_size;
+            self.compressed += entry.compressed_size;
+        }
 
-            let hours = seconds_today / 3600;
-            let minutes = (seconds_today % 3600) / 60;
+        match path.split_once('/') {
+            Some((directory, rest)) => self
+                .directories
+                .entry(directory)
+                .or_default()
+                .insert(rest, entry),
+            None => self.entries.push((path, entry)),
+        }
+    }
 
-            // Approximate date calculation (not accounting for leap seconds)
-            let year = 1970 + (days_since_epoch / 365);
-            let day_of_year = days_since_epoch % 365;
-            let month = (day_of_year / 31).min(11) + 1;
-            let day = (day_of_year % 31) + 1;
+    /// Subdirectories with their totals first, then the entries, indented by depth
+    fn write(&self, output: &mut impl Write, columns: &Columns, depth: usize) -> io::Result<()> {
+        let indent = "  ".repeat(depth);
 
-            format!(
-                "{:02}.{:02}.{:04} {:02}:{:02}",
-                day, month, year, hours, minutes
-            )
+        for (name, directory) in &self.directories {
+            let label = format!("{}{}/ ({} files)", indent, name, directory.files);
+            columns.write_total(output, &label, directory.size, directory.compressed)?;
+            directory.write(output, columns, depth + 1)?;
+        }
+        for (name, entry) in &self.entries {
+            columns.write_entry(output, entry, &format!("{}{}", indent, name))?;
         }
-        Err(_) => "Unknown".to_string(),
+
+        Ok(())
     }
 }
 
+/// Show every index generation (creation and appends) with its time and entry count
+fn list_generations(reader: &ArchiveReader, format: OutputFormat) -> Result<()> {
+    let generations = reader.generations()?;
+
+    if !format.is_text() {
+        let mut report = Report::new(
+            format,
+            "archive",
+            "generation",
+            "generations",
+            json!({ "archive": reader.path() }),
+        );
+        for (i, generation) in generations.iter().enumerate() {
+            let entries = reader
+                .generation_entries(generation)
+                .map(|entries| entries.len())
+                .ok();
+            report.item(json!({
+                "generation": i + 1,
+                "created": generation.created,
+                "entries": entries,
+                "index_offset": generation.index_offset,
+                "index_length": generation.index_length,
+                "current": i + 1 == generations.len(),
+            }));
+        }
+        report.finish(json!({ "generations": generations.len() }));
+        return Ok(());
+    }
+
+    let mut output = PagerWriter::new()?;
+    writeln!(output, "Archive: {}", reader.path())?;
+    writeln!(output, "Generations: {}", generations.len())?;
+    writeln!(output, "{:-<80}", "")?;
+    writeln!(
+        output,
+        "{:<12} {:<20} {:>10} {:>14} {:>12}",
+        "Generation", "Created", "Entries", "Index Offset", "Index Size"
+    )?;
+    writeln!(output, "{:-<80}", "")?;
+
+    for (i, generation) in generations.iter().enumerate() {
+        let entry_count = reader
+            .generation_entries(generation)
+            .map(|entries| entries.len().to_string())
+            .unwrap_or_else(|_| "unreadable".to_string());
+        let current = if i + 1 == generations.len() {
+            " (current)"
+        } else {
+            ""
+        };
+
+        writeln!(
+            output,
+            "{:<12} {:<20} {:>10} {:>14} {:>12}{}",
+            i + 1,
+            terminal::local_time(generation.created),
+            entry_count,
+            generation.index_offset,
+            format_size(generation.index_length),
+            current
+        )?;
+    }
+
+    Ok(())
+}
+
 /// Safely truncate a UTF-8 string to a display width
 fn truncate_path(path: &str, max_width: usize) -> String {
     if path.len() <= max_width {
         return path.to_string();
     }
 
-    // Target lengths for prefix
-    let prefix_len = 27;
+    // The end of a path tells most, it gets the larger part
+    let suffix_len = max_width.saturating_sub(3).div_ceil(2) + 1;
+    let prefix_len = max_width.saturating_sub(3).saturating_sub(suffix_len);
 
     // Find safe prefix (respect UTF-8 boundaries)
     let mut safe_prefix = 0;
-    for (_i, c) in path.chars().enumerate() {
+    for c in path.chars() {
         if safe_prefix >= prefix_len {
             break;
         }
@@ -173,13 +464,8 @@ fn truncate_path(path: &str, max_width: usize) -> String {
 
     // Find safe suffix (respect UTF-8 boundaries)
     let mut safe_suffix_start = path.len();
-    let mut chars_counted = 0;
-    for c in path.chars().rev() {
-        if chars_counted >= 30 {
-            break;
-        }
+    for c in path.chars().rev().take(suffix_len) {
         safe_suffix_start -= c.len_utf8();
-        chars_counted += 1;
     }
 
     format!("{}...{}", &path[..safe_prefix], &path[safe_suffix_start..])
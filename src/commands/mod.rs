@@ -1,6 +1,51 @@
 pub mod append;
 pub mod create;
 pub mod defragment;
+pub mod delete;
+pub mod diff;
 pub mod extract;
+pub mod keygen;
 pub mod list;
+pub mod recover;
+pub mod repair;
+pub mod salvage;
+pub mod sign;
+pub mod update;
 pub mod validate;
+
+use clap::ArgMatches;
+
+use crate::crypto::KeySource;
+use crate::reader::{AccessOptions, ArchiveReader};
+
+/// Key files and lock waiting given on the command line
+pub fn access_options(matches: &ArgMatches) -> AccessOptions {
+    AccessOptions {
+        keys: key_source(matches),
+        wait: matches.get_flag("wait"),
+    }
+}
+
+/// Key files given on the command line
+pub fn key_source(matches: &ArgMatches) -> KeySource {
+    KeySource {
+        passphrase_file: string_arg(matches, "passphrase-file"),
+        identity: string_arg(matches, "identity"),
+        mac_key_file: string_arg(matches, "mac-key-file"),
+    }
+}
+
+/// Tell the user a reader fell back to the last complete index
+pub fn warn_if_interrupted(reader: &ArchiveReader) {
+    if reader.is_interrupted() {
+        eprintln!(
+            "Archive {} has an interrupted append, reading its last complete index; run dar recover to clean it up",
+            reader.path()
+        );
+    }
+}
+
+// Not every subcommand takes every key option
+fn string_arg(matches: &ArgMatches, id: &str) -> Option<String> {
+    matches.try_get_one::<String>(id).ok().flatten().cloned()
+}
@@ -0,0 +1,163 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::OpenOptions;
+
+use crate::archive::{calculate_archive_checksum, read_header, read_index_entries_at, read_tail};
+use crate::commands::key_source;
+use crate::crypto::{require_mac_key, unlock};
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::ArchiveEndRecord;
+use crate::recovery::{RecoveryRecord, planned_percent};
+use crate::rewrite::{committed_end, finish_archive, note_stale_signatures, write_end_record};
+use crate::terminal::success;
+use crate::volume::ArchiveSource;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file_path = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+
+    if let ArchiveSource::Volumes(_) = ArchiveSource::open(file_path)? {
+        return Err(eyre!(
+            "Cannot recover {}, it is split into volumes",
+            file_path
+        ));
+    }
+
+    let _lock = ArchiveLock::acquire(
+        file_path,
+        LockMode::Exclusive,
+        "recover",
+        matches.get_flag("wait"),
+    )?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path)
+        .map_err(|e| eyre!("Failed to open archive {}: {}", file_path, e))?;
+    let file_size = file.metadata()?.len();
+
+    let (header, header_result) = read_header(&mut file);
+    header_result.map_err(|e| eyre!("Cannot read archive {}: {}", file_path, e))?;
+    let mut header = header.ok_or_else(|| eyre!("Cannot read archive {}", file_path))?;
+
+    let tail = read_tail(&mut file, &header, file_size)?;
+    let end_record = &tail.end_record;
+
+    let interrupted = tail.is_interrupted(&header);
+    let recovery_lost = end_record.recovery_offset != 0
+        && RecoveryRecord::read_from(&mut file, end_record.recovery_offset).is_err();
+
+    if !interrupted && !recovery_lost {
+        success(&format!(
+            "Archive {} is complete, nothing to recover!",
+            file_path
+        ));
+        return Ok(());
+    }
+
+    let key = unlock(
+        &key_source(matches),
+        &mut file,
+        header.flags,
+        header.kdf.as_ref(),
+    )?;
+    let mac_key = require_mac_key(&key_source(matches), header.flags)?;
+    let entries = read_index_entries_at(&mut file, end_record.index_offset, key.as_ref())
+        .map_err(|e| eyre!("Last complete index of {} is unreadable: {}", file_path, e))?;
+
+    println!("Recovering archive {}...", file_path);
+
+    // Appends leave the archive they extend intact up to its end record
+    if tail.torn
+        && let Some(archive_end) = committed_end(&mut file, &header, tail.index_end())
+    {
+        println!(
+            "  Removing {} bytes of an interrupted append after the index at {}",
+            file_size - archive_end,
+            end_record.index_offset
+        );
+        file.set_len(archive_end)?;
+        file.sync_all()?;
+
+        success(&format!(
+            "Archive {} recovered with {} entries!",
+            file_path,
+            entries.len()
+        ));
+        return Ok(());
+    }
+
+    let end_record_offset = if tail.torn {
+        let index_end = tail.index_end();
+        if file_size > index_end {
+            println!(
+                "  Removing {} bytes of an interrupted append after the index at {}",
+                file_size - index_end,
+                end_record.index_offset
+            );
+        }
+        if header.recovery_offset != 0 {
+            println!("  Recovery record was overwritten by the interrupted append");
+        }
+
+        file.set_len(index_end)?;
+        write_end_record(
+            &mut file,
+            index_end,
+            &ArchiveEndRecord::new(end_record.index_offset, end_record.index_length),
+        )?;
+        header.recovery_offset = 0;
+        index_end
+    } else {
+        let end_record_offset = file_size - ArchiveEndRecord::SIZE as u64;
+        header.recovery_offset = end_record.recovery_offset;
+
+        // Checksums in place mean only the recovery section is missing, it is
+        // rebuilt from the contents only when they still match them
+        if !interrupted {
+            let calculated =
+                calculate_archive_checksum(&mut file, &header, file_size, mac_key.as_ref())?;
+            if calculated != end_record.archive_checksum {
+                return Err(eyre!(
+                    "Archive {} is damaged rather than interrupted, see dar validate and dar repair",
+                    file_path
+                ));
+            }
+            println!("  Rebuilding unfinished recovery record");
+        } else {
+            println!(
+                "  Completing interrupted append of the index at {}",
+                end_record.index_offset
+            );
+        }
+        end_record_offset
+    };
+
+    let recovery = match header.recovery_offset {
+        0 => None,
+        offset => Some(
+            planned_percent(offset, end_record_offset - offset)
+                .ok_or_else(|| eyre!("Space reserved for the recovery record is inconsistent"))?,
+        ),
+    };
+
+    header.index_section_start = end_record.index_offset;
+    header.total_files = entries.len() as u32;
+    let signed = finish_archive(
+        &mut file,
+        &header,
+        end_record_offset,
+        mac_key.as_ref(),
+        recovery,
+    )?;
+    note_stale_signatures(file_path, signed);
+
+    success(&format!(
+        "Archive {} recovered with {} entries!",
+        file_path,
+        entries.len()
+    ));
+
+    Ok(())
+}
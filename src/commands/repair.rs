@@ -0,0 +1,118 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::archive::{calculate_archive_checksum, read_header};
+use crate::commands::key_source;
+use crate::crypto::require_mac_key;
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::ArchiveEndRecord;
+use crate::recovery::{locate_section, repair};
+use crate::terminal::success;
+use crate::volume::ArchiveSource;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file_path = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+
+    // Damaged blocks are rewritten in place, which only single files support
+    if let ArchiveSource::Volumes(_) = ArchiveSource::open(file_path)? {
+        return Err(eyre!(
+            "Cannot repair {}, it is split into volumes",
+            file_path
+        ));
+    }
+
+    // Repair a copy when asked to, leaving the damaged original untouched
+    let _lock;
+    let target = match matches.get_one::<String>("out") {
+        Some(out) => {
+            _lock = ArchiveLock::acquire(
+                file_path,
+                LockMode::Shared,
+                "repair",
+                matches.get_flag("wait"),
+            )?;
+            if Path::new(out).exists() {
+                return Err(eyre!("File {} already exists", out));
+            }
+            std::fs::copy(file_path, out)
+                .map_err(|e| eyre!("Failed to copy {} to {}: {}", file_path, out, e))?;
+            out
+        }
+        None => {
+            _lock = ArchiveLock::acquire(
+                file_path,
+                LockMode::Exclusive,
+                "repair",
+                matches.get_flag("wait"),
+            )?;
+            file_path
+        }
+    };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(target)
+        .map_err(|e| eyre!("Failed to open archive {}: {}", target, e))?;
+    let file_size = file.metadata()?.len();
+
+    println!("Repairing archive {}...", target);
+
+    let (offset, record) = locate_section(&mut file, file_size)?;
+    let report = repair(&mut file, offset, &record)?;
+
+    println!(
+        "  Blocks checked: {} ({} bytes each)",
+        record.block_count(),
+        record.block_size
+    );
+    println!(
+        "  Damaged blocks: {} ({} repaired)",
+        report.damaged_blocks, report.repaired_blocks
+    );
+    println!("  Damaged parity blocks: {}", report.damaged_parity);
+
+    if !report.unrecoverable_stripes.is_empty() {
+        return Err(eyre!(
+            "Damage exceeds recovery record in {} stripe(s), archive cannot be fully repaired",
+            report.unrecoverable_stripes.len()
+        ));
+    }
+
+    // End record is not covered by parity, rebuild it from the repaired header
+    let (header, header_result) = read_header(&mut file);
+    header_result?;
+    let header = header.ok_or_else(|| eyre!("Cannot read archive header"))?;
+
+    let mut end_record = ArchiveEndRecord::new(
+        header.index_section_start,
+        offset - header.index_section_start,
+    );
+    end_record.archive_checksum = header.archive_checksum;
+    end_record.recovery_offset = offset;
+
+    let mut end_record_bytes = Vec::new();
+    end_record.write_to(&mut end_record_bytes)?;
+
+    let end_record_offset = offset + record.section_size();
+    file.seek(SeekFrom::Start(end_record_offset))?;
+    file.write_all(&end_record_bytes)?;
+    file.set_len(end_record_offset + ArchiveEndRecord::SIZE as u64)?;
+    file.flush()?;
+
+    let file_size = file.metadata()?.len();
+    let mac_key = require_mac_key(&key_source(matches), header.flags)?;
+    let checksum = calculate_archive_checksum(&mut file, &header, file_size, mac_key.as_ref())?;
+    if checksum != header.archive_checksum {
+        return Err(eyre!("Archive checksum mismatch after repair"));
+    }
+
+    success(&format!("Archive {} successfully repaired!", target));
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::archive::{
+    EntryKeys, decompress_data, hash_data, read_header, read_local_header, restore_file,
+    unseal_data,
+};
+use crate::commands::key_source;
+use crate::crypto::{read_mac_key, require_mac_key, unlock};
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::{ArchiveHeader, ArchiveIndexEntry, LocalEntryHeader};
+use crate::rewrite::ArchiveRewriter;
+use crate::terminal::success;
+use crate::volume::ArchiveSource;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file_path = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+    let out_dir = matches
+        .get_one::<String>("out")
+        .ok_or_else(|| eyre!("Output directory required"))?;
+    let reindex = matches.get_one::<String>("reindex");
+    let verbose = matches.get_flag("verbose");
+
+    if let Some(path) = reindex
+        && Path::new(path).exists()
+    {
+        return Err(eyre!("File {} already exists", path));
+    }
+
+    let _lock = ArchiveLock::acquire(
+        file_path,
+        LockMode::Shared,
+        "salvage",
+        matches.get_flag("wait"),
+    )?;
+    let mut file = ArchiveSource::open(file_path)?;
+
+    // Header tells where data lives and how to unlock it; without it only
+    // unencrypted entries can be found
+    let (header, header_result) = read_header(&mut file);
+    let key = match header {
+        Some(ref h) if h.is_encrypted() => {
+            unlock(&key_source(matches), &mut file, h.flags, h.kdf.as_ref())?
+        }
+        _ => None,
+    };
+    let mac_key = match header {
+        Some(ref h) => require_mac_key(&key_source(matches), h.flags)?,
+        None => read_mac_key(&key_source(matches))?,
+    };
+
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+    drop(file);
+
+    println!("Salvaging archive {}...", file_path);
+    if let Err(e) = header_result {
+        println!("  Header unreadable ({}), scanning whole file", e);
+    }
+
+    // Scan data section only, unless header offsets are unusable
+    let file_size = bytes.len() as u64;
+    let data_start = header
+        .as_ref()
+        .map(|h| h.data_section_start)
+        .filter(|&start| start >= ArchiveHeader::SIZE as u64 && start < file_size)
+        .unwrap_or(ArchiveHeader::SIZE as u64) as usize;
+    let data_end = header
+        .as_ref()
+        .map(|h| h.index_section_start)
+        .filter(|&end| end > data_start as u64 && end <= file_size)
+        .unwrap_or(file_size) as usize;
+
+    let mut writer = match reindex {
+        Some(_) => Some(ArchiveRewriter::new(
+            &bytes,
+            header.as_ref(),
+            data_start,
+            mac_key.is_some(),
+        )?),
+        None => None,
+    };
+
+    let keys = EntryKeys {
+        cipher: key.as_ref(),
+        mac: mac_key.as_ref(),
+    };
+
+    let mut recovered = 0u32;
+    let mut damaged = 0u32;
+    let mut position = data_start;
+
+    while let Some(found) = find_local_header(&bytes[position..data_end]) {
+        let header_pos = position + found;
+
+        // Random bytes can look like a magic, only a verified header counts
+        let Ok((entry, header_size)) = read_local_header(&bytes[header_pos..data_end], keys.cipher)
+        else {
+            position = header_pos + 1;
+            continue;
+        };
+
+        let entry_pos = header_pos + header_size;
+        match recover_entry(&bytes[..data_end], entry_pos, &entry, keys) {
+            Ok((stored, data)) => {
+                position = entry_pos + 8 + stored.len();
+                match writer {
+                    Some(ref mut writer) => writer.add(entry, stored, keys.cipher)?,
+                    None => {
+                        restore_file(out_dir, &entry, &data)?;
+                        if verbose {
+                            println!("  Recovered: {} ({} bytes)", entry.path, data.len());
+                        }
+                    }
+                }
+                recovered += 1;
+            }
+            Err(e) => {
+                println!("  Damaged: {}: {}", entry.path, e);
+                damaged += 1;
+                position = header_pos + 1;
+            }
+        }
+    }
+
+    if recovered == 0 {
+        return Err(eyre!(
+            "No entries could be salvaged from {} (archives before version 0005 have no local entry headers)",
+            file_path
+        ));
+    }
+
+    println!("  Entries recovered: {}, damaged: {}", recovered, damaged);
+
+    match (writer, reindex) {
+        (Some(writer), Some(path)) => {
+            let archive_bytes = writer.finish(keys, None)?;
+            // Never replace a file that appeared while salvaging
+            let mut archive_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::AlreadyExists => eyre!("File {} already exists", path),
+                    _ => eyre!("Failed to create archive {}: {}", path, e),
+                })?;
+            archive_file.write_all(&archive_bytes)?;
+            archive_file.flush()?;
+            success(&format!(
+                "Salvaged {} entries into new archive {}!",
+                recovered, path
+            ));
+        }
+        _ => {
+            success(&format!(
+                "Salvaged {} entries from {} to {}!",
+                recovered, file_path, out_dir
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read, decrypt and verify entry data following a local header
+/// Returns stored (possibly sealed) bytes and uncompressed data
+fn recover_entry(
+    bytes: &[u8],
+    entry_pos: usize,
+    entry: &ArchiveIndexEntry,
+    keys: EntryKeys,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let length_end = entry_pos + 8;
+    if length_end > bytes.len() {
+        return Err(eyre!("Entry data truncated"));
+    }
+
+    let stored_len = u64::from_be_bytes(bytes[entry_pos..length_end].try_into().unwrap());
+    if stored_len != entry.compressed_size {
+        return Err(eyre!(
+            "Entry length mismatch: {} vs {}",
+            stored_len,
+            entry.compressed_size
+        ));
+    }
+
+    let data_end = length_end
+        .checked_add(stored_len as usize)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| eyre!("Entry data truncated"))?;
+    let stored = bytes[length_end..data_end].to_vec();
+
+    let compressed = unseal_data(stored.clone(), entry, keys.cipher)?;
+    let data = decompress_data(compressed, entry)?;
+
+    if data.len() as u64 != entry.uncompressed_size {
+        return Err(eyre!(
+            "Uncompressed size mismatch: {} vs {}",
+            data.len(),
+            entry.uncompressed_size
+        ));
+    }
+
+    if hash_data(&data, keys.mac) != entry.checksum {
+        return Err(eyre!("Checksum mismatch"));
+    }
+
+    Ok((stored, data))
+}
+
+fn find_local_header(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(LocalEntryHeader::MAGIC.len())
+        .position(|window| window == LocalEntryHeader::MAGIC)
+}
@@ -0,0 +1,81 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::archive::{calculate_archive_checksum, read_header};
+use crate::commands::key_source;
+use crate::crypto::require_mac_key;
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::SignatureBlock;
+use crate::recovery::rebuild_section;
+use crate::signature::{read_signing_key, sidecar_path, sign_checksum};
+use crate::terminal::success;
+use crate::volume::ArchiveSource;
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file_path = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+    let key_path = matches
+        .get_one::<String>("key")
+        .ok_or_else(|| eyre!("Signing key required"))?;
+    let sidecar = matches.get_flag("sidecar");
+
+    let key = read_signing_key(key_path)?;
+
+    // A sidecar signature leaves the archive itself untouched
+    let mode = if sidecar {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let _lock = ArchiveLock::acquire(file_path, mode, "sign", matches.get_flag("wait"))?;
+    let mut source = ArchiveSource::open(file_path)?;
+    if !sidecar && let ArchiveSource::Volumes(_) = source {
+        return Err(eyre!(
+            "Cannot embed a signature in {}, it is split into volumes (use --sidecar)",
+            file_path
+        ));
+    }
+    let file_size = source.size()?;
+
+    let (header, header_result) = read_header(&mut source);
+    header_result?;
+    let header = header.ok_or_else(|| eyre!("Cannot read archive header"))?;
+    let mac_key = require_mac_key(&key_source(matches), header.flags)?;
+
+    // Never vouch for an archive that is already damaged
+    let checksum = calculate_archive_checksum(&mut source, &header, file_size, mac_key.as_ref())?;
+    if checksum != header.archive_checksum {
+        return Err(eyre!(
+            "Archive checksum mismatch, refusing to sign {}",
+            file_path
+        ));
+    }
+
+    let block = sign_checksum(&key, &checksum);
+
+    if sidecar {
+        let path = sidecar_path(file_path);
+        std::fs::write(&path, block.to_bytes())
+            .map_err(|e| eyre!("Failed to write signature file {}: {}", path, e))?;
+        success(&format!("Signature written to {}!", path));
+    } else {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file_path)
+            .map_err(|e| eyre!("Failed to open archive {}: {}", file_path, e))?;
+        file.seek(SeekFrom::Start(SignatureBlock::OFFSET as u64))?;
+        file.write_all(&block.to_bytes())?;
+        // The recovery record protects the header bytes the signature now occupies
+        if header.recovery_offset != 0 {
+            rebuild_section(&mut file, header.recovery_offset)?;
+        }
+        file.flush()?;
+        success(&format!("Archive {} successfully signed!", file_path));
+    }
+
+    Ok(())
+}
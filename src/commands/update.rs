@@ -0,0 +1,114 @@
+use clap::ArgMatches;
+use eyre::{Result, eyre};
+use std::collections::{HashMap, HashSet};
+
+use crate::commands::access_options;
+use crate::incremental::matches_entry;
+use crate::models::archive::ArchiveIndexEntry;
+use crate::progress::{Event, ProgressSink, cli_sink};
+use crate::rewrite::ArchiveAppender;
+use crate::terminal::success;
+use crate::utils::{calculate_archive_path, collect_files, total_size};
+
+pub fn call(matches: &ArgMatches) -> Result<()> {
+    let file = matches
+        .get_one::<String>("file")
+        .ok_or_else(|| eyre!("File required"))?;
+
+    let verbose = matches.get_flag("verbose");
+    let progress = matches.get_flag("progress");
+    let compare_checksum = matches.get_flag("checksum");
+    let delete_missing = matches.get_flag("delete-missing");
+    let content = matches.get_many::<String>("content").unwrap();
+
+    let mut appender = ArchiveAppender::open(file, "update", &access_options(matches))?;
+    let files = collect_files(content)?;
+    let mut sink = cli_sink(verbose, progress.then(|| total_size(&files)));
+
+    println!("Updating archive {}...", file);
+
+    // New index keeps the order of the current one, new paths go last
+    let mut entries: Vec<ArchiveIndexEntry> = std::mem::take(&mut appender.entries);
+    let positions: HashMap<String, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.path.clone(), i))
+        .collect();
+
+    let mut walked: HashSet<String> = HashSet::new();
+    let (mut updated, mut added, mut unchanged) = (0u32, 0u32, 0u32);
+
+    for (path, root) in &files {
+        let archive_path = calculate_archive_path(path, root);
+        let position = positions.get(&archive_path).copied();
+        walked.insert(archive_path);
+
+        if let Some(i) = position
+            && !entries[i].is_deleted()
+            && matches_entry(
+                path,
+                &entries[i],
+                compare_checksum,
+                appender.mac_key.as_ref(),
+            )?
+        {
+            sink.event(&Event::Skipped {
+                name: &entries[i].path,
+                size: entries[i].uncompressed_size,
+            });
+            unchanged += 1;
+            continue;
+        }
+
+        let entry = appender.add_file(path, root, &mut sink)?;
+        match position {
+            Some(i) => {
+                entries[i] = entry;
+                updated += 1;
+            }
+            None => {
+                entries.push(entry);
+                added += 1;
+            }
+        }
+    }
+
+    // Incrementals record deletions as tombstones, so base archives don't bring files back
+    let mut removed = 0u32;
+    if delete_missing {
+        let incremental = appender.header.is_incremental();
+        let mut kept = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if entry.is_deleted() || walked.contains(&entry.path) {
+                kept.push(entry);
+                continue;
+            }
+            if verbose {
+                println!("  Deleted: {}", entry.path);
+            }
+            if incremental {
+                kept.push(ArchiveIndexEntry::tombstone(entry.path));
+            }
+            removed += 1;
+        }
+        entries = kept;
+    }
+
+    // Progress bar finishes its line before the summary
+    drop(sink);
+    println!(
+        "  {} updated, {} added, {} removed, {} unchanged",
+        updated, added, removed, unchanged
+    );
+
+    if updated + added + removed == 0 {
+        success(&format!("Archive {} is up to date!", file));
+        return Ok(());
+    }
+
+    appender.commit(&entries)?;
+
+    success(&format!("Archive {} successfully updated!", file));
+
+    Ok(())
+}
@@ -1,16 +1,20 @@
 use clap::ArgMatches;
 use eyre::{Result, eyre};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use serde_json::json;
 
-use crate::archive::{
-    calculate_archive_checksum, decompress_data, parse_index_entry, read_end_record, read_header,
-};
-use crate::models::archive::{ArchiveHeader, ArchiveIndexEntry};
+use crate::archive::{calculate_archive_checksum, read_end_record, read_header, verify_entry_data};
+use crate::commands::{access_options, key_source};
+use crate::crypto::require_mac_key;
+use crate::lock::{ArchiveLock, LockMode};
+use crate::output::{OutputFormat, Report};
 use crate::pager::PagerWriter;
+use crate::progress::{Event, ProgressSink, cli_sink};
+use crate::reader::ArchiveReader;
+use crate::recovery::RecoveryRecord;
+use crate::signature::{find_signature, parse_verifying_key, verify_signature};
 use crate::terminal::success;
 use crate::validation::{ValidationContext, ValidationLevel};
+use crate::volume::ArchiveSource;
 
 pub fn call(matches: &ArgMatches) -> Result<()> {
     let file = matches
@@ -19,6 +23,7 @@ pub fn call(matches: &ArgMatches) -> Result<()> {
 
     let verbose = matches.get_flag("verbose");
     let slow = matches.get_flag("slow");
+    let progress = matches.get_flag("progress");
 
     let level = if slow {
         ValidationLevel::Slow
@@ -26,20 +31,36 @@ pub fn call(matches: &ArgMatches) -> Result<()> {
         ValidationLevel::Full
     };
 
-    validate_archive(file, level, verbose)?;
+    validate_archive(file, level, verbose, progress, matches)?;
 
     Ok(())
 }
 
-fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result<()> {
-    if !Path::new(path).exists() {
-        return Err(eyre!("Archive file not found: {}", path));
-    }
-
-    let file_size = std::fs::metadata(path)?.len();
-    let mut file = File::open(path)?;
-    let output = PagerWriter::new()?;
-    let mut ctx = ValidationContext::new(file_size, verbose, output);
+fn validate_archive(
+    path: &str,
+    level: ValidationLevel,
+    verbose: bool,
+    progress: bool,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let verify_key = matches
+        .get_one::<String>("verify-key")
+        .map(|value| parse_verifying_key(value))
+        .transpose()?;
+
+    let _lock = ArchiveLock::acquire(path, LockMode::Shared, "validate", matches.get_flag("wait"))?;
+    let mut file = ArchiveSource::open(path)?;
+    let file_size = file.size()?;
+    let format = OutputFormat::from_matches(matches);
+    let mut ctx = if format.is_text() {
+        ValidationContext::new(file_size, verbose, PagerWriter::new()?)
+    } else {
+        let head = json!({ "archive": path, "file_size": file_size });
+        ValidationContext::with_report(
+            file_size,
+            Report::new(format, "archive", "check", "checks", head),
+        )
+    };
 
     let _ = ctx.writeln(format_args!("Validating archive: {}", path));
     let _ = ctx.writeln(format_args!("File size: {} bytes", file_size));
@@ -47,8 +68,14 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
 
     // Basic validation
     let _ = ctx.writeln(format_args!("Basic Checks:"));
-    ctx.check("Header present (≥512 bytes)", check_min_size(&file, 512));
-    ctx.check("End record present (≥64 bytes)", check_min_size(&file, 64));
+    ctx.check(
+        "Header present (≥512 bytes)",
+        check_min_size(file_size, 512),
+    );
+    ctx.check(
+        "End record present (≥64 bytes)",
+        check_min_size(file_size, 64),
+    );
 
     let (header, header_result) = read_header(&mut file);
     ctx.check("Header readable", header_result);
@@ -56,10 +83,38 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
     let (end_record, end_result) = read_end_record(&mut file, file_size);
     ctx.check("End record readable", end_result);
 
-    if header.is_some() && end_record.is_some() {
-        let h = header.as_ref().unwrap();
-        let e = end_record.as_ref().unwrap();
+    // Index and entries of encrypted archives can only be checked with the key
+    let reader = ArchiveReader::from_source(
+        path,
+        ArchiveSource::open(path)?,
+        &access_options(matches),
+        None,
+    );
+    ctx.check(
+        "Archive opens (header, end record and index agree)",
+        reader.as_ref().map(|_| ()),
+    );
+    let reader = reader.ok();
+
+    // Interrupted appends leave the header pointing at the last complete index
+    if let Some(ref r) = reader
+        && r.is_interrupted()
+    {
+        ctx.check(
+            "Append complete",
+            Err(eyre!(
+                "Append after the index at {} was interrupted, run dar recover",
+                r.end_record().index_offset
+            )),
+        );
+    }
+
+    // Keyed checksums prove authenticity, plain ones only integrity
+    let mac_key = require_mac_key(&key_source(matches), header.as_ref().map_or(0, |h| h.flags))?;
+    let authenticated = header.as_ref().is_some_and(|h| h.is_authenticated());
+    let checksum_name = if authenticated { "MAC" } else { "checksum" };
 
+    if let (Some(h), Some(e)) = (header.as_ref(), end_record.as_ref()) {
         ctx.check(
             "Data section offset valid",
             check_offset(h.data_section_start, file_size, "Data"),
@@ -74,36 +129,64 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
                 Ok(())
             } else {
                 Err(eyre!(
-                    "Mismatch: header says {} but end record says {}",
+                    "Mismatch: header says {} but end record says {} (interrupted append? run dar recover)",
                     h.index_section_start,
                     e.index_offset
                 ))
             },
         );
 
+        if h.recovery_offset != 0 {
+            ctx.check(
+                "Recovery record readable",
+                RecoveryRecord::read_from(&mut file, h.recovery_offset).map(|_| ()),
+            );
+        }
+
         // Archive checksum verification
         let _ = ctx.writeln(format_args!("\nChecksum Verification:"));
-        match calculate_archive_checksum(&mut file, &h, file_size) {
+        if mac_key.is_some() && !authenticated {
+            ctx.check(
+                "Archive checksums keyed",
+                Err(eyre!(
+                    "Archive was created without a MAC key, authenticity cannot be verified"
+                )),
+            );
+        }
+        match calculate_archive_checksum(&mut file, h, file_size, mac_key.as_ref()) {
             Ok(calculated) => {
                 ctx.check(
-                    "Archive checksum (header)",
+                    &format!("Archive {} (header)", checksum_name),
                     if h.archive_checksum == calculated {
                         Ok(())
                     } else {
-                        Err(eyre!("Header checksum mismatch"))
+                        Err(eyre!("Header {} mismatch", checksum_name))
                     },
                 );
                 ctx.check(
-                    "Archive checksum (end record)",
+                    &format!("Archive {} (end record)", checksum_name),
                     if e.archive_checksum == calculated {
                         Ok(())
                     } else {
-                        Err(eyre!("End record checksum mismatch"))
+                        Err(eyre!("End record {} mismatch", checksum_name))
                     },
                 );
+                if let Some(ref key) = verify_key {
+                    ctx.check(
+                        "Signature (Ed25519)",
+                        find_signature(&mut file, path)
+                            .and_then(|block| verify_signature(&block, key, &calculated)),
+                    );
+                }
             }
             Err(e) => {
                 ctx.check("Archive checksum calculation", Err(e));
+                if verify_key.is_some() {
+                    ctx.check(
+                        "Signature (Ed25519)",
+                        Err(eyre!("Archive checksum unavailable")),
+                    );
+                }
             }
         }
     }
@@ -111,55 +194,75 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
     // Full validation (index parsing)
     if matches!(level, ValidationLevel::Full) {
         let _ = ctx.writeln(format_args!("\nIndex Validation:"));
-        if let Some(ref header) = header {
-            match validate_index(&mut file, header) {
-                Ok((entry_count, index_entries)) => {
-                    ctx.check(&format!("Index readable ({} entries)", entry_count), Ok(()));
+        match reader {
+            Some(ref reader) => {
+                ctx.check(
+                    &format!("Index readable ({} entries)", reader.entries().len()),
+                    Ok::<_, eyre::Report>(()),
+                );
 
-                    // Validate offsets and sizes
-                    for (i, entry) in index_entries.iter().enumerate() {
-                        ctx.check(
-                            &format!("Entry {} offset valid ({})", i + 1, &entry.path),
-                            check_offset(entry.data_offset, file_size, "Data entry"),
-                        );
+                // Validate offsets and sizes
+                for (i, entry) in reader.entries().iter().enumerate() {
+                    if entry.is_deleted() {
+                        continue;
                     }
-                }
-                Err(e) => {
-                    ctx.check("Index readable", Err(e));
+                    ctx.check(
+                        &format!("Entry {} offset valid ({})", i + 1, &entry.path),
+                        check_offset(entry.data_offset, file_size, "Data entry"),
+                    );
                 }
             }
+            None => {
+                let _ = ctx.writeln(format_args!(
+                    "  ✗ Cannot validate index: archive does not open"
+                ));
+            }
         }
     }
 
     // Slow validation (entry checksums)
     if matches!(level, ValidationLevel::Slow) {
         let _ = ctx.writeln(format_args!("\nEntry Checksum Verification (Slow Mode):"));
-        if let Some(ref header) = header {
-            match validate_index(&mut file, header) {
-                Ok((_, index_entries)) => {
-                    for (i, entry) in index_entries.iter().enumerate() {
-                        match verify_entry_data(&mut file, &header, &entry) {
-                            Ok(()) => {
-                                ctx.check(
-                                    &format!("Entry {} checksum ({})", i + 1, entry.path),
-                                    Ok(()),
-                                );
-                            }
-                            Err(e) => {
-                                ctx.check(
-                                    &format!("Entry {} checksum ({})", i + 1, entry.path),
-                                    Err(e),
-                                );
-                            }
-                        }
+        match reader {
+            Some(ref reader) => {
+                let mut sink = cli_sink(
+                    false,
+                    progress.then(|| {
+                        reader
+                            .live_entries()
+                            .map(|entry| entry.uncompressed_size)
+                            .sum()
+                    }),
+                );
+                for (i, entry) in reader.entries().iter().enumerate() {
+                    if entry.is_deleted() {
+                        continue;
                     }
-                }
-                Err(_) => {
-                    let _ = ctx.writeln(format_args!(
-                        "  ✗ Cannot validate entries: index not readable"
-                    ));
+                    sink.event(&Event::FileStarted {
+                        name: &entry.path,
+                        source: None,
+                        size: entry.uncompressed_size,
+                    });
+                    ctx.check(
+                        &format!("Entry {} checksum ({})", i + 1, entry.path),
+                        reader
+                            .open_entry(entry)
+                            .and_then(|data| verify_entry_data(&data, entry, mac_key.as_ref())),
+                    );
+                    sink.event(&Event::FileFinished {
+                        name: &entry.path,
+                        source: None,
+                        size: entry.uncompressed_size,
+                        stored_size: entry.compressed_size,
+                        algorithm: entry.compression_algorithm,
+                    });
                 }
             }
+            None => {
+                let _ = ctx.writeln(format_args!(
+                    "  ✗ Cannot validate entries: archive does not open"
+                ));
+            }
         }
     }
 
@@ -167,8 +270,25 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
     let _ = ctx.writeln(format_args!("\n{}", "=".repeat(50)));
     let _ = ctx.writeln(format_args!("Validation Summary: {}", ctx.summary()));
 
-    if ctx.is_valid() {
-        success("Archive is valid!");
+    if !format.is_text() {
+        let valid = ctx.is_valid();
+        ctx.finish_report(json!({
+            "authenticated": authenticated,
+            "signature_verified": verify_key.is_some() && valid,
+        }));
+        if !valid {
+            return Err(eyre!("Archive validation failed"));
+        }
+    } else if ctx.is_valid() {
+        let status = if authenticated { "authentic" } else { "valid" };
+        if verify_key.is_some() {
+            success(&format!(
+                "Archive is {} and its signature is verified!",
+                status
+            ));
+        } else {
+            success(&format!("Archive is {}!", status));
+        }
     } else {
         let _ = ctx.writeln(format_args!("\nErrors found:"));
         let errors = ctx.errors.clone();
@@ -182,9 +302,8 @@ fn validate_archive(path: &str, level: ValidationLevel, verbose: bool) -> Result
 }
 
 /// Check minimum file size
-fn check_min_size(file: &File, min_size: u64) -> Result<()> {
-    file.metadata()?
-        .len()
+fn check_min_size(file_size: u64, min_size: u64) -> Result<()> {
+    file_size
         .ge(&min_size)
         .then_some(())
         .ok_or_else(|| eyre!("File too small"))
@@ -203,76 +322,3 @@ fn check_offset(offset: u64, file_size: u64, location: &str) -> Result<()> {
         ))
     }
 }
-
-/// Parse and validate all index entries
-fn validate_index(
-    file: &mut File,
-    header: &ArchiveHeader,
-) -> Result<(u32, Vec<ArchiveIndexEntry>)> {
-    file.seek(SeekFrom::Start(header.index_section_start))?;
-
-    let mut buf = [0u8; 4];
-    file.read_exact(&mut buf)?;
-    let entry_count = u32::from_be_bytes(buf);
-
-    let mut entries = Vec::new();
-
-    for _ in 0..entry_count {
-        match parse_index_entry(file) {
-            Ok(entry) => entries.push(entry),
-            Err(e) => return Err(eyre!("Failed to parse index entry: {}", e)),
-        }
-    }
-
-    Ok((entry_count, entries))
-}
-
-/// Verify entry data by decompressing and checking checksum
-fn verify_entry_data(
-    file: &mut File,
-    header: &ArchiveHeader,
-    entry: &ArchiveIndexEntry,
-) -> Result<()> {
-    let data_abs_offset = header.data_section_start + entry.data_offset;
-    file.seek(SeekFrom::Start(data_abs_offset))?;
-
-    // Read entry length
-    let mut buf = [0u8; 8];
-    file.read_exact(&mut buf)?;
-    let entry_len = u64::from_be_bytes(buf);
-
-    if entry_len != entry.compressed_size {
-        return Err(eyre!(
-            "Entry length mismatch: {} vs {}",
-            entry_len,
-            entry.compressed_size
-        ));
-    }
-
-    // Read compressed data
-    let mut compressed = vec![0u8; entry.compressed_size as usize];
-    file.read_exact(&mut compressed)?;
-
-    // Decompress
-    let uncompressed = decompress_data(compressed, &entry).unwrap();
-
-    // Verify size
-    if uncompressed.len() as u64 != entry.uncompressed_size {
-        return Err(eyre!(
-            "Uncompressed size mismatch: {} vs {}",
-            uncompressed.len(),
-            entry.uncompressed_size
-        ));
-    }
-
-    // Verify checksum
-    let hash = blake3::hash(&uncompressed);
-    let mut calculated = [0u8; 32];
-    calculated.copy_from_slice(hash.as_bytes());
-
-    if calculated != entry.checksum {
-        return Err(eyre!("Checksum mismatch for entry"));
-    }
-
-    Ok(())
-}
@@ -0,0 +1,431 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::io::{Read, Seek, SeekFrom};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::error::{Error, Result, io_context};
+use crate::models::archive::{ArchiveHeader, KdfParams, RecipientStanza};
+
+/// Environment variable checked for the passphrase before prompting
+pub const PASSPHRASE_ENV: &str = "DAR_PASSPHRASE";
+
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+
+/// Associated data binding sealed blobs to their role in the archive
+/// Entry data is sealed with `entry_data_aad`, which starts with `DATA_AAD`;
+/// entries written before that use `DATA_AAD` alone
+pub const DATA_AAD: &[u8] = b"DAR\0data";
+pub const INDEX_AAD: &[u8] = b"DAR\0index";
+pub const LOCAL_AAD: &[u8] = b"DAR\0local";
+pub const RECIPIENT_AAD: &[u8] = b"DAR\0recipient";
+
+/// Comment line identity files use to carry their public key
+pub const PUBLIC_KEY_COMMENT: &str = "# public key:";
+
+/// BLAKE3 context for deriving the checksum MAC key from a shared secret
+const MAC_CONTEXT: &str = "dar 2026-01 archive checksum mac key";
+
+/// BLAKE3 context for deriving key-wrapping keys from X25519 shared secrets
+const WRAP_CONTEXT: &str = "dar 2026-01 recipient file key wrap";
+
+/// Symmetric key used to seal data blocks and the index of an archive
+pub struct ArchiveKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ArchiveKey {
+    /// Derive key from passphrase with Argon2id
+    pub fn derive(passphrase: &str, params: &KdfParams) -> Result<Self> {
+        let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+            .map_err(|e| Error::Key(format!("Invalid key derivation parameters: {}", e)))?;
+        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+
+        let mut key = Key::default();
+        argon
+            .hash_password_into(passphrase.as_bytes(), &params.salt, &mut key)
+            .map_err(|e| Error::Key(format!("Key derivation failed: {}", e)))?;
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key),
+        })
+    }
+
+    /// Use raw 32-byte key (random file key of recipient-encrypted archives)
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Encrypt plaintext, returning nonce followed by ciphertext and tag
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::Key("Encryption failed".to_string()))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt and authenticate blob produced by `seal`
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(Error::Decrypt {
+                what: "block, it is too short".to_string(),
+            });
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::Decrypt {
+                what: "data".to_string(),
+            })
+    }
+}
+
+/// Associated data binding sealed entry data to its entry: path, sizes and the
+/// data offset it was written at, so blocks cannot be moved onto other entries
+///
+/// Stored in the index entry, as rewrites move data to other offsets.
+pub fn entry_data_aad(
+    path: &str,
+    data_offset: u64,
+    uncompressed_size: u64,
+    stored_size: u64,
+) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(DATA_AAD.len() + 3 * 8 + 32);
+    aad.extend_from_slice(DATA_AAD);
+    aad.extend_from_slice(&data_offset.to_be_bytes());
+    aad.extend_from_slice(&uncompressed_size.to_be_bytes());
+    aad.extend_from_slice(&stored_size.to_be_bytes());
+    aad.extend_from_slice(blake3::hash(path.as_bytes()).as_bytes());
+    aad
+}
+
+/// Generate fresh key derivation parameters with a random salt
+pub fn new_kdf_params() -> KdfParams {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    KdfParams {
+        salt,
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+    }
+}
+
+/// Generate random per-archive file key
+pub fn new_file_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Generate new X25519 identity (secret key)
+pub fn new_identity() -> StaticSecret {
+    StaticSecret::random_from_rng(OsRng)
+}
+
+/// Wrap file key to a recipient using an ephemeral X25519 exchange
+pub fn wrap_file_key(file_key: &[u8; 32], recipient: &PublicKey) -> Result<RecipientStanza> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+
+    let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral_public, recipient);
+    let sealed = wrap_key.seal(file_key, RECIPIENT_AAD)?;
+
+    let mut wrapped_key = [0u8; 72];
+    wrapped_key.copy_from_slice(&sealed);
+
+    Ok(RecipientStanza {
+        ephemeral_public: ephemeral_public.to_bytes(),
+        wrapped_key,
+    })
+}
+
+/// Recover file key from the first stanza addressed to the identity
+pub fn unwrap_file_key(stanzas: &[RecipientStanza], identity: &StaticSecret) -> Result<[u8; 32]> {
+    let own_public = PublicKey::from(identity);
+
+    for stanza in stanzas {
+        let ephemeral_public = PublicKey::from(stanza.ephemeral_public);
+        let shared = identity.diffie_hellman(&ephemeral_public);
+        let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral_public, &own_public);
+
+        if let Ok(file_key) = wrap_key.open(&stanza.wrapped_key, RECIPIENT_AAD) {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&file_key);
+            return Ok(key);
+        }
+    }
+
+    Err(Error::Key(
+        "Identity is not a recipient of this archive".to_string(),
+    ))
+}
+
+fn derive_wrap_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ArchiveKey {
+    let mut material = Vec::with_capacity(96);
+    material.extend_from_slice(shared);
+    material.extend_from_slice(ephemeral.as_bytes());
+    material.extend_from_slice(recipient.as_bytes());
+    ArchiveKey::from_bytes(&blake3::derive_key(WRAP_CONTEXT, &material))
+}
+
+/// Parse recipient public key given as hex or as path to a file containing it
+pub fn parse_public_key(value: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from(public_key_bytes(value)?))
+}
+
+/// Read X25519 identity from key file
+pub fn read_identity(path: &str) -> Result<StaticSecret> {
+    Ok(StaticSecret::from(secret_key_bytes(path, "identity")?))
+}
+
+/// Raw public key given as hex or as path to a file containing it
+/// Key files written by keygen are accepted too (their public key comment is used)
+pub fn public_key_bytes(value: &str) -> Result<[u8; 32]> {
+    let text = match std::fs::read_to_string(value) {
+        Ok(content) => content,
+        Err(_) => value.to_string(),
+    };
+
+    let public_line = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(PUBLIC_KEY_COMMENT))
+        .map(str::to_string);
+
+    decode_key(public_line.as_deref().unwrap_or(&text), "public key")
+}
+
+/// Raw secret key read from key file written by keygen
+pub fn secret_key_bytes(path: &str, kind: &str) -> Result<[u8; 32]> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| io_context(e, format!("Failed to read {} file {}", kind, path)))?;
+    decode_key(&content, kind)
+}
+
+/// Decode first non-comment line of key material as 32 hex-encoded bytes
+fn decode_key(text: &str, kind: &str) -> Result<[u8; 32]> {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| Error::Key(format!("No {} found", kind)))?;
+
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(line, &mut key).map_err(|e| {
+        Error::Key(format!(
+            "Invalid {} (expected 64 hex characters): {}",
+            kind, e
+        ))
+    })?;
+    Ok(key)
+}
+
+/// Files the keys of an archive are read from (--passphrase-file, --identity,
+/// --mac-key-file); the passphrase falls back to the environment and a prompt
+#[derive(Clone, Default)]
+pub struct KeySource {
+    pub passphrase_file: Option<String>,
+    pub identity: Option<String>,
+    pub mac_key_file: Option<String>,
+}
+
+/// Read shared secret from the MAC key file and derive keyed BLAKE3 key from it
+pub fn read_mac_key(keys: &KeySource) -> Result<Option<[u8; 32]>> {
+    match &keys.mac_key_file {
+        Some(path) => {
+            let secret = std::fs::read(path)
+                .map_err(|e| io_context(e, format!("Failed to read MAC key file {}", path)))?;
+            if secret.is_empty() {
+                return Err(Error::Key(format!("MAC key file {} is empty", path)));
+            }
+            Ok(Some(blake3::derive_key(MAC_CONTEXT, &secret)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// MAC key required to check an archive with keyed checksums
+pub fn require_mac_key(keys: &KeySource, flags: u8) -> Result<Option<[u8; 32]>> {
+    let mac_key = read_mac_key(keys)?;
+    if flags & ArchiveHeader::FLAG_MAC != 0 && mac_key.is_none() {
+        return Err(Error::Key(
+            "Archive checksums are keyed MACs, --mac-key-file is required".to_string(),
+        ));
+    }
+    Ok(mac_key)
+}
+
+/// Obtain passphrase from the passphrase file, environment or interactive prompt
+pub fn read_passphrase(keys: &KeySource, confirm: bool) -> Result<String> {
+    if let Some(path) = &keys.passphrase_file {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| io_context(e, format!("Failed to read passphrase file {}", path)))?;
+        // Only the first line is used, so files ending with a newline work as expected
+        let passphrase = content.lines().next().unwrap_or("").to_string();
+        return non_empty(passphrase);
+    }
+
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return non_empty(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("Passphrase: ")
+        .map_err(|e| io_context(e, "Failed to read passphrase"))?;
+
+    if confirm {
+        let repeated = rpassword::prompt_password("Repeat passphrase: ")
+            .map_err(|e| io_context(e, "Failed to read passphrase"))?;
+        if passphrase != repeated {
+            return Err(Error::Key("Passphrases do not match".to_string()));
+        }
+    }
+
+    non_empty(passphrase)
+}
+
+/// Obtain archive key if the header describes an encrypted archive
+/// Passphrase archives derive it, recipient archives unwrap it with the identity
+pub fn unlock<R: Read + Seek>(
+    keys: &KeySource,
+    file: &mut R,
+    flags: u8,
+    kdf: Option<&KdfParams>,
+) -> Result<Option<ArchiveKey>> {
+    if flags & ArchiveHeader::FLAG_RECIPIENTS != 0 {
+        let identity_path = keys.identity.as_ref().ok_or_else(|| {
+            Error::Key("Archive is encrypted to recipients, --identity is required".to_string())
+        })?;
+        let identity = read_identity(identity_path)?;
+
+        file.seek(SeekFrom::Start(ArchiveHeader::SIZE as u64))?;
+        let stanzas = RecipientStanza::read_section(file)?;
+        let file_key = unwrap_file_key(&stanzas, &identity)?;
+        return Ok(Some(ArchiveKey::from_bytes(&file_key)));
+    }
+
+    match kdf {
+        Some(params) => {
+            let passphrase = read_passphrase(keys, false)?;
+            Ok(Some(ArchiveKey::derive(&passphrase, params)?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn non_empty(passphrase: String) -> Result<String> {
+    if passphrase.is_empty() {
+        Err(Error::Key("Passphrase must not be empty".to_string()))
+    } else {
+        Ok(passphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, the defaults take seconds in debug builds
+    fn kdf_params() -> KdfParams {
+        KdfParams {
+            salt: [7u8; 16],
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        }
+    }
+
+    fn is_decrypt_error(result: Result<Vec<u8>>) -> bool {
+        matches!(result, Err(Error::Decrypt { .. }))
+    }
+
+    #[test]
+    fn passphrase_key_opens_what_it_sealed() {
+        let key = ArchiveKey::derive("correct horse", &kdf_params()).unwrap();
+        let sealed = key.seal(b"entry data", DATA_AAD).unwrap();
+        assert_eq!(key.open(&sealed, DATA_AAD).unwrap(), b"entry data");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let key = ArchiveKey::derive("correct horse", &kdf_params()).unwrap();
+        let sealed = key.seal(b"entry data", DATA_AAD).unwrap();
+
+        let wrong = ArchiveKey::derive("battery staple", &kdf_params()).unwrap();
+        assert!(is_decrypt_error(wrong.open(&sealed, DATA_AAD)));
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let key = ArchiveKey::derive("correct horse", &kdf_params()).unwrap();
+        let sealed = key.seal(b"entry data", DATA_AAD).unwrap();
+
+        for position in [0, NONCE_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(is_decrypt_error(key.open(&tampered, DATA_AAD)));
+        }
+        assert!(is_decrypt_error(key.open(&sealed[..NONCE_SIZE], DATA_AAD)));
+    }
+
+    #[test]
+    fn data_sealed_for_another_entry_is_rejected() {
+        let key = ArchiveKey::derive("correct horse", &kdf_params()).unwrap();
+        let aad = entry_data_aad("a.txt", 0, 10, 50);
+        let sealed = key.seal(b"entry data", &aad).unwrap();
+
+        assert!(is_decrypt_error(
+            key.open(&sealed, &entry_data_aad("b.txt", 0, 10, 50))
+        ));
+        assert!(is_decrypt_error(
+            key.open(&sealed, &entry_data_aad("a.txt", 64, 10, 50))
+        ));
+    }
+
+    #[test]
+    fn only_a_recipient_identity_unwraps_the_file_key() {
+        let recipient = new_identity();
+        let other = new_identity();
+        let file_key = new_file_key();
+        let stanzas = [wrap_file_key(&file_key, &PublicKey::from(&recipient)).unwrap()];
+
+        assert_eq!(unwrap_file_key(&stanzas, &recipient).unwrap(), file_key);
+        assert!(matches!(
+            unwrap_file_key(&stanzas, &other),
+            Err(Error::Key(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_stanza_is_rejected() {
+        let recipient = new_identity();
+        let mut stanza = wrap_file_key(&new_file_key(), &PublicKey::from(&recipient)).unwrap();
+        stanza.wrapped_key[NONCE_SIZE] ^= 1;
+
+        assert!(unwrap_file_key(&[stanza], &recipient).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+
+use crate::models::archive::CompressionAlgorithm;
+use crate::parser::ParseError;
+
+/// Result of library operations on archives
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why an archive could not be read or written
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the archive, or a file added to it, failed
+    Io(std::io::Error),
+    /// Structure does not start with its magic: not an archive, or damaged
+    InvalidMagic {
+        structure: &'static str,
+    },
+    UnsupportedVersion([u8; 4]),
+    /// Field of the header, end record or an entry is out of bounds or above its limit
+    Malformed(ParseError),
+    /// Index cannot be read, at entry `entry` (counted from 0) if known
+    CorruptIndex {
+        entry: Option<usize>,
+        reason: String,
+    },
+    /// Stored data of an entry does not match its index entry
+    CorruptEntry {
+        path: String,
+        reason: String,
+    },
+    /// Data does not match its checksum
+    ChecksumMismatch {
+        path: String,
+    },
+    Decompress {
+        algo: CompressionAlgorithm,
+        path: String,
+        reason: String,
+    },
+    /// No codec with this id is registered
+    CodecUnavailable {
+        id: u8,
+    },
+    /// Codec registered with an id another codec already has
+    CodecIdTaken {
+        id: u8,
+        name: String,
+    },
+    /// Wrong key, or sealed data was tampered with
+    Decrypt {
+        what: String,
+    },
+    /// Header, end record and index trailers disagree
+    Inconsistent(String),
+    NoSuchGeneration {
+        requested: usize,
+        available: usize,
+    },
+    /// Passphrase, identity or MAC key is missing, unreadable or does not fit the archive
+    Key(String),
+    /// Signature is missing, invalid or made with another key
+    Signature(String),
+    /// Volumes of a split archive are missing or do not belong together
+    Volume(String),
+    /// Recovery section is missing, damaged or cannot be built
+    Recovery(String),
+    /// Another process holds the lock of the archive, `holder` says what it does
+    Locked {
+        operation: String,
+        path: String,
+        holder: String,
+    },
+    /// Archives do not form a chain of a full archive and its incrementals
+    Chain(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::InvalidMagic { structure } => write!(f, "Invalid {} magic", structure),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported version {}",
+                    String::from_utf8_lossy(version)
+                )
+            }
+            Self::Malformed(e) => write!(f, "{}", e),
+            Self::CorruptIndex {
+                entry: Some(entry),
+                reason,
+            } => write!(f, "Index entry {} is corrupt: {}", entry + 1, reason),
+            Self::CorruptIndex {
+                entry: None,
+                reason,
+            } => write!(f, "Index is corrupt: {}", reason),
+            Self::CorruptEntry { path, reason } => {
+                write!(f, "Entry {} is corrupt: {}", path, reason)
+            }
+            Self::ChecksumMismatch { path } => write!(f, "Checksum mismatch for {}", path),
+            Self::Decompress { algo, path, reason } => {
+                write!(
+                    f,
+                    "Failed to decompress {} with {:?}: {}",
+                    path, algo, reason
+                )
+            }
+            Self::CodecUnavailable { id } => {
+                write!(f, "Codec {} is not available, it has to be registered", id)
+            }
+            Self::CodecIdTaken { id, name } => {
+                write!(f, "Codec id {} is already taken by {}", id, name)
+            }
+            Self::Decrypt { what } => {
+                write!(f, "Failed to decrypt {} (wrong key or tampered)", what)
+            }
+            Self::Inconsistent(reason) => write!(f, "{}", reason),
+            Self::NoSuchGeneration {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Archive has {} generations, there is no generation {}",
+                available, requested
+            ),
+            Self::Key(reason)
+            | Self::Signature(reason)
+            | Self::Volume(reason)
+            | Self::Recovery(reason)
+            | Self::Chain(reason) => write!(f, "{}", reason),
+            Self::Locked {
+                operation,
+                path,
+                holder,
+            } => write!(
+                f,
+                "Cannot {} {}, it is {} (use --wait to wait for it)",
+                operation, path, holder
+            ),
+        }
+    }
+}
+
+/// Wrapped I/O and parse errors are part of the message, so they are not
+/// returned as source as well (reports would print them twice)
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::BadMagic { structure } => Self::InvalidMagic { structure },
+            ParseError::UnsupportedVersion(version) => Self::UnsupportedVersion(version),
+            e => Self::Malformed(e),
+        }
+    }
+}
+
+/// I/O error with what was being done, keeping its kind
+pub fn io_context(e: std::io::Error, context: impl fmt::Display) -> Error {
+    Error::Io(std::io::Error::new(e.kind(), format!("{}: {}", context, e)))
+}
+
+/// Failed read of `field`: running out of bytes means the structure is truncated
+pub fn read_failed(e: std::io::Error, field: &'static str) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => ParseError::Truncated { field }.into(),
+        _ => Error::Io(e),
+    }
+}
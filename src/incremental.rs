@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{metadata, read_dir};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::archive::{hash_data, read_header};
+use crate::error::{Error, Result};
+use crate::models::archive::{ArchiveHeader, ArchiveIndexEntry};
+use crate::reader::{AccessOptions, ArchiveReader};
+use crate::volume::ArchiveSource;
+
+/// Entry of a chain together with the archive (position in the chain) holding its data
+pub struct ChainEntry {
+    pub archive: usize,
+    pub entry: ArchiveIndexEntry,
+}
+
+/// Files of a chain of archives (full archive followed by its incrementals)
+/// as they were when the newest archive was created
+pub struct ChainState {
+    pub archives: Vec<ArchiveReader>,
+    pub entries: BTreeMap<String, ChainEntry>,
+}
+
+impl ChainState {
+    /// Checksum of the newest archive, recorded as base of the next incremental
+    pub fn checksum(&self) -> [u8; 32] {
+        self.archives.last().unwrap().header().archive_checksum
+    }
+}
+
+/// Read and link chain of archives, oldest first, applying tombstones along the way
+pub fn load_chain(
+    paths: &[String],
+    operation: &str,
+    options: &AccessOptions,
+) -> Result<ChainState> {
+    let mut archives: Vec<ArchiveReader> = Vec::new();
+    let mut entries = BTreeMap::new();
+
+    for (position, path) in paths.iter().enumerate() {
+        let archive = ArchiveReader::open(path, operation, options)?;
+
+        check_link(archives.last().map(|a| a.header()), archive.header(), path)?;
+
+        for entry in archive.entries() {
+            if entry.is_deleted() {
+                entries.remove(&entry.path);
+            } else {
+                entries.insert(
+                    entry.path.clone(),
+                    ChainEntry {
+                        archive: position,
+                        entry: entry.clone(),
+                    },
+                );
+            }
+        }
+
+        archives.push(archive);
+    }
+
+    Ok(ChainState { archives, entries })
+}
+
+/// Find chain ending with `newest` by following recorded base checksums to
+/// archives in the same directory; returns paths oldest first
+pub fn discover_chain(newest: &str) -> Result<Vec<String>> {
+    let mut chain = vec![newest.to_string()];
+    let mut current = read_archive_header(newest)?;
+    if !current.is_incremental() {
+        return Ok(chain);
+    }
+
+    let directory = archive_directory(newest);
+
+    // Checksums of all readable archives next to the newest one
+    let mut candidates: HashMap<[u8; 32], String> = HashMap::new();
+    for (path, header) in archive_headers(&directory)? {
+        candidates.entry(header.archive_checksum).or_insert(path);
+    }
+
+    while let Some(base_checksum) = current.base_checksum {
+        let base = candidates.get(&base_checksum).ok_or_else(|| {
+            Error::Chain(format!(
+                "Base archive of {} (checksum {}) not found in {}, or it was changed by \
+                 update or delete after the incremental archive was created",
+                chain.last().unwrap(),
+                hex::encode(base_checksum),
+                directory.display()
+            ))
+        })?;
+        if chain.contains(base) {
+            return Err(Error::Chain(format!("Archive chain loops at {}", base)));
+        }
+
+        current = read_archive_header(base)?;
+        chain.push(base.clone());
+    }
+
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Incremental archives next to `path` that are based on it
+pub fn find_dependents(path: &str, header: &ArchiveHeader) -> Result<Vec<String>> {
+    Ok(archive_headers(&archive_directory(path))?
+        .into_iter()
+        .filter(|(_, other)| other.base_checksum == Some(header.archive_checksum))
+        .map(|(path, _)| path)
+        .collect())
+}
+
+fn archive_directory(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Headers of all readable archives in directory
+fn archive_headers(directory: &Path) -> Result<Vec<(String, ArchiveHeader)>> {
+    let mut headers = Vec::new();
+    for dir_entry in read_dir(directory)? {
+        let path = dir_entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let path = path.to_string_lossy().to_string();
+        if let Ok(header) = read_archive_header(&path) {
+            headers.push((path, header));
+        }
+    }
+    Ok(headers)
+}
+
+fn read_archive_header(path: &str) -> Result<ArchiveHeader> {
+    let mut source = ArchiveSource::open(path)?;
+    let (header, header_result) = read_header(&mut source);
+    header_result?;
+    header.ok_or_else(|| Error::Inconsistent(format!("Cannot read archive {}", path)))
+}
+
+/// Check archive continues the chain: full archive first, then incrementals
+/// each recording the checksum of its predecessor
+fn check_link(previous: Option<&ArchiveHeader>, header: &ArchiveHeader, path: &str) -> Result<()> {
+    match previous {
+        None if header.is_incremental() => Err(Error::Chain(format!(
+            "{} is an incremental archive, give its base archives first",
+            path
+        ))),
+        None => Ok(()),
+        Some(_) if !header.is_incremental() => Err(Error::Chain(format!(
+            "{} is not an incremental archive",
+            path
+        ))),
+        Some(previous) if header.base_checksum != Some(previous.archive_checksum) => {
+            Err(Error::Chain(format!(
+                "{} is not based on the archive before it in the chain (wrong order, or \
+                 the base was changed by update or delete after {} was created)",
+                path, path
+            )))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Whether file on disk still matches its entry in the chain
+/// Compares size and modification time, and content when `compare_checksum` is set
+pub fn is_unchanged(
+    path: &Path,
+    found: &ChainEntry,
+    chain: &ChainState,
+    compare_checksum: bool,
+    mac_key: Option<&[u8; 32]>,
+) -> Result<bool> {
+    // Entry checksums of MAC archives can only be reproduced with the same key
+    let key = if compare_checksum && chain.archives[found.archive].header().is_authenticated() {
+        Some(mac_key.ok_or_else(|| {
+            Error::Key(
+                "Base archive checksums are keyed MACs, --mac-key-file is required".to_string(),
+            )
+        })?)
+    } else {
+        None
+    };
+
+    matches_entry(path, &found.entry, compare_checksum, key)
+}
+
+/// Whether file on disk matches an index entry by size and modification time,
+/// and by checksum (keyed with `mac_key` if given) when `compare_checksum` is set
+pub fn matches_entry(
+    path: &Path,
+    entry: &ArchiveIndexEntry,
+    compare_checksum: bool,
+    mac_key: Option<&[u8; 32]>,
+) -> Result<bool> {
+    let fs_meta = metadata(path)?;
+    // Files dated before the epoch are stored as modified at the epoch
+    let modification_time = fs_meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+
+    if fs_meta.len() != entry.uncompressed_size || modification_time != entry.modification_time {
+        return Ok(false);
+    }
+
+    if !compare_checksum {
+        return Ok(true);
+    }
+
+    Ok(hash_data(&std::fs::read(path)?, mac_key) == entry.checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    use crate::archive::EntryMetadata;
+    use crate::rewrite::ArchiveAppender;
+    use crate::writer::ArchiveWriter;
+
+    /// Full archive with a.txt and b.txt, and an incremental on it that
+    /// deletes b.txt and adds c.txt; returns their paths
+    fn chain(dir: &TempDir) -> (String, String) {
+        let full = dir.path().join("full.dar").to_string_lossy().to_string();
+        let mut writer = ArchiveWriter::builder().build().unwrap();
+        for name in ["a.txt", "b.txt"] {
+            writer
+                .add_bytes(name, name.as_bytes(), EntryMetadata::default())
+                .unwrap();
+        }
+        std::fs::write(&full, writer.finish().unwrap()).unwrap();
+
+        let incremental = dir.path().join("incr.dar").to_string_lossy().to_string();
+        let base_checksum = read_archive_header(&full).unwrap().archive_checksum;
+        let mut writer = ArchiveWriter::builder()
+            .base_checksum(base_checksum)
+            .build()
+            .unwrap();
+        writer.add_tombstone("b.txt");
+        writer
+            .add_bytes("c.txt", b"c.txt", EntryMetadata::default())
+            .unwrap();
+        std::fs::write(&incremental, writer.finish().unwrap()).unwrap();
+
+        (full, incremental)
+    }
+
+    fn is_chain_error<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Chain(_)))
+    }
+
+    #[test]
+    fn chain_applies_incrementals_in_order() {
+        let dir = TempDir::new().unwrap();
+        let (full, incremental) = chain(&dir);
+
+        let state = load_chain(&[full, incremental], "test", &AccessOptions::default()).unwrap();
+        let paths: Vec<&String> = state.entries.keys().collect();
+        assert_eq!(paths, ["a.txt", "c.txt"]);
+        assert_eq!(state.entries["c.txt"].archive, 1);
+    }
+
+    #[test]
+    fn wrong_chain_order_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let (full, incremental) = chain(&dir);
+        let options = AccessOptions::default();
+
+        assert!(is_chain_error(load_chain(
+            &[incremental.clone(), full.clone()],
+            "test",
+            &options
+        )));
+        assert!(is_chain_error(load_chain(
+            std::slice::from_ref(&incremental),
+            "test",
+            &options
+        )));
+        assert!(is_chain_error(load_chain(
+            &[full.clone(), full],
+            "test",
+            &options
+        )));
+    }
+
+    #[test]
+    fn chain_is_discovered_from_newest_archive() {
+        let dir = TempDir::new().unwrap();
+        let (full, incremental) = chain(&dir);
+
+        assert_eq!(
+            discover_chain(&incremental).unwrap(),
+            [full.clone(), incremental]
+        );
+        assert_eq!(discover_chain(&full).unwrap(), [full]);
+    }
+
+    #[test]
+    fn base_of_an_incremental_is_not_modified() {
+        let dir = TempDir::new().unwrap();
+        let (full, incremental) = chain(&dir);
+        let options = AccessOptions::default();
+
+        assert!(is_chain_error(ArchiveAppender::open(
+            &full, "test", &options
+        )));
+        assert!(ArchiveAppender::open(&incremental, "test", &options).is_ok());
+    }
+
+    #[test]
+    fn missing_base_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let (full, incremental) = chain(&dir);
+        std::fs::remove_file(full).unwrap();
+
+        assert!(is_chain_error(discover_chain(&incremental)));
+    }
+}
@@ -1,7 +1,23 @@
 pub mod archive;
+pub mod codec;
 pub mod commands;
+pub mod crypto;
+pub mod error;
+pub mod incremental;
+pub mod lock;
 pub mod models;
+pub mod output;
 pub mod pager;
+pub mod parser;
+pub mod progress;
+pub mod reader;
+pub mod recovery;
+pub mod rewrite;
+pub mod signature;
 pub mod terminal;
 pub mod utils;
 pub mod validation;
+pub mod volume;
+pub mod writer;
+
+pub use error::{Error, Result};
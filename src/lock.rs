@@ -0,0 +1,206 @@
+use std::fs::{File, TryLockError};
+
+use crate::error::{Error, Result, io_context};
+use crate::volume::first_volume;
+
+/// Access a command needs to an archive
+#[derive(Clone, Copy, PartialEq)]
+pub enum LockMode {
+    /// Reading (list, extract, validate...), any number at a time
+    Shared,
+    /// Modifying, excludes every other dar process
+    Exclusive,
+}
+
+/// Advisory lock (flock) on an archive file, released when dropped
+///
+/// Exclusive holders note their operation in `<archive>.lock` so that waiting
+/// processes can tell what they wait for.
+pub struct ArchiveLock {
+    _file: File,
+    note: Option<String>,
+}
+
+impl ArchiveLock {
+    /// Lock archive at `path` (its first volume for split archives), waiting
+    /// for other processes only if `wait` is set
+    pub fn acquire(path: &str, mode: LockMode, operation: &str, wait: bool) -> Result<Self> {
+        // Every volume of a set is locked through the first one
+        let path = first_volume(path).unwrap_or_else(|| path.to_string());
+
+        loop {
+            let file = File::open(&path)
+                .map_err(|e| io_context(e, format!("Failed to open archive {}", path)))?;
+
+            let result = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock(),
+            };
+            match result {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let holder = describe_holder(&path, mode);
+                    if !wait {
+                        return Err(Error::Locked {
+                            operation: operation.to_string(),
+                            path,
+                            holder,
+                        });
+                    }
+                    eprintln!("Waiting for {}, it is {}...", path, holder);
+                    match mode {
+                        LockMode::Shared => file.lock_shared()?,
+                        LockMode::Exclusive => file.lock()?,
+                    }
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(io_context(e, format!("Cannot lock archive {}", path)));
+                }
+            }
+
+            // Purging renames a new archive over the one we waited for
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+
+            let note = match mode {
+                LockMode::Shared => None,
+                LockMode::Exclusive => {
+                    let note = note_path(&path);
+                    let _ = std::fs::write(
+                        &note,
+                        format!("{} (pid {})", operation, std::process::id()),
+                    );
+                    Some(note)
+                }
+            };
+
+            return Ok(Self { _file: file, note });
+        }
+    }
+}
+
+impl Drop for ArchiveLock {
+    fn drop(&mut self) {
+        if let Some(note) = &self.note {
+            let _ = std::fs::remove_file(note);
+        }
+    }
+}
+
+fn note_path(path: &str) -> String {
+    format!("{}.lock", path)
+}
+
+/// What holds the lock we could not get, for messages
+fn describe_holder(path: &str, mode: LockMode) -> String {
+    // A note left behind by a crashed process does not tell who holds the lock
+    // now, it is only trusted while an exclusive lock is held
+    let exclusive = match mode {
+        LockMode::Shared => true,
+        LockMode::Exclusive => File::open(path).is_ok_and(|file| file.try_lock_shared().is_err()),
+    };
+    if !exclusive {
+        return "being read by another dar process (list, extract, validate...)".to_string();
+    }
+
+    match std::fs::read_to_string(note_path(path)) {
+        Ok(operation) if !operation.trim().is_empty() => {
+            format!("being modified by dar {}", operation.trim())
+        }
+        _ => "being modified by another dar process".to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(locked), Ok(current)) => locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &str) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    use crate::volume::{volume_path, write_volumes};
+
+    fn archive(dir: &TempDir) -> String {
+        let path = dir.path().join("a.dar").to_string_lossy().to_string();
+        std::fs::write(&path, b"archive").unwrap();
+        path
+    }
+
+    /// Holder named by the error of a lock that could not be taken
+    fn holder(result: Result<ArchiveLock>) -> String {
+        match result {
+            Err(Error::Locked { holder, .. }) => holder,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("lock was granted"),
+        }
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let _first = ArchiveLock::acquire(&path, LockMode::Shared, "list", false).unwrap();
+        assert!(ArchiveLock::acquire(&path, LockMode::Shared, "extract", false).is_ok());
+    }
+
+    #[test]
+    fn shared_lock_conflicts_with_exclusive() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let shared = ArchiveLock::acquire(&path, LockMode::Shared, "list", false).unwrap();
+        let holder = holder(ArchiveLock::acquire(
+            &path,
+            LockMode::Exclusive,
+            "update",
+            false,
+        ));
+        assert!(holder.starts_with("being read"));
+
+        drop(shared);
+        assert!(ArchiveLock::acquire(&path, LockMode::Exclusive, "update", false).is_ok());
+    }
+
+    #[test]
+    fn exclusive_lock_names_its_operation() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let exclusive = ArchiveLock::acquire(&path, LockMode::Exclusive, "update", false).unwrap();
+        for mode in [LockMode::Shared, LockMode::Exclusive] {
+            let holder = holder(ArchiveLock::acquire(&path, mode, "list", false));
+            assert!(holder.starts_with("being modified by dar update"));
+        }
+
+        drop(exclusive);
+        assert!(!std::path::Path::new(&note_path(&path)).exists());
+    }
+
+    #[test]
+    fn volumes_are_locked_through_the_first_one() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("v.dar").to_string_lossy().to_string();
+        write_volumes(&base, &[0u8; 300], 164).unwrap();
+
+        let _exclusive = ArchiveLock::acquire(&base, LockMode::Exclusive, "update", false).unwrap();
+        match ArchiveLock::acquire(&volume_path(&base, 3), LockMode::Shared, "list", false) {
+            Err(Error::Locked { path, .. }) => assert_eq!(path, volume_path(&base, 1)),
+            _ => panic!("volume 3 was not locked through volume 1"),
+        }
+    }
+}
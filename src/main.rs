@@ -2,41 +2,70 @@
 extern crate clap;
 extern crate term;
 
-mod archive;
+use std::process::ExitCode;
+
 mod cli;
-mod commands;
-mod models;
-mod pager;
-mod terminal;
-mod utils;
-mod validation;
 
-fn main() -> eyre::Result<()> {
+use dar::commands;
+
+fn main() -> eyre::Result<ExitCode> {
     color_eyre::install()?;
 
     let matches = cli::build_cli().get_matches();
 
     match matches.subcommand() {
         Some(("create", sub_matches)) => {
-            commands::create::call(&sub_matches)?;
+            commands::create::call(sub_matches)?;
         }
         Some(("extract", sub_matches)) => {
-            commands::extract::call(&sub_matches)?;
+            commands::extract::call(sub_matches)?;
         }
         Some(("list", sub_matches)) => {
-            commands::list::call(&sub_matches)?;
+            commands::list::call(sub_matches)?;
         }
         Some(("append", sub_matches)) => {
-            commands::append::call(&sub_matches);
+            commands::append::call(sub_matches)?;
+        }
+        Some(("update", sub_matches)) => {
+            commands::update::call(sub_matches)?;
         }
         Some(("defragment", sub_matches)) => {
-            commands::defragment::call(&sub_matches);
+            commands::defragment::call(sub_matches)?;
         }
         Some(("validate", sub_matches)) => {
-            commands::validate::call(&sub_matches)?;
+            commands::validate::call(sub_matches)?;
+        }
+        Some(("sign", sub_matches)) => {
+            commands::sign::call(sub_matches)?;
+        }
+        Some(("repair", sub_matches)) => {
+            commands::repair::call(sub_matches)?;
+        }
+        Some(("recover", sub_matches)) => {
+            commands::recover::call(sub_matches)?;
+        }
+        Some(("salvage", sub_matches)) => {
+            commands::salvage::call(sub_matches)?;
+        }
+        Some(("delete", sub_matches)) => {
+            commands::delete::call(sub_matches)?;
+        }
+        Some(("diff", sub_matches)) => {
+            // Like diff(1): 1 when there are differences, 2 when comparing failed
+            return match commands::diff::call(sub_matches) {
+                Ok(false) => Ok(ExitCode::SUCCESS),
+                Ok(true) => Ok(ExitCode::from(1)),
+                Err(report) => {
+                    eprintln!("Error: {:?}", report);
+                    Ok(ExitCode::from(2))
+                }
+            };
+        }
+        Some(("keygen", sub_matches)) => {
+            commands::keygen::call(sub_matches)?;
         }
         _ => unreachable!(),
     };
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
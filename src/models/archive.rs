@@ -1,7 +1,8 @@
-use std::io::Write;
+use std::io::{Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use eyre::{Error, Result, eyre};
+use crate::crypto::{NONCE_SIZE, TAG_SIZE};
+use crate::error::Result;
 
 /// Archive header: 512 bytes fixed size
 /// Contains metadata for locating and validating archive sections
@@ -11,12 +12,35 @@ pub struct ArchiveHeader {
     pub total_files: u32,
     pub created_timestamp: u64,
     pub archive_checksum: [u8; 32], // BLAKE3 hash (computed last)
+    pub flags: u8,
+    pub kdf: Option<KdfParams>, // Present only for encrypted archives
+    pub recovery_offset: u64,   // Start of recovery section, 0 if absent
+    pub base_checksum: Option<[u8; 32]>, // Present only for incremental archives
 }
 
 impl ArchiveHeader {
     pub const MAGIC: &'static [u8] = b"DAR\0";
-    pub const VERSION: &'static [u8] = b"0004";
+    pub const VERSION: &'static [u8] = b"0005";
+    /// Version 0004 lacks local entry headers but is otherwise readable
+    pub const LEGACY_VERSION: &'static [u8] = b"0004";
     pub const SIZE: usize = 512;
+    /// Offset of the recovery section pointer within the header
+    pub const RECOVERY_OFFSET: usize = 97;
+    /// Offset of the base archive checksum within the header
+    pub const BASE_CHECKSUM_OFFSET: usize = 105;
+
+    /// Data blocks and index are encrypted
+    pub const FLAG_ENCRYPTED: u8 = 0x01;
+    /// File key is wrapped to X25519 recipients instead of derived from a passphrase
+    pub const FLAG_RECIPIENTS: u8 = 0x02;
+    /// Archive and entry checksums are keyed BLAKE3 MACs over a shared secret
+    pub const FLAG_MAC: u8 = 0x04;
+    /// Archive only stores changes against the base archive
+    pub const FLAG_INCREMENTAL: u8 = 0x08;
+
+    pub fn is_supported_version(version: &[u8]) -> bool {
+        version == Self::VERSION || version == Self::LEGACY_VERSION
+    }
 
     pub fn new(data_section_start: u64, index_section_start: u64, total_files: u32) -> Self {
         let created_timestamp = SystemTime::now()
@@ -30,9 +54,25 @@ impl ArchiveHeader {
             total_files,
             created_timestamp,
             archive_checksum: [0u8; 32],
+            flags: 0,
+            kdf: None,
+            recovery_offset: 0,
+            base_checksum: None,
         }
     }
 
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & Self::FLAG_ENCRYPTED != 0
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.flags & Self::FLAG_MAC != 0
+    }
+
+    pub fn is_incremental(&self) -> bool {
+        self.flags & Self::FLAG_INCREMENTAL != 0
+    }
+
     pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
         let start_pos = buf.len();
 
@@ -43,23 +83,173 @@ impl ArchiveHeader {
         buf.write_all(&self.total_files.to_be_bytes())?;
         buf.write_all(&self.created_timestamp.to_be_bytes())?;
         buf.write_all(&self.archive_checksum)?;
-        buf.push(0u8); // flags (reserved)
+        buf.push(self.flags);
+
+        match self.kdf {
+            Some(ref kdf) => kdf.write_to(buf)?,
+            None => buf.write_all(&[0u8; KdfParams::SIZE])?,
+        }
+        buf.write_all(&self.recovery_offset.to_be_bytes())?;
+        buf.write_all(&self.base_checksum.unwrap_or([0u8; 32]))?;
 
         // Pad to exactly 512 bytes from start position
         let bytes_written = buf.len() - start_pos;
-        let padding = if bytes_written < Self::SIZE {
-            Self::SIZE - bytes_written
-        } else {
-            0
-        };
+        let padding = Self::SIZE.saturating_sub(bytes_written);
         buf.write_all(&vec![0u8; padding])?;
 
         Ok(())
     }
 }
 
+/// Argon2id parameters used to derive the key of an encrypted archive
+/// Stored in the header right after the flags byte
+#[derive(Clone)]
+pub struct KdfParams {
+    pub salt: [u8; 16],
+    pub m_cost: u32, // memory in KiB
+    pub t_cost: u32, // iterations
+    pub p_cost: u32, // parallelism
+}
+
+impl KdfParams {
+    /// Offset of the parameters within the header
+    pub const OFFSET: usize = 69;
+    pub const SIZE: usize = 28;
+
+    /// Read parameters from raw header bytes, if the archive is passphrase-encrypted
+    pub fn from_header(buf: &[u8]) -> Option<Self> {
+        let flags = buf[68];
+        if flags & ArchiveHeader::FLAG_ENCRYPTED == 0 || flags & ArchiveHeader::FLAG_RECIPIENTS != 0
+        {
+            return None;
+        }
+
+        let params = &buf[Self::OFFSET..Self::OFFSET + Self::SIZE];
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&params[0..16]);
+
+        Some(Self {
+            salt,
+            m_cost: u32::from_be_bytes(params[16..20].try_into().unwrap()),
+            t_cost: u32::from_be_bytes(params[20..24].try_into().unwrap()),
+            p_cost: u32::from_be_bytes(params[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_all(&self.salt)?;
+        buf.write_all(&self.m_cost.to_be_bytes())?;
+        buf.write_all(&self.t_cost.to_be_bytes())?;
+        buf.write_all(&self.p_cost.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+/// Recipient stanza: per-archive file key wrapped to one X25519 public key
+/// Stanzas form the recipient section between the header and the data section:
+/// [count: u32][stanza]*
+pub struct RecipientStanza {
+    pub ephemeral_public: [u8; 32],
+    pub wrapped_key: [u8; 72], // nonce + sealed file key + tag
+}
+
+impl RecipientStanza {
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_all(&self.ephemeral_public)?;
+        buf.write_all(&self.wrapped_key)?;
+        Ok(())
+    }
+
+    /// Write complete recipient section
+    pub fn write_section(stanzas: &[Self], buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_all(&(stanzas.len() as u32).to_be_bytes())?;
+        for stanza in stanzas {
+            stanza.write_to(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Read complete recipient section
+    pub fn read_section<R: Read>(reader: &mut R) -> Result<Vec<Self>> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        let count = u32::from_be_bytes(buf);
+
+        let mut stanzas = Vec::new();
+        for _ in 0..count {
+            let mut stanza = Self {
+                ephemeral_public: [0u8; 32],
+                wrapped_key: [0u8; 72],
+            };
+            reader.read_exact(&mut stanza.ephemeral_public)?;
+            reader.read_exact(&mut stanza.wrapped_key)?;
+            stanzas.push(stanza);
+        }
+
+        Ok(stanzas)
+    }
+}
+
+/// Signature block: Ed25519 signature over the archive checksum
+/// Embedded in the header padding (excluded from the archive checksum)
+/// or stored as-is in a `.sig` sidecar file
+pub struct SignatureBlock {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl SignatureBlock {
+    pub const MAGIC: &'static [u8] = b"DSIG";
+    /// Offset of the embedded block within the header
+    pub const OFFSET: usize = 400;
+    pub const SIZE: usize = 100;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[0..4].copy_from_slice(Self::MAGIC);
+        buf[4..36].copy_from_slice(&self.public_key);
+        buf[36..100].copy_from_slice(&self.signature);
+        buf
+    }
+
+    /// Parse block, returns None if magic is absent (archive not signed)
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE || &buf[0..4] != Self::MAGIC {
+            return None;
+        }
+
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&buf[4..36]);
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(&buf[36..100]);
+
+        Some(Self {
+            public_key,
+            signature,
+        })
+    }
+}
+
+/// Local entry header: copy of the index entry in front of each data entry
+/// Lets `dar salvage` rebuild entries when the index or end record is lost:
+/// [magic "DENT"][body_length: u32][body: index entry, sealed if encrypted][BLAKE3 of body]
+pub struct LocalEntryHeader;
+
+impl LocalEntryHeader {
+    pub const MAGIC: &'static [u8] = b"DENT";
+    /// Magic, body length and body checksum
+    pub const FIXED_SIZE: usize = 40;
+
+    /// Size of the local header of an entry
+    pub fn size(entry: &ArchiveIndexEntry, encrypted: bool) -> usize {
+        let seal_overhead = if encrypted { NONCE_SIZE + TAG_SIZE } else { 0 };
+        Self::FIXED_SIZE + entry.encoded_len() + seal_overhead
+    }
+}
+
 /// Archive index entry: file metadata for later retrieval
 /// Each entry is prefixed with its length for safe parsing
+#[derive(Clone)]
 pub struct ArchiveIndexEntry {
     pub path: String,
     pub data_offset: u64,
@@ -71,52 +261,99 @@ pub struct ArchiveIndexEntry {
     pub gid: u8,
     pub permissions: u16,
     pub checksum: [u8; 32], // BLAKE3 of uncompressed data
+    pub flags: u8,
+    /// Associated data the entry's data is sealed with (see `crypto::entry_data_aad`),
+    /// None for plain entries and ones sealed before it was stored
+    pub data_aad: Option<Vec<u8>>,
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+/// Codec of entry data, by its id byte (see `crate::codec`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompressionAlgorithm {
     None,
     Brotli,
     Zstandard,
     Lzma,
+    /// Codec registered by a library user, ids 4-255
+    Custom(u8),
 }
 
-impl TryFrom<u8> for CompressionAlgorithm {
-    type Error = Error;
-
-    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
-        return match value {
-            0 => Ok(CompressionAlgorithm::None),
-            1 => Ok(CompressionAlgorithm::Brotli),
-            2 => Ok(CompressionAlgorithm::Zstandard),
-            3 => Ok(CompressionAlgorithm::Lzma),
-            _ => Err(eyre!("Invalid value for CompressionAlgorithm")),
-        };
+impl From<u8> for CompressionAlgorithm {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CompressionAlgorithm::None,
+            1 => CompressionAlgorithm::Brotli,
+            2 => CompressionAlgorithm::Zstandard,
+            3 => CompressionAlgorithm::Lzma,
+            id => CompressionAlgorithm::Custom(id),
+        }
     }
 }
 
-impl Into<u8> for CompressionAlgorithm {
-    fn into(self: CompressionAlgorithm) -> u8 {
-        return match self {
+impl From<CompressionAlgorithm> for u8 {
+    fn from(val: CompressionAlgorithm) -> Self {
+        match val {
             CompressionAlgorithm::None => 0,
             CompressionAlgorithm::Brotli => 1,
             CompressionAlgorithm::Zstandard => 2,
             CompressionAlgorithm::Lzma => 3,
-        };
+            CompressionAlgorithm::Custom(id) => id,
+        }
     }
 }
 
 impl CompressionAlgorithm {
     pub fn as_byte(&self) -> u8 {
-        return *self as u8;
+        (*self).into()
     }
 }
 
 impl ArchiveIndexEntry {
+    /// Tombstone: path was deleted, entry has no data
+    pub const FLAG_DELETED: u8 = 0x01;
+
+    /// Size of the encoded entry including its length prefix
+    pub fn encoded_len(&self) -> usize {
+        // length, path length, path, offsets and sizes, algo, mtime, uid, gid, perm, checksum
+        4 + 4
+            + self.path.len()
+            + 8 * 3
+            + 1
+            + 8
+            + 1
+            + 1
+            + 2
+            + 32
+            + 1
+            + self.data_aad.as_ref().map_or(0, |aad| 2 + aad.len())
+    }
+
+    /// Tombstone recording that path was deleted since the base archive
+    pub fn tombstone(path: String) -> Self {
+        Self {
+            path,
+            data_offset: 0,
+            uncompressed_size: 0,
+            compressed_size: 0,
+            compression_algorithm: CompressionAlgorithm::None,
+            modification_time: 0,
+            uid: 0,
+            gid: 0,
+            permissions: 0,
+            checksum: [0u8; 32],
+            flags: Self::FLAG_DELETED,
+            data_aad: None,
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.flags & Self::FLAG_DELETED != 0
+    }
+
     /// Write entry to buffer in binary format
     /// Format: [entry_length: u32][path_length: u32][path: utf8][data_offset: u64][uncompressed_size: u64]
     ///         [compressed_size: u64][compression_algo: u8][mod_time: u64][uid: u8][gid: u8][perm: u16][checksum: 32bytes]
+    ///         [flags: u8]([data_aad_length: u16][data_aad], sealed entries only)
     pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
         let start_len = buf.len();
 
@@ -138,6 +375,11 @@ impl ArchiveIndexEntry {
         buf.write_all(&self.gid.to_be_bytes())?;
         buf.write_all(&self.permissions.to_be_bytes())?;
         buf.write_all(&self.checksum)?;
+        buf.push(self.flags);
+        if let Some(aad) = &self.data_aad {
+            buf.write_all(&(aad.len() as u16).to_be_bytes())?;
+            buf.write_all(aad)?;
+        }
 
         // Calculate and update entry length (excluding the 4-byte length field itself)
         let entry_len = (buf.len() - start_len - 4) as u32;
@@ -147,12 +389,121 @@ impl ArchiveIndexEntry {
     }
 }
 
+/// Volume header: 64 bytes in front of every volume of a split archive
+/// Volume payloads concatenated form the archive, all offsets are logical
+pub struct VolumeHeader {
+    pub set_id: [u8; 16], // random, shared by all volumes of an archive
+    pub number: u32,      // 1-based
+    pub count: u32,
+    pub offset: u64, // logical offset of this volume's payload
+    pub length: u64, // payload length
+}
+
+impl VolumeHeader {
+    pub const MAGIC: &'static [u8] = b"DVOL";
+    pub const SIZE: usize = 64;
+
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start_pos = buf.len();
+
+        buf.write_all(Self::MAGIC)?;
+        buf.write_all(&self.set_id)?;
+        buf.write_all(&self.number.to_be_bytes())?;
+        buf.write_all(&self.count.to_be_bytes())?;
+        buf.write_all(&self.offset.to_be_bytes())?;
+        buf.write_all(&self.length.to_be_bytes())?;
+
+        // Pad to exactly 64 bytes from start position
+        let bytes_written = buf.len() - start_pos;
+        buf.write_all(&vec![0u8; Self::SIZE - bytes_written])?;
+
+        Ok(())
+    }
+
+    /// Parse header, returns None if magic is absent (not a volume)
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE || &buf[0..4] != Self::MAGIC {
+            return None;
+        }
+
+        let mut set_id = [0u8; 16];
+        set_id.copy_from_slice(&buf[4..20]);
+
+        Some(Self {
+            set_id,
+            number: u32::from_be_bytes(buf[20..24].try_into().unwrap()),
+            count: u32::from_be_bytes(buf[24..28].try_into().unwrap()),
+            offset: u64::from_be_bytes(buf[28..36].try_into().unwrap()),
+            length: u64::from_be_bytes(buf[36..44].try_into().unwrap()),
+        })
+    }
+}
+
+/// Index trailer: 36 bytes following every index section written since format
+/// generations were introduced, counted in the index length
+/// Links the index to the one it superseded, so appends form a history
+pub struct IndexTrailer {
+    pub previous_offset: u64, // index section this index superseded, 0 if none
+    pub previous_length: u64,
+    pub created: u64, // UNIX time the index was written
+}
+
+impl IndexTrailer {
+    pub const MAGIC: &'static [u8] = b"DGEN";
+    pub const SIZE: usize = 36;
+
+    pub fn new(previous_offset: u64, previous_length: u64) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            previous_offset,
+            previous_length,
+            created,
+        }
+    }
+
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start_pos = buf.len();
+
+        buf.write_all(Self::MAGIC)?;
+        buf.write_all(&self.previous_offset.to_be_bytes())?;
+        buf.write_all(&self.previous_length.to_be_bytes())?;
+        buf.write_all(&self.created.to_be_bytes())?;
+
+        // Truncated BLAKE3 tells a trailer apart from index bytes that happen to match the magic
+        let check = blake3::hash(&buf[start_pos..]);
+        buf.write_all(&check.as_bytes()[..8])?;
+
+        Ok(())
+    }
+
+    /// Parse trailer, returns None if absent (index written by an older version)
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE
+            || &buf[0..4] != Self::MAGIC
+            || blake3::hash(&buf[0..28]).as_bytes()[..8] != buf[28..36]
+        {
+            return None;
+        }
+
+        Some(Self {
+            previous_offset: u64::from_be_bytes(buf[4..12].try_into().unwrap()),
+            previous_length: u64::from_be_bytes(buf[12..20].try_into().unwrap()),
+            created: u64::from_be_bytes(buf[20..28].try_into().unwrap()),
+        })
+    }
+}
+
 /// Archive end record: 64 bytes fixed size
 /// Located at the end of the archive for quick validation and index location
 pub struct ArchiveEndRecord {
     pub index_offset: u64,
     pub index_length: u64,
     pub archive_checksum: [u8; 32], // BLAKE3 of entire archive
+    pub recovery_offset: u64,       // Start of recovery section, 0 if absent
 }
 
 impl ArchiveEndRecord {
@@ -164,6 +515,7 @@ impl ArchiveEndRecord {
             index_offset,
             index_length,
             archive_checksum: [0u8; 32],
+            recovery_offset: 0,
         }
     }
 
@@ -175,14 +527,11 @@ impl ArchiveEndRecord {
         buf.write_all(&self.index_length.to_be_bytes())?;
         buf.write_all(&self.archive_checksum)?;
         buf.push(0u8); // flags (reserved)
+        buf.write_all(&self.recovery_offset.to_be_bytes())?;
 
         // Pad to exactly 64 bytes from start position
         let bytes_written = buf.len() - start_pos;
-        let padding = if bytes_written < Self::SIZE {
-            Self::SIZE - bytes_written
-        } else {
-            0
-        };
+        let padding = Self::SIZE.saturating_sub(bytes_written);
         buf.write_all(&vec![0u8; padding])?;
 
         Ok(())
@@ -0,0 +1,297 @@
+use clap::ArgMatches;
+use serde_json::{Map, Value, json};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::codec::codec;
+use crate::models::archive::{ArchiveIndexEntry, CompressionAlgorithm};
+use crate::progress::{Event, ProgressSink};
+
+/// Output of the global `--format` option
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    /// One JSON document once the command is done
+    Json,
+    /// One JSON record per line as the command goes
+    Ndjson,
+}
+
+impl OutputFormat {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        match matches.get_one::<String>("format").map(String::as_str) {
+            Some("json") => Self::Json,
+            Some("ndjson") => Self::Ndjson,
+            _ => Self::Text,
+        }
+    }
+
+    pub fn is_text(self) -> bool {
+        self == Self::Text
+    }
+}
+
+/// Machine-readable report of a command: a head describing what it works on,
+/// one record per item (entry, check) and a summary
+///
+/// As JSON it is printed as one object with the head's fields, the items under
+/// `items_key` and the summary under "summary". As NDJSON every part is a line
+/// of its own, told apart by its "type" field.
+pub struct Report {
+    format: OutputFormat,
+    output: Box<dyn Write>,
+    items_key: &'static str,
+    item_type: &'static str,
+    document: Map<String, Value>,
+    items: Vec<Value>,
+}
+
+impl Report {
+    /// Start report of `head_type` (e.g. "archive") whose items are of `item_type`
+    pub fn new(
+        format: OutputFormat,
+        head_type: &'static str,
+        item_type: &'static str,
+        items_key: &'static str,
+        head: Value,
+    ) -> Self {
+        Self::with_output(
+            format,
+            head_type,
+            item_type,
+            items_key,
+            head,
+            Box::new(io::stdout()),
+        )
+    }
+
+    /// Start report printed to `output` instead of stdout
+    pub fn with_output(
+        format: OutputFormat,
+        head_type: &'static str,
+        item_type: &'static str,
+        items_key: &'static str,
+        head: Value,
+        output: Box<dyn Write>,
+    ) -> Self {
+        let document = match head {
+            Value::Object(fields) => fields,
+            _ => Map::new(),
+        };
+        let mut report = Self {
+            format,
+            output,
+            items_key,
+            item_type,
+            document,
+            items: Vec::new(),
+        };
+        if format == OutputFormat::Ndjson {
+            report.print_record(head_type, report.document.clone());
+        }
+        report
+    }
+
+    pub fn item(&mut self, item: Value) {
+        match self.format {
+            OutputFormat::Ndjson => self.print_record(self.item_type, object(item)),
+            _ => self.items.push(item),
+        }
+    }
+
+    /// Print summary, and with JSON the whole document
+    pub fn finish(&mut self, summary: Value) {
+        match self.format {
+            OutputFormat::Ndjson => self.print_record("summary", object(summary)),
+            _ => {
+                let items = std::mem::take(&mut self.items);
+                let mut document = std::mem::take(&mut self.document);
+                document.insert(self.items_key.to_string(), Value::Array(items));
+                document.insert("summary".to_string(), summary);
+                self.print_line(
+                    &serde_json::to_string_pretty(&Value::Object(document)).unwrap_or_default(),
+                );
+            }
+        }
+    }
+
+    fn print_record(&mut self, record_type: &str, fields: Map<String, Value>) {
+        let mut record = Map::from_iter([("type".to_string(), json!(record_type))]);
+        record.extend(fields);
+        self.print_line(&Value::Object(record).to_string());
+    }
+
+    /// A reader that went away (`| head`) is not an error
+    fn print_line(&mut self, line: &str) {
+        let _ = writeln!(self.output, "{}", line);
+    }
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(fields) => fields,
+        value => Map::from_iter([("value".to_string(), value)]),
+    }
+}
+
+/// Every field of an index entry
+pub fn entry_json(entry: &ArchiveIndexEntry) -> Value {
+    json!({
+        "path": entry.path,
+        "deleted": entry.is_deleted(),
+        "data_offset": entry.data_offset,
+        "size": entry.uncompressed_size,
+        "compressed_size": entry.compressed_size,
+        "algorithm": algorithm_name(entry.compression_algorithm),
+        "algorithm_id": entry.compression_algorithm.as_byte(),
+        "mtime": entry.modification_time,
+        "uid": entry.uid,
+        "gid": entry.gid,
+        "mode": format!("{:04o}", entry.permissions),
+        "checksum": hex::encode(entry.checksum),
+        "flags": entry.flags,
+    })
+}
+
+/// Name of the registered codec, or its id when it is not registered
+pub fn algorithm_name(algorithm: CompressionAlgorithm) -> String {
+    codec(algorithm)
+        .map(|codec| codec.name().to_string())
+        .unwrap_or_else(|_| format!("codec {}", algorithm.as_byte()))
+}
+
+/// Adds a record per stored or skipped file to a report, `action` naming what
+/// happened to stored ones ("added", "extracted")
+pub struct ReportSink {
+    report: Rc<RefCell<Report>>,
+    action: &'static str,
+}
+
+impl ReportSink {
+    pub fn new(report: Rc<RefCell<Report>>, action: &'static str) -> Self {
+        Self { report, action }
+    }
+}
+
+impl ProgressSink for ReportSink {
+    fn event(&mut self, event: &Event) {
+        let item = match *event {
+            Event::FileFinished {
+                name,
+                source,
+                size,
+                stored_size,
+                algorithm,
+            } => json!({
+                "path": name,
+                "status": self.action,
+                "source": source.map(|source| source.display().to_string()),
+                "size": size,
+                "stored_size": stored_size,
+                "algorithm": algorithm_name(algorithm),
+            }),
+            Event::Skipped { name, size } => json!({
+                "path": name,
+                "status": "unchanged",
+                "size": size,
+            }),
+            Event::Error { name, error } => json!({
+                "path": name,
+                "status": "failed",
+                "error": error.to_string(),
+            }),
+            _ => return,
+        };
+        self.report.borrow_mut().item(item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output kept readable after the report took ownership of it
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Report of an archive with two entries, as printed in `format`
+    fn report(format: OutputFormat) -> String {
+        let captured = Captured::default();
+        let mut report = Report::with_output(
+            format,
+            "archive",
+            "entry",
+            "entries",
+            json!({ "archive": "a.dar" }),
+            Box::new(captured.clone()),
+        );
+        report.item(json!({ "path": "a.txt" }));
+        report.item(json!({ "path": "b.txt" }));
+        report.finish(json!({ "entries": 2 }));
+        drop(report);
+
+        String::from_utf8(captured.0.take()).unwrap()
+    }
+
+    #[test]
+    fn json_is_one_document() {
+        let document: Value = serde_json::from_str(&report(OutputFormat::Json)).unwrap();
+        assert_eq!(
+            document,
+            json!({
+                "archive": "a.dar",
+                "entries": [{ "path": "a.txt" }, { "path": "b.txt" }],
+                "summary": { "entries": 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn ndjson_is_one_typed_record_per_line() {
+        let records: Vec<Value> = report(OutputFormat::Ndjson)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            [
+                json!({ "type": "archive", "archive": "a.dar" }),
+                json!({ "type": "entry", "path": "a.txt" }),
+                json!({ "type": "entry", "path": "b.txt" }),
+                json!({ "type": "summary", "entries": 2 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn ndjson_wraps_values_that_are_not_objects() {
+        let captured = Captured::default();
+        let mut report = Report::with_output(
+            OutputFormat::Ndjson,
+            "archive",
+            "entry",
+            "entries",
+            json!(null),
+            Box::new(captured.clone()),
+        );
+        report.item(json!("a.txt"));
+        drop(report);
+
+        let output = String::from_utf8(captured.0.take()).unwrap();
+        assert_eq!(
+            output,
+            "{\"type\":\"archive\"}\n{\"type\":\"entry\",\"value\":\"a.txt\"}\n"
+        );
+    }
+}
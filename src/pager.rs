@@ -1,4 +1,4 @@
-use std::io::{self, Write, IsTerminal};
+use std::io::{self, IsTerminal, Write};
 use std::process::{Command, Stdio};
 
 /// A writer that automatically pages output if connected to a terminal
@@ -54,16 +54,16 @@ impl Write for PagerWriter {
 fn create_pager() -> io::Result<impl Write> {
     let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
     let mut parts = pager.split_whitespace();
-    let cmd = parts.next().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::Other, "Invalid PAGER")
-    })?;
+    let cmd = parts
+        .next()
+        .ok_or_else(|| io::Error::other("Invalid PAGER"))?;
 
     let child = Command::new(cmd)
         .args(parts)
         .stdin(Stdio::piped())
         .spawn()?;
 
-    child.stdin.ok_or_else(|| {
-        io::Error::new(io::ErrorKind::Other, "Failed to get pager stdin")
-    })
+    child
+        .stdin
+        .ok_or_else(|| io::Error::other("Failed to get pager stdin"))
 }
@@ -0,0 +1,458 @@
+use std::fmt;
+use std::path::{Component, Path};
+
+use crate::models::archive::{
+    ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry, CompressionAlgorithm, KdfParams,
+};
+
+/// Longest path an index entry may have, in bytes
+pub const MAX_PATH_LENGTH: usize = 4096;
+/// Longest index entry (fixed fields, path and room for future fields)
+pub const MAX_ENTRY_LENGTH: usize = MAX_PATH_LENGTH + 512;
+/// Most entries an index may have
+pub const MAX_ENTRY_COUNT: u32 = 1 << 24;
+/// Largest entry size (stored or uncompressed) accepted
+pub const MAX_ENTRY_SIZE: u64 = 1 << 40;
+/// Argon2 memory cost limit in KiB (1 GiB), so a header cannot exhaust memory
+pub const MAX_KDF_MEMORY: u32 = 1024 * 1024;
+/// Argon2 pass limit, so a header cannot make unlocking take forever
+pub const MAX_KDF_PASSES: u32 = 16;
+/// Argon2 lane limit
+pub const MAX_KDF_LANES: u32 = 16;
+
+/// Smallest index entry: length, path length and fixed fields of an entry
+/// written before flags existed
+const MIN_ENTRY_LENGTH: usize = 4 + 4 + 8 + 8 + 8 + 1 + 8 + 1 + 1 + 2 + 32;
+
+/// Why archive bytes could not be parsed
+#[derive(Debug)]
+pub enum ParseError {
+    /// Bytes end before the field
+    Truncated {
+        field: &'static str,
+    },
+    /// Structure does not start with its magic
+    BadMagic {
+        structure: &'static str,
+    },
+    UnsupportedVersion([u8; 4]),
+    /// Field is above its sanity limit
+    TooLarge {
+        field: &'static str,
+        value: u64,
+        limit: u64,
+    },
+    /// Offset points outside the part of the archive it belongs to
+    OutOfBounds {
+        field: &'static str,
+        value: u64,
+    },
+    InvalidUtf8 {
+        field: &'static str,
+    },
+    /// Entry path is empty, absolute or leaves the extraction directory
+    UnsafePath(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { field } => write!(f, "Truncated {}", field),
+            Self::BadMagic { structure } => write!(f, "Invalid {} magic", structure),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported version {}",
+                    String::from_utf8_lossy(version)
+                )
+            }
+            Self::TooLarge {
+                field,
+                value,
+                limit,
+            } => write!(f, "{} {} exceeds limit {}", field, value, limit),
+            Self::OutOfBounds { field, value } => write!(f, "{} {} is out of bounds", field, value),
+            Self::InvalidUtf8 { field } => write!(f, "{} is not valid UTF-8", field),
+            Self::UnsafePath(path) => write!(f, "Unsafe entry path {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Bounds-checked big-endian reader over untrusted bytes
+struct Fields<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize, field: &'static str) -> Result<&'a [u8], ParseError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(ParseError::Truncated { field })?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], ParseError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N, field)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, ParseError> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.array(field)?))
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.array(field)?))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ParseError> {
+        Ok(u64::from_be_bytes(self.array(field)?))
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+}
+
+fn check_limit(field: &'static str, value: u64, limit: u64) -> Result<(), ParseError> {
+    if value > limit {
+        return Err(ParseError::TooLarge {
+            field,
+            value,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Parse the 512-byte archive header
+pub fn parse_header(bytes: &[u8]) -> Result<ArchiveHeader, ParseError> {
+    if bytes.len() < ArchiveHeader::SIZE {
+        return Err(ParseError::Truncated { field: "header" });
+    }
+    let mut fields = Fields::new(&bytes[..ArchiveHeader::SIZE]);
+
+    if fields.take(4, "header magic")? != ArchiveHeader::MAGIC {
+        return Err(ParseError::BadMagic {
+            structure: "header",
+        });
+    }
+    let version: [u8; 4] = fields.array("version")?;
+    if !ArchiveHeader::is_supported_version(&version) {
+        return Err(ParseError::UnsupportedVersion(version));
+    }
+
+    let data_section_start = fields.u64("data section offset")?;
+    let index_section_start = fields.u64("index section offset")?;
+    let total_files = fields.u32("file count")?;
+    let created_timestamp = fields.u64("creation time")?;
+    let archive_checksum = fields.array("archive checksum")?;
+    let flags = fields.u8("flags")?;
+
+    if data_section_start < ArchiveHeader::SIZE as u64 {
+        return Err(ParseError::OutOfBounds {
+            field: "Data section offset",
+            value: data_section_start,
+        });
+    }
+    if index_section_start < data_section_start {
+        return Err(ParseError::OutOfBounds {
+            field: "Index section offset",
+            value: index_section_start,
+        });
+    }
+    check_limit("File count", total_files as u64, MAX_ENTRY_COUNT as u64)?;
+
+    let kdf = KdfParams::from_header(bytes);
+    if let Some(kdf) = &kdf {
+        check_limit("KDF memory cost", kdf.m_cost as u64, MAX_KDF_MEMORY as u64)?;
+        check_limit("KDF passes", kdf.t_cost as u64, MAX_KDF_PASSES as u64)?;
+        check_limit("KDF lanes", kdf.p_cost as u64, MAX_KDF_LANES as u64)?;
+    }
+
+    let mut fields = Fields::new(&bytes[ArchiveHeader::RECOVERY_OFFSET..ArchiveHeader::SIZE]);
+    let recovery_offset = fields.u64("recovery offset")?;
+    if recovery_offset != 0 && recovery_offset < index_section_start {
+        return Err(ParseError::OutOfBounds {
+            field: "Recovery offset",
+            value: recovery_offset,
+        });
+    }
+    let base_checksum: [u8; 32] =
+        Fields::new(&bytes[ArchiveHeader::BASE_CHECKSUM_OFFSET..ArchiveHeader::SIZE])
+            .array("base checksum")?;
+
+    Ok(ArchiveHeader {
+        data_section_start,
+        index_section_start,
+        total_files,
+        created_timestamp,
+        archive_checksum,
+        flags,
+        kdf,
+        recovery_offset,
+        base_checksum: (flags & ArchiveHeader::FLAG_INCREMENTAL != 0).then_some(base_checksum),
+    })
+}
+
+/// Parse the 64-byte end record found at the end of a file of `file_size` bytes
+pub fn parse_end_record(bytes: &[u8], file_size: u64) -> Result<ArchiveEndRecord, ParseError> {
+    let mut fields = Fields::new(bytes);
+
+    if fields.take(4, "end record magic")? != ArchiveEndRecord::MAGIC {
+        return Err(ParseError::BadMagic {
+            structure: "end record",
+        });
+    }
+    let index_offset = fields.u64("index offset")?;
+    let index_length = fields.u64("index length")?;
+    let archive_checksum = fields.array("archive checksum")?;
+    let _flags = fields.u8("end record flags")?;
+    let recovery_offset = fields.u64("recovery offset")?;
+
+    // Index and recovery section precede the end record, anything else is a
+    // leftover of an interrupted append
+    let end_record_offset = file_size.saturating_sub(ArchiveEndRecord::SIZE as u64);
+    if index_offset
+        .checked_add(index_length)
+        .is_none_or(|index_end| index_end > end_record_offset)
+    {
+        return Err(ParseError::OutOfBounds {
+            field: "Index offset",
+            value: index_offset,
+        });
+    }
+    if recovery_offset > end_record_offset {
+        return Err(ParseError::OutOfBounds {
+            field: "Recovery offset",
+            value: recovery_offset,
+        });
+    }
+
+    Ok(ArchiveEndRecord {
+        index_offset,
+        index_length,
+        archive_checksum,
+        recovery_offset,
+    })
+}
+
+/// Check entry length read from the 4-byte prefix before reading the entry
+pub fn check_entry_length(length: u32) -> Result<usize, ParseError> {
+    let length = length as usize;
+    check_limit("Entry length", length as u64, MAX_ENTRY_LENGTH as u64)?;
+    if length < MIN_ENTRY_LENGTH - 4 {
+        return Err(ParseError::Truncated {
+            field: "index entry",
+        });
+    }
+    Ok(length)
+}
+
+/// Check entry count of an index
+pub fn check_entry_count(count: u32) -> Result<u32, ParseError> {
+    check_limit("Entry count", count as u64, MAX_ENTRY_COUNT as u64)?;
+    Ok(count)
+}
+
+/// Parse index entry body (everything after its length prefix)
+pub fn parse_entry(bytes: &[u8]) -> Result<ArchiveIndexEntry, ParseError> {
+    let mut fields = Fields::new(bytes);
+
+    let path_length = fields.u32("path length")? as usize;
+    check_limit("Path length", path_length as u64, MAX_PATH_LENGTH as u64)?;
+    let path = std::str::from_utf8(fields.take(path_length, "path")?)
+        .map_err(|_| ParseError::InvalidUtf8 { field: "Path" })?
+        .to_string();
+    check_path(&path)?;
+
+    let data_offset = fields.u64("data offset")?;
+    let uncompressed_size = fields.u64("uncompressed size")?;
+    let compressed_size = fields.u64("compressed size")?;
+    check_limit("Uncompressed size", uncompressed_size, MAX_ENTRY_SIZE)?;
+    check_limit("Compressed size", compressed_size, MAX_ENTRY_SIZE)?;
+    if data_offset.checked_add(compressed_size).is_none() {
+        return Err(ParseError::OutOfBounds {
+            field: "Data offset",
+            value: data_offset,
+        });
+    }
+
+    let algorithm = fields.u8("compression algorithm")?;
+    // Unknown codec ids parse, reading their data fails unless the codec is registered
+    let compression_algorithm = CompressionAlgorithm::from(algorithm);
+
+    let modification_time = fields.u64("modification time")?;
+    let uid = fields.u8("uid")?;
+    let gid = fields.u8("gid")?;
+    let permissions = fields.u16("permissions")?;
+    let checksum = fields.array("checksum")?;
+
+    // Flags were added later, older entries end at the checksum
+    let flags = match fields.remaining() {
+        0 => 0,
+        _ => fields.u8("flags")?,
+    };
+    // So was the associated data of sealed entry data
+    let data_aad = match fields.remaining() {
+        0 => None,
+        _ => {
+            let length = fields.u16("data AAD length")? as usize;
+            Some(fields.take(length, "data AAD")?.to_vec())
+        }
+    };
+
+    Ok(ArchiveIndexEntry {
+        path,
+        data_offset,
+        uncompressed_size,
+        compressed_size,
+        compression_algorithm,
+        modification_time,
+        uid,
+        gid,
+        permissions,
+        checksum,
+        flags,
+        data_aad,
+    })
+}
+
+/// Entry paths are joined to the extraction directory, they must stay inside it
+pub fn check_path(path: &str) -> Result<(), ParseError> {
+    check_limit("Path length", path.len() as u64, MAX_PATH_LENGTH as u64)?;
+    let escapes = path.is_empty()
+        || path.contains('\0')
+        || Path::new(path)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(ParseError::UnsafePath(path.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn header_bytes() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ArchiveHeader::new(512, 1024, 3)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn end_record_bytes(index_offset: u64, index_length: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ArchiveEndRecord::new(index_offset, index_length)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn is_malformed<T>(result: Result<T, ParseError>) -> bool {
+        matches!(result.map_err(Error::from), Err(Error::Malformed(_)))
+    }
+
+    #[test]
+    fn header_parses() {
+        let header = parse_header(&header_bytes()).unwrap();
+        assert_eq!(header.index_section_start, 1024);
+        assert_eq!(header.total_files, 3);
+    }
+
+    #[test]
+    fn truncated_header_is_malformed() {
+        let bytes = header_bytes();
+        for length in [0, 4, 8, 100, ArchiveHeader::SIZE - 1] {
+            assert!(is_malformed(parse_header(&bytes[..length])), "{length}");
+        }
+    }
+
+    #[test]
+    fn oversized_header_fields_are_malformed() {
+        let set = |range: std::ops::Range<usize>, value: &[u8]| {
+            let mut bytes = header_bytes();
+            bytes[range].copy_from_slice(value);
+            bytes
+        };
+
+        // Data section inside the header, index before the data
+        assert!(is_malformed(parse_header(&set(8..16, &[0; 8]))));
+        assert!(is_malformed(parse_header(&set(
+            16..24,
+            &u64::MIN.to_be_bytes()
+        ))));
+        assert!(is_malformed(parse_header(&set(
+            8..16,
+            &u64::MAX.to_be_bytes()
+        ))));
+        assert!(is_malformed(parse_header(&set(
+            24..28,
+            &u32::MAX.to_be_bytes()
+        ))));
+        // Recovery section before the index
+        let recovery = ArchiveHeader::RECOVERY_OFFSET..ArchiveHeader::RECOVERY_OFFSET + 8;
+        assert!(is_malformed(parse_header(&set(
+            recovery,
+            &600u64.to_be_bytes()
+        ))));
+
+        // Argon2 memory cost, passes and lanes of a passphrase-encrypted archive
+        for (field, value) in [(16, MAX_KDF_MEMORY + 1), (20, 17), (24, u32::MAX)] {
+            let mut bytes = set(68..69, &[ArchiveHeader::FLAG_ENCRYPTED]);
+            let start = KdfParams::OFFSET + field;
+            bytes[start..start + 4].copy_from_slice(&value.to_be_bytes());
+            assert!(is_malformed(parse_header(&bytes)), "{field}");
+        }
+    }
+
+    #[test]
+    fn end_record_parses() {
+        let end_record = parse_end_record(&end_record_bytes(512, 100), 676).unwrap();
+        assert_eq!(end_record.index_offset, 512);
+        assert_eq!(end_record.index_length, 100);
+    }
+
+    #[test]
+    fn truncated_end_record_is_malformed() {
+        let bytes = end_record_bytes(512, 100);
+        for length in [0, 4, 12, 20, 52, 60] {
+            assert!(
+                is_malformed(parse_end_record(&bytes[..length], 676)),
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_end_record_fields_are_malformed() {
+        // Index past the end record, and offset plus length overflowing
+        let bytes = end_record_bytes(512, 101);
+        assert!(is_malformed(parse_end_record(&bytes, 676)));
+        let bytes = end_record_bytes(u64::MAX, 2);
+        assert!(is_malformed(parse_end_record(&bytes, 676)));
+        // Recovery section past the end record
+        let mut bytes = end_record_bytes(512, 100);
+        bytes[53..61].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(is_malformed(parse_end_record(&bytes, 676)));
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::models::archive::CompressionAlgorithm;
+use crate::utils::format_size;
+
+/// What happens while entries are added to an archive, or read from it
+pub enum Event<'a> {
+    /// Adding entry `name` of `size` bytes started, read from `source` if it is a file
+    FileStarted {
+        name: &'a str,
+        source: Option<&'a Path>,
+        size: u64,
+    },
+    /// `done` of `size` bytes of a large file read
+    BytesProcessed { name: &'a str, done: u64, size: u64 },
+    /// Entry `name` stored: `size` bytes compressed with `algorithm` to `stored_size`
+    FileFinished {
+        name: &'a str,
+        source: Option<&'a Path>,
+        size: u64,
+        stored_size: u64,
+        algorithm: CompressionAlgorithm,
+    },
+    /// File of `size` bytes left out, unchanged since the base archive
+    Skipped { name: &'a str, size: u64 },
+    /// Adding entry `name` failed, the error is returned to the caller as well
+    Error { name: &'a str, error: &'a Error },
+}
+
+/// Receives events of archive writing, to report progress or log added files
+pub trait ProgressSink {
+    fn event(&mut self, event: &Event);
+}
+
+/// Ignores all events
+pub struct Silent;
+
+impl ProgressSink for Silent {
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Passes events to each sink in turn
+impl ProgressSink for Vec<Box<dyn ProgressSink>> {
+    fn event(&mut self, event: &Event) {
+        for sink in self.iter_mut() {
+            sink.event(event);
+        }
+    }
+}
+
+/// Prints added and skipped files on stdout (`--verbose`)
+pub struct VerboseLog;
+
+impl ProgressSink for VerboseLog {
+    fn event(&mut self, event: &Event) {
+        match *event {
+            Event::FileFinished {
+                name,
+                source,
+                size,
+                stored_size,
+                algorithm,
+            } => {
+                let ratio = if stored_size > 0 {
+                    (stored_size as f64 / size as f64) * 100.0
+                } else {
+                    0.0
+                };
+                match source {
+                    Some(source) => println!(
+                        "  Added: {:?} -> {} ({}B -> {}B, {:.1}%, {:?})",
+                        source, name, size, stored_size, ratio, algorithm
+                    ),
+                    None => println!(
+                        "  Added: {} ({}B -> {}B, {:.1}%, {:?})",
+                        name, size, stored_size, ratio, algorithm
+                    ),
+                }
+            }
+            Event::Skipped { name, .. } => println!("  Unchanged: {}", name),
+            _ => {}
+        }
+    }
+}
+
+/// Overall progress on one stderr line (`--progress`): current file, bytes done
+/// of the pre-scanned total, throughput and ETA
+///
+/// When stderr is not a terminal a plain line is printed every few seconds instead.
+pub struct ProgressBar {
+    total: u64,
+    /// Bytes of finished and skipped files
+    done: u64,
+    /// Bytes of the current file read so far
+    current: u64,
+    name: String,
+    started: Instant,
+    last_draw: Option<Instant>,
+    terminal: bool,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 24;
+
+    /// Bar for files adding up to `total` bytes
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            done: 0,
+            current: 0,
+            name: String::new(),
+            started: Instant::now(),
+            last_draw: None,
+            terminal: io::stderr().is_terminal(),
+        }
+    }
+
+    fn draw(&mut self, force: bool) {
+        let interval = if self.terminal {
+            Duration::from_millis(100)
+        } else {
+            Duration::from_secs(5)
+        };
+        let now = Instant::now();
+        if !force && self.last_draw.is_some_and(|last| now - last < interval) {
+            return;
+        }
+        self.last_draw = Some(now);
+
+        let done = (self.done + self.current).min(self.total);
+        let fraction = if self.total > 0 {
+            done as f64 / self.total as f64
+        } else {
+            1.0
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = if rate > 0.0 {
+            format_duration(((self.total - done) as f64 / rate) as u64)
+        } else {
+            "--:--".to_string()
+        };
+        let status = format!(
+            "{:>3.0}% {}/{} {:.1} MB/s ETA {}",
+            fraction * 100.0,
+            format_size(done),
+            format_size(self.total),
+            rate / (1024.0 * 1024.0),
+            eta
+        );
+
+        if self.terminal {
+            let filled = (fraction * Self::WIDTH as f64) as usize;
+            eprint!(
+                "\r[{}{}] {} {}\x1b[K",
+                "#".repeat(filled),
+                " ".repeat(Self::WIDTH - filled),
+                status,
+                self.name
+            );
+        } else {
+            eprintln!("{} {}", status, self.name);
+        }
+    }
+}
+
+impl ProgressSink for ProgressBar {
+    fn event(&mut self, event: &Event) {
+        match *event {
+            Event::FileStarted { name, .. } => {
+                self.name = name.to_string();
+                self.current = 0;
+                self.draw(false);
+            }
+            Event::BytesProcessed { done, .. } => {
+                self.current = done;
+                self.draw(false);
+            }
+            Event::FileFinished { size, .. } => {
+                self.done += size;
+                self.current = 0;
+                self.draw(false);
+            }
+            Event::Skipped { size, .. } => {
+                self.done += size;
+                self.draw(false);
+            }
+            Event::Error { .. } => {}
+        }
+    }
+}
+
+impl Drop for ProgressBar {
+    /// Final state of the bar, then leave its line
+    fn drop(&mut self) {
+        if self.last_draw.is_some() {
+            self.name.clear();
+            self.draw(true);
+            if self.terminal {
+                eprintln!();
+            }
+        }
+    }
+}
+
+/// Seconds as m:ss, or h:mm:ss from an hour on
+fn format_duration(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            (seconds % 3600) / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Sinks of the command line's `--verbose` flag and of `--progress`, given the
+/// total bytes to process
+pub fn cli_sink(verbose: bool, progress: Option<u64>) -> Vec<Box<dyn ProgressSink>> {
+    let mut sinks: Vec<Box<dyn ProgressSink>> = Vec::new();
+    if verbose {
+        sinks.push(Box::new(VerboseLog));
+    }
+    if let Some(total) = progress {
+        sinks.push(Box::new(ProgressBar::new(total)));
+    }
+    sinks
+}
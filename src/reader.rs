@@ -0,0 +1,200 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::archive::{
+    Generation, read_entry_data, read_generations, read_header, read_index_entries_at, read_tail,
+};
+use crate::crypto::{ArchiveKey, KeySource, unlock};
+use crate::error::{Error, Result};
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::{ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry};
+use crate::volume::ArchiveSource;
+
+/// How archives are opened: where their keys come from and whether to wait
+/// for a lock another process holds
+#[derive(Clone, Default)]
+pub struct AccessOptions {
+    pub keys: KeySource,
+    pub wait: bool,
+}
+
+/// Archive opened for reading: header and end record parsed and checked
+/// against each other, index of the selected generation read
+///
+/// Every command reading archives goes through it, so that they agree on what
+/// a valid archive is and on which index is current.
+pub struct ArchiveReader {
+    _lock: Option<ArchiveLock>,
+    path: String,
+    source: RefCell<ArchiveSource>,
+    header: ArchiveHeader,
+    end_record: ArchiveEndRecord,
+    interrupted: bool,
+    key: Option<ArchiveKey>,
+    entries: Vec<ArchiveIndexEntry>,
+    positions: HashMap<String, usize>,
+}
+
+impl ArchiveReader {
+    /// Open archive at `path` for `operation` under a shared lock, reading its current index
+    pub fn open(path: &str, operation: &str, options: &AccessOptions) -> Result<Self> {
+        Self::open_generation(path, operation, options, None)
+    }
+
+    /// Open archive as it was at generation `number` (1 = oldest), the current one if None
+    pub fn open_generation(
+        path: &str,
+        operation: &str,
+        options: &AccessOptions,
+        generation: Option<usize>,
+    ) -> Result<Self> {
+        let lock = ArchiveLock::acquire(path, LockMode::Shared, operation, options.wait)?;
+        let mut reader = Self::from_source(path, ArchiveSource::open(path)?, options, generation)?;
+        reader._lock = Some(lock);
+        Ok(reader)
+    }
+
+    /// Read archive from an already opened source, for callers holding the lock themselves
+    pub fn from_source(
+        path: &str,
+        mut source: ArchiveSource,
+        options: &AccessOptions,
+        generation: Option<usize>,
+    ) -> Result<Self> {
+        let (header, header_result) = read_header(&mut source);
+        header_result?;
+        let header =
+            header.ok_or_else(|| Error::Inconsistent(format!("Cannot read archive {}", path)))?;
+
+        let file_size = source.size()?;
+        let tail = read_tail(&mut source, &header, file_size)?;
+        let interrupted = tail.is_interrupted(&header);
+        let end_record = tail.end_record;
+
+        // Appends move the header to the new index before storing the checksum,
+        // so a complete end record and a header elsewhere means damage
+        if !tail.torn
+            && header.index_section_start != end_record.index_offset
+            && end_record.archive_checksum != [0u8; 32]
+        {
+            return Err(Error::Inconsistent(format!(
+                "Header and end record of {} point to different indexes ({} and {})",
+                path, header.index_section_start, end_record.index_offset
+            )));
+        }
+
+        let key = unlock(
+            &options.keys,
+            &mut source,
+            header.flags,
+            header.kdf.as_ref(),
+        )?;
+
+        let index_offset = match generation {
+            Some(number) => {
+                let generations = read_generations(&mut source, &header, &end_record)?;
+                generations
+                    .get(number.wrapping_sub(1))
+                    .map(|generation| generation.index_offset)
+                    .ok_or(Error::NoSuchGeneration {
+                        requested: number,
+                        available: generations.len(),
+                    })?
+            }
+            None => end_record.index_offset,
+        };
+        let entries = read_index_entries_at(&mut source, index_offset, key.as_ref())?;
+
+        if !interrupted && generation.is_none() && entries.len() != header.total_files as usize {
+            return Err(Error::Inconsistent(format!(
+                "Index of {} has {} entries but its header records {}",
+                path,
+                entries.len(),
+                header.total_files
+            )));
+        }
+
+        let positions = entries
+            .iter()
+            .enumerate()
+            .map(|(position, entry)| (entry.path.clone(), position))
+            .collect();
+
+        Ok(Self {
+            _lock: None,
+            path: path.to_string(),
+            source: RefCell::new(source),
+            header,
+            end_record,
+            interrupted,
+            key,
+            entries,
+            positions,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
+
+    /// End record of the archive (describing the last complete index if the tail is torn)
+    pub fn end_record(&self) -> &ArchiveEndRecord {
+        &self.end_record
+    }
+
+    /// An append was interrupted and the last complete index was read instead,
+    /// `dar recover` completes or removes it
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// Entries of the selected index in stored order, tombstones included
+    pub fn entries(&self) -> &[ArchiveIndexEntry] {
+        &self.entries
+    }
+
+    /// Entries of files in the archive, without tombstones
+    pub fn live_entries(&self) -> impl Iterator<Item = &ArchiveIndexEntry> {
+        self.entries.iter().filter(|entry| !entry.is_deleted())
+    }
+
+    /// Entry of the file at `path`, None when missing or deleted
+    pub fn find(&self, path: &str) -> Option<&ArchiveIndexEntry> {
+        self.positions
+            .get(path)
+            .map(|&position| &self.entries[position])
+            .filter(|entry| !entry.is_deleted())
+    }
+
+    /// Read, decrypt and decompress data of an entry
+    pub fn open_entry(&self, entry: &ArchiveIndexEntry) -> Result<Vec<u8>> {
+        read_entry_data(
+            &mut *self.source.borrow_mut(),
+            self.header.data_section_start,
+            entry,
+            self.key.as_ref(),
+        )
+    }
+
+    /// All index generations, oldest first
+    pub fn generations(&self) -> Result<Vec<Generation>> {
+        read_generations(
+            &mut *self.source.borrow_mut(),
+            &self.header,
+            &self.end_record,
+        )
+    }
+
+    /// Entries of an earlier generation's index
+    pub fn generation_entries(&self, generation: &Generation) -> Result<Vec<ArchiveIndexEntry>> {
+        read_index_entries_at(
+            &mut *self.source.borrow_mut(),
+            generation.index_offset,
+            self.key.as_ref(),
+        )
+    }
+}
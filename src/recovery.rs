@@ -0,0 +1,513 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::error::{Error, Result, read_failed};
+use crate::parser::ParseError;
+
+/// Recovery section: Reed-Solomon parity over fixed-size blocks of everything
+/// before it (header, data and index sections)
+///
+/// Layout:
+/// [magic "DREC"][block_size: u32][protected_length: u64][data_shards: u32]
+/// [parity_shards: u32][stripe_count: u32][block hashes: 32 bytes each]
+/// [parity hashes: 32 bytes each][table checksum: 32 bytes][parity blocks]
+///
+/// Blocks are grouped into stripes of `data_shards` blocks, each stripe having
+/// `parity_shards` parity blocks. The last block and stripe are zero-padded.
+pub struct RecoveryRecord {
+    pub block_size: u32,
+    pub protected_length: u64,
+    pub data_shards: u32,
+    pub parity_shards: u32,
+    pub stripe_count: u32,
+    pub block_hashes: Vec<[u8; 32]>,
+    pub parity_hashes: Vec<[u8; 32]>,
+}
+
+impl RecoveryRecord {
+    pub const MAGIC: &'static [u8] = b"DREC";
+    const FIXED_SIZE: usize = 28;
+    const MIN_BLOCK_SIZE: u64 = 512;
+    const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+    const MAX_STRIPE_BLOCKS: u64 = 128;
+    /// Bytes read at a time when scanning for a section whose pointers are lost
+    const SCAN_CHUNK: usize = 1024 * 1024;
+
+    /// Plan record for given protected length and redundancy percentage
+    pub fn plan(protected_length: u64, percent: u32) -> Self {
+        // Aim for at least a full stripe of blocks on small archives
+        let block_size = protected_length
+            .div_ceil(Self::MAX_STRIPE_BLOCKS)
+            .next_power_of_two()
+            .clamp(Self::MIN_BLOCK_SIZE, Self::MAX_BLOCK_SIZE);
+        let blocks = protected_length.div_ceil(block_size).max(1);
+        let data_shards = blocks.min(Self::MAX_STRIPE_BLOCKS);
+        let parity_shards = (data_shards * percent as u64).div_ceil(100).max(1);
+        let stripe_count = blocks.div_ceil(data_shards);
+
+        Self {
+            block_size: block_size as u32,
+            protected_length,
+            data_shards: data_shards as u32,
+            parity_shards: parity_shards as u32,
+            stripe_count: stripe_count as u32,
+            block_hashes: Vec::new(),
+            parity_hashes: Vec::new(),
+        }
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.protected_length
+            .div_ceil(self.block_size as u64)
+            .max(1)
+    }
+
+    /// Redundancy the record was planned with
+    pub fn percent(&self) -> u32 {
+        (self.parity_shards * 100 / self.data_shards).max(1)
+    }
+
+    fn parity_count(&self) -> u64 {
+        self.stripe_count as u64 * self.parity_shards as u64
+    }
+
+    /// Size of the table part (everything before the parity blocks)
+    pub fn table_size(&self) -> u64 {
+        Self::FIXED_SIZE as u64 + (self.block_count() + self.parity_count()) * 32 + 32
+    }
+
+    /// Total size of the section
+    pub fn section_size(&self) -> u64 {
+        self.table_size() + self.parity_count() * self.block_size as u64
+    }
+
+    fn write_table(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start = buf.len();
+        buf.write_all(Self::MAGIC)?;
+        buf.write_all(&self.block_size.to_be_bytes())?;
+        buf.write_all(&self.protected_length.to_be_bytes())?;
+        buf.write_all(&self.data_shards.to_be_bytes())?;
+        buf.write_all(&self.parity_shards.to_be_bytes())?;
+        buf.write_all(&self.stripe_count.to_be_bytes())?;
+        for hash in self.block_hashes.iter().chain(self.parity_hashes.iter()) {
+            buf.write_all(hash)?;
+        }
+        let table_checksum = blake3::hash(&buf[start..]);
+        buf.write_all(table_checksum.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read and verify table of the section starting at offset
+    pub fn read_from<R: Read + Seek>(file: &mut R, offset: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut fixed = [0u8; Self::FIXED_SIZE];
+        file.read_exact(&mut fixed)
+            .map_err(|e| read_failed(e, "recovery section"))?;
+        if &fixed[0..4] != Self::MAGIC {
+            return Err(ParseError::BadMagic {
+                structure: "recovery section",
+            }
+            .into());
+        }
+
+        let mut record = Self {
+            block_size: u32::from_be_bytes(fixed[4..8].try_into().unwrap()),
+            protected_length: u64::from_be_bytes(fixed[8..16].try_into().unwrap()),
+            data_shards: u32::from_be_bytes(fixed[16..20].try_into().unwrap()),
+            parity_shards: u32::from_be_bytes(fixed[20..24].try_into().unwrap()),
+            stripe_count: u32::from_be_bytes(fixed[24..28].try_into().unwrap()),
+            block_hashes: Vec::new(),
+            parity_hashes: Vec::new(),
+        };
+
+        // Fields come from the file: sums must not overflow, and the table must
+        // fit in it before anything is allocated for its hashes
+        let out_of_bounds = |field, value| Err(ParseError::OutOfBounds { field, value }.into());
+        if record.block_size == 0 {
+            return out_of_bounds("Recovery block size", 0);
+        }
+        let shards = record.data_shards as u64 + record.parity_shards as u64;
+        if record.data_shards == 0 || record.parity_shards == 0 || shards > 256 {
+            return out_of_bounds("Recovery shard count", shards);
+        }
+        if record.protected_length != offset {
+            return out_of_bounds("Recovery protected length", record.protected_length);
+        }
+        if record.stripe_count as u64 != record.block_count().div_ceil(record.data_shards as u64) {
+            return out_of_bounds("Recovery stripe count", record.stripe_count as u64);
+        }
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if record.table_size() > file_size.saturating_sub(offset) {
+            return Err(ParseError::Truncated {
+                field: "recovery table",
+            }
+            .into());
+        }
+        file.seek(SeekFrom::Start(offset + Self::FIXED_SIZE as u64))?;
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&fixed);
+        record.block_hashes = read_hashes(file, record.block_count(), &mut hasher)?;
+        record.parity_hashes = read_hashes(file, record.parity_count(), &mut hasher)?;
+
+        let mut table_checksum = [0u8; 32];
+        file.read_exact(&mut table_checksum)
+            .map_err(|e| read_failed(e, "recovery table"))?;
+        if hasher.finalize().as_bytes() != &table_checksum {
+            return Err(Error::Recovery(
+                "Recovery section table checksum mismatch".to_string(),
+            ));
+        }
+
+        Ok(record)
+    }
+
+    /// Offset of parity block `index` (stripe-major) in the file
+    fn parity_offset(&self, index: u64) -> u64 {
+        self.protected_length + self.table_size() + index * self.block_size as u64
+    }
+}
+
+/// Outcome of a repair run
+pub struct RepairReport {
+    pub damaged_blocks: u64,
+    pub repaired_blocks: u64,
+    pub damaged_parity: u64,
+    pub unrecoverable_stripes: Vec<u32>,
+}
+
+/// Build recovery section protecting `protected` (header, data and index)
+pub fn build_section(protected: &[u8], percent: u32) -> Result<Vec<u8>> {
+    let mut record = RecoveryRecord::plan(protected.len() as u64, percent);
+    let codec = codec(&record)?;
+    let mut parity_blocks: Vec<Vec<u8>> = Vec::new();
+
+    for stripe in 0..record.stripe_count as u64 {
+        let mut shards = stripe_from_slice(&record, protected, stripe);
+        // Padding blocks of the last stripe are virtual and have no hash
+        let first_block = stripe * record.data_shards as u64;
+        let real_blocks = (record.block_count() - first_block).min(record.data_shards as u64);
+        for shard in shards.iter().take(real_blocks as usize) {
+            record.block_hashes.push(*blake3::hash(shard).as_bytes());
+        }
+        codec
+            .encode(&mut shards)
+            .map_err(|e| Error::Recovery(format!("Reed-Solomon encoding failed: {:?}", e)))?;
+        for parity in shards.drain(record.data_shards as usize..) {
+            record.parity_hashes.push(*blake3::hash(&parity).as_bytes());
+            parity_blocks.push(parity);
+        }
+    }
+
+    let mut section = Vec::with_capacity(record.section_size() as usize);
+    record.write_table(&mut section)?;
+    for parity in parity_blocks {
+        section.extend_from_slice(&parity);
+    }
+
+    Ok(section)
+}
+
+/// Redundancy of a section planned to fill `section_size` bytes after
+/// `protected_length`, for rebuilding a section whose writing was interrupted
+pub fn planned_percent(protected_length: u64, section_size: u64) -> Option<u32> {
+    (1..=100).find(|&percent| {
+        RecoveryRecord::plan(protected_length, percent).section_size() == section_size
+    })
+}
+
+/// Recompute recovery section in place after the protected bytes changed
+/// (e.g. after embedding a signature into the header)
+pub fn rebuild_section(file: &mut File, offset: u64) -> Result<()> {
+    let old = RecoveryRecord::read_from(file, offset)?;
+    let percent = old.percent();
+
+    let mut protected = vec![0u8; offset as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut protected)?;
+
+    let section = build_section(&protected, percent)?;
+    if section.len() as u64 != old.section_size() {
+        return Err(Error::Recovery(
+            "Rebuilt recovery section does not fit in place".to_string(),
+        ));
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&section)?;
+    Ok(())
+}
+
+/// Locate recovery section: end record pointer, header pointer, then a scan for its magic
+pub fn locate_section(file: &mut File, file_size: u64) -> Result<(u64, RecoveryRecord)> {
+    let mut candidates = Vec::new();
+
+    if file_size >= 64 {
+        let mut end = [0u8; 64];
+        file.seek(SeekFrom::Start(file_size - 64))?;
+        file.read_exact(&mut end)?;
+        if &end[0..4] == b"DEND" {
+            candidates.push(u64::from_be_bytes(end[53..61].try_into().unwrap()));
+        }
+    }
+
+    if file_size >= 512 {
+        let mut header = [0u8; 512];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        candidates.push(u64::from_be_bytes(header[97..105].try_into().unwrap()));
+    }
+
+    for offset in candidates {
+        if offset > 0
+            && offset < file_size
+            && let Ok(record) = RecoveryRecord::read_from(file, offset)
+        {
+            return Ok((offset, record));
+        }
+    }
+
+    // Both pointers are damaged: look for a section with a valid table, a chunk
+    // at a time; chunks overlap so that a magic across their boundary is found
+    let overlap = RecoveryRecord::MAGIC.len() - 1;
+    let mut chunk = vec![0u8; RecoveryRecord::SCAN_CHUNK];
+    let mut position = 0u64;
+    while position + (overlap as u64) < file_size {
+        let length = (file_size - position).min(chunk.len() as u64) as usize;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut chunk[..length])?;
+
+        let mut start = 0;
+        while let Some(found) = find_magic(&chunk[start..length]) {
+            let offset = position + (start + found) as u64;
+            if let Ok(record) = RecoveryRecord::read_from(file, offset) {
+                return Ok((offset, record));
+            }
+            start += found + 1;
+        }
+        position += (length - overlap) as u64;
+    }
+
+    Err(Error::Recovery(
+        "Archive has no usable recovery record".to_string(),
+    ))
+}
+
+/// Verify every block against the recovery record and rewrite damaged ones
+pub fn repair(file: &mut File, offset: u64, record: &RecoveryRecord) -> Result<RepairReport> {
+    let codec = codec(record)?;
+    let data_shards = record.data_shards as usize;
+    let block_size = record.block_size as usize;
+
+    let mut report = RepairReport {
+        damaged_blocks: 0,
+        repaired_blocks: 0,
+        damaged_parity: 0,
+        unrecoverable_stripes: Vec::new(),
+    };
+
+    for stripe in 0..record.stripe_count as u64 {
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::new();
+        let mut damaged = Vec::new();
+
+        for i in 0..data_shards as u64 {
+            let block = stripe * data_shards as u64 + i;
+            if block >= record.block_count() {
+                // Virtual zero padding block of the last stripe
+                shards.push(Some(vec![0u8; block_size]));
+                continue;
+            }
+
+            let content = read_block(file, block * block_size as u64, block_size, offset)?;
+            if blake3::hash(&content).as_bytes() == &record.block_hashes[block as usize] {
+                shards.push(Some(content));
+            } else {
+                shards.push(None);
+                damaged.push(i as usize);
+                report.damaged_blocks += 1;
+            }
+        }
+
+        for i in 0..record.parity_shards as u64 {
+            let index = stripe * record.parity_shards as u64 + i;
+            let content = read_block(file, record.parity_offset(index), block_size, u64::MAX)?;
+            if blake3::hash(&content).as_bytes() == &record.parity_hashes[index as usize] {
+                shards.push(Some(content));
+            } else {
+                shards.push(None);
+                damaged.push(data_shards + i as usize);
+                report.damaged_parity += 1;
+            }
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+
+        if codec.reconstruct(&mut shards).is_err() {
+            report.unrecoverable_stripes.push(stripe as u32);
+            continue;
+        }
+
+        for i in damaged {
+            let shard = shards[i].as_ref().unwrap();
+            if i < data_shards {
+                let block_offset = (stripe * data_shards as u64 + i as u64) * block_size as u64;
+                let length = (record.protected_length - block_offset).min(block_size as u64);
+                file.seek(SeekFrom::Start(block_offset))?;
+                file.write_all(&shard[..length as usize])?;
+                report.repaired_blocks += 1;
+            } else {
+                let index = stripe * record.parity_shards as u64 + (i - data_shards) as u64;
+                file.seek(SeekFrom::Start(record.parity_offset(index)))?;
+                file.write_all(shard)?;
+            }
+        }
+    }
+
+    // Table may have been lost together with a truncated tail
+    file.seek(SeekFrom::Start(offset))?;
+    let mut table = Vec::new();
+    record.write_table(&mut table)?;
+    file.write_all(&table)?;
+
+    Ok(report)
+}
+
+fn codec(record: &RecoveryRecord) -> Result<ReedSolomon> {
+    ReedSolomon::new(record.data_shards as usize, record.parity_shards as usize)
+        .map_err(|e| Error::Recovery(format!("Invalid Reed-Solomon parameters: {:?}", e)))
+}
+
+/// Data shards of a stripe plus empty parity shards, zero-padded
+fn stripe_from_slice(record: &RecoveryRecord, protected: &[u8], stripe: u64) -> Vec<Vec<u8>> {
+    let block_size = record.block_size as usize;
+    let total = (record.data_shards + record.parity_shards) as usize;
+    let mut shards = vec![vec![0u8; block_size]; total];
+
+    for (i, shard) in shards
+        .iter_mut()
+        .take(record.data_shards as usize)
+        .enumerate()
+    {
+        let start = (stripe as usize * record.data_shards as usize + i) * block_size;
+        if start < protected.len() {
+            let end = (start + block_size).min(protected.len());
+            shard[..end - start].copy_from_slice(&protected[start..end]);
+        }
+    }
+
+    shards
+}
+
+/// Read block zero-padded to block size, never past `limit` or end of file
+fn read_block(file: &mut File, offset: u64, block_size: usize, limit: u64) -> Result<Vec<u8>> {
+    let mut block = vec![0u8; block_size];
+    let wanted = (limit.saturating_sub(offset)).min(block_size as u64) as usize;
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < wanted {
+        let n = file.read(&mut block[filled..wanted])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+
+    Ok(block)
+}
+
+fn read_hashes<R: Read>(
+    file: &mut R,
+    count: u64,
+    hasher: &mut blake3::Hasher,
+) -> Result<Vec<[u8; 32]>> {
+    let mut hashes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut hash = [0u8; 32];
+        file.read_exact(&mut hash)
+            .map_err(|e| read_failed(e, "recovery table"))?;
+        hasher.update(&hash);
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+fn find_magic(data: &[u8]) -> Option<usize> {
+    data.windows(RecoveryRecord::MAGIC.len())
+        .position(|window| window == RecoveryRecord::MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PROTECTED: usize = 20_000;
+
+    fn archive_with_section() -> Vec<u8> {
+        let mut bytes: Vec<u8> = (0..PROTECTED).map(|i| (i % 251) as u8).collect();
+        let section = build_section(&bytes, 10).unwrap();
+        bytes.extend_from_slice(&section);
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Result<RecoveryRecord> {
+        RecoveryRecord::read_from(&mut Cursor::new(bytes), PROTECTED as u64)
+    }
+
+    #[test]
+    fn section_reads_back() {
+        let record = read(&archive_with_section()).unwrap();
+        assert_eq!(record.protected_length, PROTECTED as u64);
+    }
+
+    #[test]
+    fn truncated_section_is_malformed() {
+        let bytes = archive_with_section();
+        for length in [PROTECTED, PROTECTED + 10, PROTECTED + 100] {
+            assert!(
+                matches!(read(&bytes[..length]), Err(Error::Malformed(_))),
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_section_fields_are_malformed() {
+        let fields: [(std::ops::Range<usize>, &[u8]); 6] = [
+            (4..8, &0u32.to_be_bytes()),
+            (8..16, &u64::MAX.to_be_bytes()),
+            (16..20, &0u32.to_be_bytes()),
+            (16..20, &u32::MAX.to_be_bytes()),
+            (20..24, &u32::MAX.to_be_bytes()),
+            (24..28, &u32::MAX.to_be_bytes()),
+        ];
+        for (range, value) in fields {
+            let mut bytes = archive_with_section();
+            bytes[PROTECTED + range.start..PROTECTED + range.end].copy_from_slice(value);
+            assert!(
+                matches!(read(&bytes), Err(Error::Malformed(_))),
+                "{range:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn table_larger_than_file_is_malformed() {
+        // One-byte blocks with a matching stripe count: a hash per protected byte
+        let mut bytes = archive_with_section();
+        let data_shards =
+            u32::from_be_bytes(bytes[PROTECTED + 16..PROTECTED + 20].try_into().unwrap());
+        let stripes = (PROTECTED as u32).div_ceil(data_shards);
+        bytes[PROTECTED + 4..PROTECTED + 8].copy_from_slice(&1u32.to_be_bytes());
+        bytes[PROTECTED + 24..PROTECTED + 28].copy_from_slice(&stripes.to_be_bytes());
+        assert!(matches!(
+            read(&bytes),
+            Err(Error::Malformed(ParseError::Truncated { .. }))
+        ));
+    }
+}
@@ -0,0 +1,541 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::archive::{
+    EntryKeys, calculate_archive_checksum, read_end_record, read_header, read_index_entries_at,
+    read_tail, write_archive_checksum, write_entry, write_index, write_local_header,
+};
+use crate::crypto::{ArchiveKey, require_mac_key, unlock};
+use crate::error::{Error, Result, io_context};
+use crate::incremental::find_dependents;
+use crate::lock::{ArchiveLock, LockMode};
+use crate::models::archive::{
+    ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry, IndexTrailer, LocalEntryHeader,
+    SignatureBlock,
+};
+use crate::progress::ProgressSink;
+use crate::reader::AccessOptions;
+use crate::recovery::{RecoveryRecord, build_section};
+use crate::signature::sidecar_path;
+use crate::utils::calculate_archive_path;
+use crate::volume::ArchiveSource;
+
+/// Single-file archive opened for modification in place
+///
+/// Changes are appended: new data and a superseding index go after the current
+/// end record, followed by a new end record. Data of dropped entries and the
+/// previous tail stay in the file.
+pub struct ArchiveAppender {
+    _lock: ArchiveLock,
+    path: String,
+    file: File,
+    pub header: ArchiveHeader,
+    pub key: Option<ArchiveKey>,
+    pub mac_key: Option<[u8; 32]>,
+    pub entries: Vec<ArchiveIndexEntry>,
+    index_offset: u64, // current index, superseded on commit
+    index_end: u64,
+    archive_end: u64, // end of the current end record, where the new tail goes
+    recovery: Option<u32>,
+    pending: Vec<u8>, // data entries to write after the current index
+}
+
+impl ArchiveAppender {
+    /// Open archive for `operation`, holding an exclusive lock until dropped
+    pub fn open(path: &str, operation: &str, options: &AccessOptions) -> Result<Self> {
+        if let ArchiveSource::Volumes(_) = ArchiveSource::open(path)? {
+            return Err(Error::Volume(format!(
+                "Cannot modify {}, it is split into volumes",
+                path
+            )));
+        }
+        let lock = ArchiveLock::acquire(path, LockMode::Exclusive, operation, options.wait)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| io_context(e, format!("Failed to open archive {}", path)))?;
+        let file_size = file.metadata()?.len();
+
+        let (header, header_result) = read_header(&mut file);
+        header_result?;
+        let header =
+            header.ok_or_else(|| Error::Inconsistent(format!("Cannot read archive {}", path)))?;
+
+        let tail = read_tail(&mut file, &header, file_size).map_err(|e| {
+            Error::Inconsistent(format!("Cannot modify {} ({}), repair it first", path, e))
+        })?;
+        if tail.torn || header.index_section_start != tail.end_record.index_offset {
+            return Err(Error::Inconsistent(format!(
+                "Cannot modify {}, an earlier append was interrupted, run dar recover first",
+                path
+            )));
+        }
+        let end_record = tail.end_record;
+
+        // Incrementals record the archive checksum, which any change replaces
+        if let Some(dependent) = find_dependents(path, &header)?.first() {
+            return Err(Error::Chain(format!(
+                "Cannot modify {}, incremental archive {} is based on it",
+                path, dependent
+            )));
+        }
+
+        let key = unlock(&options.keys, &mut file, header.flags, header.kdf.as_ref())?;
+        let mac_key = require_mac_key(&options.keys, header.flags)?;
+        let entries = read_index_entries_at(&mut file, end_record.index_offset, key.as_ref())?;
+
+        // Keep the redundancy of an existing recovery record
+        let recovery = match header.recovery_offset {
+            0 => None,
+            offset => Some(
+                RecoveryRecord::read_from(&mut file, offset)
+                    .map_err(|e| {
+                        Error::Recovery(format!(
+                            "Cannot read recovery record of {} ({}), run dar recover first",
+                            path, e
+                        ))
+                    })?
+                    .percent(),
+            ),
+        };
+
+        Ok(Self {
+            _lock: lock,
+            path: path.to_string(),
+            file,
+            index_offset: end_record.index_offset,
+            index_end: end_record.index_offset + end_record.index_length,
+            archive_end: file_size,
+            header,
+            key,
+            mac_key,
+            entries,
+            recovery,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Redundancy of the archive's recovery record, if it has one
+    pub fn recovery(&self) -> Option<u32> {
+        self.recovery
+    }
+
+    pub fn keys(&self) -> EntryKeys<'_> {
+        EntryKeys {
+            cipher: self.key.as_ref(),
+            mac: self.mac_key.as_ref(),
+        }
+    }
+
+    /// Add file as a new data entry, returning its index entry for the new index
+    pub fn add_file(
+        &mut self,
+        path: &Path,
+        root: &Path,
+        sink: &mut dyn ProgressSink,
+    ) -> Result<ArchiveIndexEntry> {
+        let current_offset =
+            self.archive_end + self.pending.len() as u64 - self.header.data_section_start;
+        let keys = EntryKeys {
+            cipher: self.key.as_ref(),
+            mac: self.mac_key.as_ref(),
+        };
+        write_entry(
+            path,
+            &calculate_archive_path(path, root),
+            &mut self.pending,
+            current_offset,
+            keys,
+            None,
+            sink,
+        )
+    }
+
+    /// Append added data and a new index (and end record) listing `entries`,
+    /// superseding the current index
+    pub fn commit(mut self, entries: &[ArchiveIndexEntry]) -> Result<()> {
+        let index_section_start = self.archive_end + self.pending.len() as u64;
+
+        let mut tail: Vec<u8> = std::mem::take(&mut self.pending);
+        let mut index_bytes: Vec<u8> = Vec::new();
+        index_bytes.write_all(&(entries.len() as u32).to_be_bytes())?;
+        for entry in entries {
+            entry.write_to(&mut index_bytes)?;
+        }
+        write_index(&index_bytes, &mut tail, self.key.as_ref())?;
+        IndexTrailer::new(self.index_offset, self.index_end - self.index_offset)
+            .write_to(&mut tail)?;
+        let index_length = tail.len() as u64 - (index_section_start - self.archive_end);
+
+        // Recovery section is rebuilt in the space reserved between index and end record
+        let index_end = index_section_start + index_length;
+        let (recovery_offset, end_record_offset) = match self.recovery {
+            Some(percent) => (
+                index_end,
+                index_end + RecoveryRecord::plan(index_end, percent).section_size(),
+            ),
+            None => (0, index_end),
+        };
+
+        let mut end_record = ArchiveEndRecord::new(index_section_start, index_length);
+        end_record.recovery_offset = recovery_offset;
+
+        // The previous index, recovery section and end record stay intact, the new
+        // end record becomes valid only once everything before it is on disk and
+        // the header moves to the new index last, so an interrupted append leaves
+        // readers the previous index (see read_tail) and dar recover can cut the
+        // new tail off again
+        self.file.seek(SeekFrom::Start(self.archive_end))?;
+        self.file.write_all(&tail)?;
+        self.file.set_len(end_record_offset)?;
+        self.file.sync_data()?;
+        write_end_record(&mut self.file, end_record_offset, &end_record)?;
+
+        self.header.index_section_start = index_section_start;
+        self.header.total_files = entries.len() as u32;
+        self.header.recovery_offset = recovery_offset;
+        let signed = finish_archive(
+            &mut self.file,
+            &self.header,
+            end_record_offset,
+            self.mac_key.as_ref(),
+            self.recovery,
+        )?;
+
+        note_stale_signatures(&self.path, signed);
+
+        Ok(())
+    }
+}
+
+/// End of the archive as `header` describes it: its end record follows the
+/// index and recovery section, appends write their tail after it
+///
+/// None when no matching end record is there, as with appends of older versions
+/// that overwrote it.
+pub fn committed_end<R: Read + Seek>(
+    file: &mut R,
+    header: &ArchiveHeader,
+    index_end: u64,
+) -> Option<u64> {
+    let end_record_offset = match header.recovery_offset {
+        0 => index_end,
+        offset => {
+            offset.checked_add(RecoveryRecord::read_from(file, offset).ok()?.section_size())?
+        }
+    };
+    let archive_end = end_record_offset.checked_add(ArchiveEndRecord::SIZE as u64)?;
+    match read_end_record(file, archive_end) {
+        (Some(end_record), Ok(())) if end_record.index_offset == header.index_section_start => {
+            Some(archive_end)
+        }
+        _ => None,
+    }
+}
+
+/// Write end record at `offset` and make it durable
+pub fn write_end_record(file: &mut File, offset: u64, end_record: &ArchiveEndRecord) -> Result<()> {
+    let mut end_record_bytes = Vec::new();
+    end_record.write_to(&mut end_record_bytes)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&end_record_bytes)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Point header at the index, file count and recovery section of `header`,
+/// then store the archive checksum and rebuild the recovery section
+///
+/// Returns whether the header had an embedded signature, which no longer
+/// matches and is removed.
+pub fn finish_archive(
+    file: &mut File,
+    header: &ArchiveHeader,
+    end_record_offset: u64,
+    mac_key: Option<&[u8; 32]>,
+    recovery: Option<u32>,
+) -> Result<bool> {
+    let mut header_bytes = [0u8; ArchiveHeader::SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header_bytes)?;
+    let signed = SignatureBlock::from_bytes(&header_bytes[SignatureBlock::OFFSET..]).is_some();
+
+    header_bytes[16..24].copy_from_slice(&header.index_section_start.to_be_bytes());
+    header_bytes[24..28].copy_from_slice(&header.total_files.to_be_bytes());
+    header_bytes[ArchiveHeader::RECOVERY_OFFSET..ArchiveHeader::RECOVERY_OFFSET + 8]
+        .copy_from_slice(&header.recovery_offset.to_be_bytes());
+    header_bytes[SignatureBlock::OFFSET..SignatureBlock::OFFSET + SignatureBlock::SIZE].fill(0);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header_bytes)?;
+
+    let file_size = end_record_offset + ArchiveEndRecord::SIZE as u64;
+    let checksum = calculate_archive_checksum(file, header, file_size, mac_key)?;
+    file.seek(SeekFrom::Start(36))?;
+    file.write_all(&checksum)?;
+    file.seek(SeekFrom::Start(end_record_offset + 20))?;
+    file.write_all(&checksum)?;
+
+    if let Some(percent) = recovery {
+        let mut protected = vec![0u8; header.recovery_offset as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut protected)?;
+        file.seek(SeekFrom::Start(header.recovery_offset))?;
+        file.write_all(&build_section(&protected, percent)?)?;
+    }
+    file.sync_all()?;
+
+    Ok(signed)
+}
+
+/// Tell user that signatures of a modified archive have to be made again
+pub fn note_stale_signatures(path: &str, signed: bool) {
+    if signed {
+        println!(
+            "  Embedded signature removed, sign {} again (dar sign)",
+            path
+        );
+    }
+    if Path::new(&sidecar_path(path)).exists() {
+        println!("  Detached signature {} is now stale", sidecar_path(path));
+    }
+}
+
+/// Builds a fresh archive from entries of another one, copying their stored data
+pub struct ArchiveRewriter {
+    archive_bytes: Vec<u8>,
+    data_section_start: u64,
+    entries: Vec<ArchiveIndexEntry>,
+}
+
+impl ArchiveRewriter {
+    /// Start archive with the settings of `header` (if known), taking the
+    /// recipient section from `bytes` of the original archive
+    pub fn new(
+        bytes: &[u8],
+        header: Option<&ArchiveHeader>,
+        data_start: usize,
+        mac: bool,
+    ) -> Result<Self> {
+        let mut new_header = ArchiveHeader::new(0, 0, 0);
+        if let Some(header) = header {
+            new_header.flags = header.flags;
+            new_header.created_timestamp = header.created_timestamp;
+            new_header.kdf = header.kdf.clone();
+            new_header.base_checksum = header.base_checksum;
+        } else if mac {
+            new_header.flags |= ArchiveHeader::FLAG_MAC;
+        }
+
+        let mut archive_bytes = Vec::new();
+        new_header.write_to(&mut archive_bytes)?;
+
+        // Recipient section is kept as-is so the same identities can open the result
+        if new_header.flags & ArchiveHeader::FLAG_RECIPIENTS != 0 {
+            archive_bytes.extend_from_slice(&bytes[ArchiveHeader::SIZE..data_start]);
+        }
+
+        let data_section_start = archive_bytes.len() as u64;
+
+        Ok(Self {
+            archive_bytes,
+            data_section_start,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Add entry with its stored (compressed, possibly sealed) data
+    pub fn add(
+        &mut self,
+        mut entry: ArchiveIndexEntry,
+        stored: Vec<u8>,
+        key: Option<&ArchiveKey>,
+    ) -> Result<()> {
+        // Tombstones have no data
+        if entry.is_deleted() {
+            self.entries.push(entry);
+            return Ok(());
+        }
+
+        let current_offset = self.archive_bytes.len() as u64 - self.data_section_start;
+        entry.data_offset = current_offset + LocalEntryHeader::size(&entry, key.is_some()) as u64;
+
+        write_local_header(&entry, &mut self.archive_bytes, key)?;
+        self.archive_bytes
+            .write_all(&(stored.len() as u64).to_be_bytes())?;
+        self.archive_bytes.write_all(&stored)?;
+
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write index and end record, returning the complete archive
+    pub fn finish(mut self, keys: EntryKeys, recovery: Option<u32>) -> Result<Vec<u8>> {
+        let index_section_start = self.archive_bytes.len() as u64;
+        let file_count = self.entries.len() as u32;
+
+        let mut index_bytes: Vec<u8> = Vec::new();
+        index_bytes.write_all(&file_count.to_be_bytes())?;
+        for entry in &self.entries {
+            entry.write_to(&mut index_bytes)?;
+        }
+        write_index(&index_bytes, &mut self.archive_bytes, keys.cipher)?;
+        // History of the original archive is not carried over
+        IndexTrailer::new(0, 0).write_to(&mut self.archive_bytes)?;
+
+        let index_length = self.archive_bytes.len() as u64 - index_section_start;
+        let end_record_offset = self.archive_bytes.len();
+        let mut end_record = ArchiveEndRecord::new(index_section_start, index_length);
+        if recovery.is_some() {
+            end_record.recovery_offset = end_record_offset as u64;
+        }
+        end_record.write_to(&mut self.archive_bytes)?;
+
+        self.archive_bytes[8..16].copy_from_slice(&self.data_section_start.to_be_bytes());
+        self.archive_bytes[16..24].copy_from_slice(&index_section_start.to_be_bytes());
+        self.archive_bytes[24..28].copy_from_slice(&file_count.to_be_bytes());
+        self.archive_bytes[ArchiveHeader::RECOVERY_OFFSET..ArchiveHeader::RECOVERY_OFFSET + 8]
+            .copy_from_slice(&end_record.recovery_offset.to_be_bytes());
+
+        write_archive_checksum(&mut self.archive_bytes, end_record_offset, keys.mac);
+
+        if let Some(percent) = recovery {
+            let section = build_section(&self.archive_bytes[..end_record_offset], percent)?;
+            self.archive_bytes
+                .splice(end_record_offset..end_record_offset, section);
+        }
+
+        Ok(self.archive_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    use crate::archive::EntryMetadata;
+    use crate::commands::delete::purge_entries;
+    use crate::models::archive::CompressionAlgorithm;
+    use crate::progress::Silent;
+    use crate::reader::ArchiveReader;
+    use crate::writer::ArchiveWriter;
+
+    const SECRET: &[u8] = b"secret contents of the deleted entry";
+
+    /// Archive of stored (uncompressed) secret.txt and kept.txt
+    fn archive(dir: &TempDir) -> String {
+        let path = dir.path().join("a.dar").to_string_lossy().to_string();
+        let entry_metadata = EntryMetadata {
+            compression: Some(CompressionAlgorithm::None),
+            ..EntryMetadata::default()
+        };
+        let mut writer = ArchiveWriter::builder().build().unwrap();
+        writer
+            .add_bytes("secret.txt", SECRET, entry_metadata)
+            .unwrap();
+        writer
+            .add_bytes("kept.txt", b"kept", entry_metadata)
+            .unwrap();
+        std::fs::write(&path, writer.finish().unwrap()).unwrap();
+        path
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn open(path: &str) -> ArchiveReader {
+        ArchiveReader::open(path, "test", &AccessOptions::default()).unwrap()
+    }
+
+    /// Open archive and drop secret.txt from its entries
+    fn without_secret(path: &str) -> (ArchiveAppender, Vec<ArchiveIndexEntry>) {
+        let mut appender = ArchiveAppender::open(path, "test", &AccessOptions::default()).unwrap();
+        let kept = std::mem::take(&mut appender.entries)
+            .into_iter()
+            .filter(|entry| entry.path != "secret.txt")
+            .collect();
+        (appender, kept)
+    }
+
+    #[test]
+    fn delete_keeps_data_until_purged() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let (appender, kept) = without_secret(&path);
+        appender.commit(&kept).unwrap();
+
+        assert!(open(&path).find("secret.txt").is_none());
+        assert!(contains(&std::fs::read(&path).unwrap(), SECRET));
+    }
+
+    #[test]
+    fn purge_removes_data_of_deleted_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let (appender, kept) = without_secret(&path);
+        purge_entries(&path, appender, kept).unwrap();
+
+        assert!(!contains(&std::fs::read(&path).unwrap(), SECRET));
+        let reader = open(&path);
+        assert_eq!(reader.entries().len(), 1);
+        let kept = reader.find("kept.txt").unwrap();
+        assert_eq!(reader.open_entry(kept).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn interrupted_append_falls_back_to_previous_index() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+        let original = std::fs::read(&path).unwrap();
+
+        let added = dir.path().join("added.txt");
+        std::fs::write(&added, b"added").unwrap();
+        let mut appender = ArchiveAppender::open(&path, "test", &AccessOptions::default()).unwrap();
+        let mut entries = std::mem::take(&mut appender.entries);
+        entries.push(appender.add_file(&added, dir.path(), &mut Silent).unwrap());
+        appender.commit(&entries).unwrap();
+        let appended = std::fs::read(&path).unwrap();
+
+        // Crash while writing the tail: header not moved yet, end record cut off
+        let mut interrupted = original.clone();
+        interrupted.extend_from_slice(&appended[original.len()..appended.len() - 10]);
+        std::fs::write(&path, &interrupted).unwrap();
+
+        let reader = open(&path);
+        assert!(reader.is_interrupted());
+        assert_eq!(reader.entries().len(), 2);
+        assert!(reader.find("added.txt").is_none());
+        drop(reader);
+
+        assert!(matches!(
+            ArchiveAppender::open(&path, "test", &AccessOptions::default()),
+            Err(Error::Inconsistent(_))
+        ));
+    }
+
+    #[test]
+    fn completed_append_is_read() {
+        let dir = TempDir::new().unwrap();
+        let path = archive(&dir);
+
+        let added = dir.path().join("added.txt");
+        std::fs::write(&added, b"added").unwrap();
+        let mut appender = ArchiveAppender::open(&path, "test", &AccessOptions::default()).unwrap();
+        let mut entries = std::mem::take(&mut appender.entries);
+        entries.push(appender.add_file(&added, dir.path(), &mut Silent).unwrap());
+        appender.commit(&entries).unwrap();
+
+        let reader = open(&path);
+        assert!(!reader.is_interrupted());
+        let entry = reader.find("added.txt").unwrap();
+        assert_eq!(reader.open_entry(entry).unwrap(), b"added");
+    }
+}
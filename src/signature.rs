@@ -0,0 +1,185 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::crypto::{public_key_bytes, secret_key_bytes};
+use crate::error::{Error, Result};
+use crate::models::archive::SignatureBlock;
+
+/// Domain separation prefix of the signed message (followed by the archive checksum)
+const SIGNATURE_CONTEXT: &[u8] = b"DAR archive signature v1\0";
+
+/// Generate new Ed25519 signing key
+pub fn new_signing_key() -> SigningKey {
+    SigningKey::generate(&mut chacha20poly1305::aead::OsRng)
+}
+
+/// Read Ed25519 signing key from key file
+pub fn read_signing_key(path: &str) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&secret_key_bytes(
+        path,
+        "signing key",
+    )?))
+}
+
+/// Parse verifying key given as hex or as path to a key file
+pub fn parse_verifying_key(value: &str) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&public_key_bytes(value)?)
+        .map_err(|e| Error::Signature(format!("Invalid Ed25519 public key: {}", e)))
+}
+
+/// Sign archive checksum
+pub fn sign_checksum(key: &SigningKey, checksum: &[u8; 32]) -> SignatureBlock {
+    let signature = key.sign(&signed_message(checksum));
+
+    SignatureBlock {
+        public_key: key.verifying_key().to_bytes(),
+        signature: signature.to_bytes(),
+    }
+}
+
+/// Verify signature block against trusted key and archive checksum
+pub fn verify_signature(
+    block: &SignatureBlock,
+    key: &VerifyingKey,
+    checksum: &[u8; 32],
+) -> Result<()> {
+    if block.public_key != key.to_bytes() {
+        return Err(Error::Signature(format!(
+            "Archive is signed by a different key ({})",
+            hex::encode(block.public_key)
+        )));
+    }
+
+    let signature = Signature::from_bytes(&block.signature);
+    key.verify(&signed_message(checksum), &signature)
+        .map_err(|_| Error::Signature("Signature does not match archive contents".to_string()))
+}
+
+/// Path of the detached signature for an archive
+pub fn sidecar_path(archive_path: &str) -> String {
+    format!("{}.sig", archive_path)
+}
+
+/// Locate signature: embedded block first, then `.sig` sidecar
+pub fn find_signature<R: Read + Seek>(file: &mut R, archive_path: &str) -> Result<SignatureBlock> {
+    let mut buf = [0u8; SignatureBlock::SIZE];
+    file.seek(SeekFrom::Start(SignatureBlock::OFFSET as u64))?;
+    file.read_exact(&mut buf)?;
+
+    if let Some(block) = SignatureBlock::from_bytes(&buf) {
+        return Ok(block);
+    }
+
+    let sidecar = sidecar_path(archive_path);
+    match std::fs::read(&sidecar) {
+        Ok(content) => SignatureBlock::from_bytes(&content)
+            .ok_or_else(|| Error::Signature(format!("Invalid signature file {}", sidecar))),
+        Err(_) => Err(Error::Signature(format!(
+            "Archive is not signed (no embedded signature or {})",
+            sidecar
+        ))),
+    }
+}
+
+fn signed_message(checksum: &[u8; 32]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNATURE_CONTEXT.len() + checksum.len());
+    message.extend_from_slice(SIGNATURE_CONTEXT);
+    message.extend_from_slice(checksum);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::archive::{EntryMetadata, calculate_archive_checksum, read_header};
+    use crate::models::archive::CompressionAlgorithm;
+    use crate::writer::ArchiveWriter;
+
+    fn is_signature_error(result: Result<()>) -> bool {
+        matches!(result, Err(Error::Signature(_)))
+    }
+
+    fn signed_archive(key: &SigningKey) -> Vec<u8> {
+        let mut writer = ArchiveWriter::builder()
+            .signing_key(key.clone())
+            .build()
+            .unwrap();
+        let entry_metadata = EntryMetadata {
+            modification_time: 0,
+            uid: 0,
+            gid: 0,
+            permissions: 0o644,
+            compression: Some(CompressionAlgorithm::None),
+        };
+        writer
+            .add_bytes("a.txt", b"signed contents", entry_metadata)
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Verify embedded signature against the checksum recomputed from `bytes`
+    fn verify_archive(bytes: &[u8], key: &VerifyingKey) -> Result<()> {
+        let mut file = Cursor::new(bytes);
+        let (header, header_result) = read_header(&mut file);
+        header_result?;
+        let checksum =
+            calculate_archive_checksum(&mut file, &header.unwrap(), bytes.len() as u64, None)?;
+        let block = find_signature(&mut file, "unsigned.dar")?;
+        verify_signature(&block, key, &checksum)
+    }
+
+    #[test]
+    fn signature_matches_its_checksum() {
+        let key = new_signing_key();
+        let checksum = [3u8; 32];
+        let block = sign_checksum(&key, &checksum);
+        assert!(verify_signature(&block, &key.verifying_key(), &checksum).is_ok());
+    }
+
+    #[test]
+    fn flipped_checksum_byte_is_rejected() {
+        let key = new_signing_key();
+        let mut checksum = [3u8; 32];
+        let block = sign_checksum(&key, &checksum);
+
+        checksum[31] ^= 1;
+        assert!(is_signature_error(verify_signature(
+            &block,
+            &key.verifying_key(),
+            &checksum
+        )));
+    }
+
+    #[test]
+    fn signature_by_another_key_is_rejected() {
+        let checksum = [3u8; 32];
+        let block = sign_checksum(&new_signing_key(), &checksum);
+        assert!(is_signature_error(verify_signature(
+            &block,
+            &new_signing_key().verifying_key(),
+            &checksum
+        )));
+    }
+
+    #[test]
+    fn signed_archive_verifies() {
+        let key = new_signing_key();
+        let bytes = signed_archive(&key);
+        assert!(verify_archive(&bytes, &key.verifying_key()).is_ok());
+    }
+
+    #[test]
+    fn modified_signed_archive_is_rejected() {
+        let key = new_signing_key();
+        let mut bytes = signed_archive(&key);
+        let (header, _) = read_header(&mut Cursor::new(&bytes));
+        bytes[header.unwrap().data_section_start as usize] ^= 1;
+
+        assert!(is_signature_error(verify_archive(
+            &bytes,
+            &key.verifying_key()
+        )));
+    }
+}
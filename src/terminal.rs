@@ -1,6 +1,6 @@
-use std::io::prelude::*;
-
-use term;
+use chrono::{Local, TimeZone};
+use std::io::{IsTerminal, prelude::*};
+use terminal_size::{Width, terminal_size};
 
 pub fn success(message: &str) {
     match term::stdout() {
@@ -18,3 +18,31 @@ pub fn success(message: &str) {
         }
     }
 }
+
+/// Width of the terminal stdout is connected to, None when it is not a terminal
+pub fn width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+
+    if let Some((Width(columns), _)) = terminal_size()
+        && columns > 0
+    {
+        return Some(columns as usize);
+    }
+
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .or(Some(80))
+}
+
+/// Timestamp as "dd.mm.yyyy hh:mm" in the local time zone
+pub fn local_time(timestamp: u64) -> String {
+    // Timestamps come from the archive, a damaged one may be out of range
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|seconds| Local.timestamp_opt(seconds, 0).single())
+        .map(|time| time.format("%d.%m.%Y %H:%M").to_string())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
@@ -1,5 +1,72 @@
+use eyre::{Result, eyre};
+use ignore::WalkBuilder;
+use std::fs::{canonicalize, metadata};
 use std::path::{Component, Path, PathBuf};
 
+/// Files to archive for given inputs, each with the root its archive path is relative to
+/// Directories are walked respecting .gitignore
+pub fn collect_files<'a>(
+    content: impl IntoIterator<Item = &'a String>,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new(); // (file, root)
+    for item in content {
+        let relative_path = Path::new(item);
+        let absolute_path = canonicalize(relative_path)
+            .map_err(|e| eyre!("Couldn't get absolute path for {:?}: {}", relative_path, e))?;
+
+        if absolute_path.is_dir() {
+            let walker = WalkBuilder::new(&absolute_path)
+                .git_ignore(true)
+                .hidden(false)
+                .build();
+            for entry in walker {
+                let entry = entry?;
+                if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                    files.push((entry.path().to_path_buf(), absolute_path.clone()));
+                }
+            }
+        } else if absolute_path.is_file() {
+            // A single file is stored under its name
+            let root = absolute_path
+                .parent()
+                .unwrap_or(Path::new("/"))
+                .to_path_buf();
+            files.push((absolute_path, root));
+        } else {
+            eprintln!("Skipping (not file/dir): {:?}", absolute_path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Total size of collected files, for progress reporting
+pub fn total_size(files: &[(PathBuf, PathBuf)]) -> u64 {
+    files
+        .iter()
+        .filter_map(|(path, _)| metadata(path).ok())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+/// Size in B, KB, MB or GB
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit_idx = 0;
+
+    while size >= 1024.0 && unit_idx < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit_idx += 1;
+    }
+
+    if unit_idx == 0 {
+        format!("{}{}", bytes, UNITS[unit_idx])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit_idx])
+    }
+}
+
 pub fn calculate_archive_path(file_path: &Path, dir_root: &Path) -> String {
     // Calculate relative path from directory root
     let relative = file_path.strip_prefix(dir_root).unwrap_or(file_path);
@@ -1,5 +1,7 @@
+use crate::output::Report;
 use crate::pager::PagerWriter;
-use eyre::Result;
+use serde_json::{Value, json};
+use std::fmt::Display;
 use std::io::Write;
 
 #[derive(Debug, Clone, Copy)]
@@ -21,6 +23,8 @@ pub struct ValidationContext {
     checks_failed: u32,
     pub errors: Vec<String>,
     output: Option<PagerWriter>,
+    /// Machine-readable record of each check, replacing the text output
+    report: Option<Report>,
 }
 
 impl ValidationContext {
@@ -32,27 +36,48 @@ impl ValidationContext {
             checks_failed: 0,
             errors: Vec::new(),
             output: Some(output),
+            report: None,
         }
     }
 
-    pub fn check(&mut self, name: &str, result: Result<()>) {
+    /// Context recording checks in `report` instead of printing them
+    pub fn with_report(file_size: u64, report: Report) -> Self {
+        Self {
+            verbose: false,
+            _file_size: file_size,
+            checks_passed: 0,
+            checks_failed: 0,
+            errors: Vec::new(),
+            output: None,
+            report: Some(report),
+        }
+    }
+
+    pub fn check<E: Display>(&mut self, name: &str, result: Result<(), E>) {
+        if let Some(ref mut report) = self.report {
+            report.item(json!({
+                "name": name,
+                "passed": result.is_ok(),
+                "error": result.as_ref().err().map(|e| e.to_string()),
+            }));
+        }
         match result {
             Ok(()) => {
                 self.checks_passed += 1;
-                if self.verbose {
-                    if let Some(ref mut out) = self.output {
-                        let _ = writeln!(out, "  ✓ {}", name);
-                    }
+                if self.verbose
+                    && let Some(ref mut out) = self.output
+                {
+                    let _ = writeln!(out, "  ✓ {}", name);
                 }
             }
             Err(e) => {
                 self.checks_failed += 1;
                 let msg = format!("{}: {}", name, e);
                 self.errors.push(msg.clone());
-                if self.verbose {
-                    if let Some(ref mut out) = self.output {
-                        let _ = writeln!(out, "  ✗ {}", msg);
-                    }
+                if self.verbose
+                    && let Some(ref mut out) = self.output
+                {
+                    let _ = writeln!(out, "  ✗ {}", msg);
                 }
             }
         }
@@ -69,6 +94,17 @@ impl ValidationContext {
         self.checks_failed == 0
     }
 
+    /// Print the report with counts of passed and failed checks and `summary`
+    pub fn finish_report(&mut self, summary: Value) {
+        if let Some(ref mut report) = self.report {
+            let mut summary = summary;
+            summary["passed"] = json!(self.checks_passed);
+            summary["failed"] = json!(self.checks_failed);
+            summary["valid"] = json!(self.checks_failed == 0);
+            report.finish(summary);
+        }
+    }
+
     pub fn writeln(&mut self, args: std::fmt::Arguments) -> std::io::Result<()> {
         if let Some(ref mut out) = self.output {
             writeln!(out, "{}", args)
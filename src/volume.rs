@@ -0,0 +1,424 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::crypto::new_file_key;
+use crate::error::{Error, Result, io_context};
+use crate::models::archive::VolumeHeader;
+
+/// Archive opened for reading: a single file or a set of volumes
+/// Both present the archive as one seekable byte stream
+pub enum ArchiveSource {
+    File(File),
+    Volumes(VolumeSet),
+}
+
+impl ArchiveSource {
+    /// Open archive by path of a single-file archive, of any volume, or of the
+    /// set's base name (`name.dar` for `name.dar.001`...)
+    pub fn open(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            let first = volume_path(path, 1);
+            if Path::new(&first).exists() {
+                return Ok(Self::Volumes(VolumeSet::open(&first)?));
+            }
+            return Err(Error::Volume(format!("Archive file not found: {}", path)));
+        }
+
+        let mut file = File::open(path)
+            .map_err(|e| io_context(e, format!("Failed to open archive {}", path)))?;
+        let mut magic = [0u8; 4];
+        let is_volume = file.read_exact(&mut magic).is_ok() && magic == VolumeHeader::MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+
+        if is_volume {
+            Ok(Self::Volumes(VolumeSet::open(path)?))
+        } else {
+            Ok(Self::File(file))
+        }
+    }
+
+    /// Logical size of the archive
+    pub fn size(&self) -> Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            Self::Volumes(set) => Ok(set.size),
+        }
+    }
+}
+
+impl Read for ArchiveSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.read(buf),
+            Self::Volumes(set) => set.read(buf),
+        }
+    }
+}
+
+impl Seek for ArchiveSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Volumes(set) => set.seek(pos),
+        }
+    }
+}
+
+struct Volume {
+    file: File,
+    offset: u64,
+    length: u64,
+}
+
+/// Complete, verified set of volumes read as one stream
+pub struct VolumeSet {
+    volumes: Vec<Volume>,
+    position: u64,
+    size: u64,
+}
+
+impl VolumeSet {
+    /// Open all volumes of the set the given volume belongs to
+    pub fn open(path: &str) -> Result<Self> {
+        let base = volume_base(path).ok_or_else(|| {
+            Error::Volume(format!("Volume {} must end with its volume number", path))
+        })?;
+        let given = read_volume_header(path)?;
+
+        let mut volumes = Vec::new();
+        let mut offset = 0u64;
+
+        for number in 1..=given.count {
+            let volume = volume_path(base, number);
+            if !Path::new(&volume).exists() {
+                return Err(Error::Volume(format!(
+                    "Missing volume {} (volume {} of {})",
+                    volume, number, given.count
+                )));
+            }
+
+            let header = read_volume_header(&volume)?;
+            if header.set_id != given.set_id {
+                return Err(Error::Volume(format!(
+                    "Volume {} belongs to a different archive than {}",
+                    volume, path
+                )));
+            }
+            if header.number != number || header.count != given.count {
+                return Err(Error::Volume(format!(
+                    "Volume {} is volume {} of {}, expected {} of {}",
+                    volume, header.number, header.count, number, given.count
+                )));
+            }
+            if header.offset != offset {
+                return Err(Error::Volume(format!(
+                    "Volume {} does not continue previous volume",
+                    volume
+                )));
+            }
+
+            // Lengths come from the volume headers, their sums must not overflow
+            let (Some(volume_size), Some(next_offset)) = (
+                (VolumeHeader::SIZE as u64).checked_add(header.length),
+                offset.checked_add(header.length),
+            ) else {
+                return Err(Error::Volume(format!("{} is not a valid volume", volume)));
+            };
+
+            let file = File::open(&volume)?;
+            if file.metadata()?.len() != volume_size {
+                return Err(Error::Volume(format!(
+                    "Volume {} is truncated or has trailing data",
+                    volume
+                )));
+            }
+
+            volumes.push(Volume {
+                file,
+                offset,
+                length: header.length,
+            });
+            offset = next_offset;
+        }
+
+        Ok(Self {
+            volumes,
+            position: 0,
+            size: offset,
+        })
+    }
+}
+
+impl Read for VolumeSet {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.position;
+        let Some(volume) = self
+            .volumes
+            .iter_mut()
+            .find(|v| position >= v.offset && position < v.offset + v.length)
+        else {
+            return Ok(0); // end of archive
+        };
+
+        let within = position - volume.offset;
+        let available = (volume.length - within).min(buf.len() as u64) as usize;
+        volume
+            .file
+            .seek(SeekFrom::Start(VolumeHeader::SIZE as u64 + within))?;
+        let n = volume.file.read(&mut buf[..available])?;
+
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for VolumeSet {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.size.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Split archive into volumes of at most `volume_size` bytes each (header included)
+/// Returns paths of the written volumes
+pub fn write_volumes(path: &str, archive_bytes: &[u8], volume_size: u64) -> Result<Vec<String>> {
+    let payload_size = match volume_size.checked_sub(VolumeHeader::SIZE as u64) {
+        Some(payload_size) if payload_size > 0 => payload_size as usize,
+        _ => {
+            return Err(Error::Volume(format!(
+                "Volume size {} leaves no room after the {}-byte volume header",
+                volume_size,
+                VolumeHeader::SIZE
+            )));
+        }
+    };
+    let count = archive_bytes.len().div_ceil(payload_size);
+    if count > 999 {
+        return Err(Error::Volume(format!(
+            "Archive would need {} volumes, at most 999 are supported",
+            count
+        )));
+    }
+
+    let mut set_id = [0u8; 16];
+    set_id.copy_from_slice(&new_file_key()[..16]);
+
+    let mut paths = Vec::new();
+    for (i, payload) in archive_bytes.chunks(payload_size).enumerate() {
+        let header = VolumeHeader {
+            set_id,
+            number: i as u32 + 1,
+            count: count as u32,
+            offset: (i * payload_size) as u64,
+            length: payload.len() as u64,
+        };
+
+        let mut header_bytes = Vec::new();
+        header.write_to(&mut header_bytes)?;
+
+        let volume = volume_path(path, header.number);
+        if let Err(error) = write_volume(&volume, &header_bytes, payload) {
+            // A partial set is of no use, and would block the next attempt
+            for written in &paths {
+                let _ = std::fs::remove_file(written);
+            }
+            return Err(error);
+        }
+        paths.push(volume);
+    }
+
+    Ok(paths)
+}
+
+/// Write one volume, never replacing an existing file
+fn write_volume(volume: &str, header_bytes: &[u8], payload: &[u8]) -> Result<()> {
+    let mut volume_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(volume)
+        .map_err(|e| io_context(e, format!("Failed to create volume {}", volume)))?;
+    volume_file.write_all(header_bytes)?;
+    volume_file.write_all(payload)?;
+    volume_file.flush()?;
+    Ok(())
+}
+
+/// First existing volume of archive `base`, such as one left over from an
+/// earlier set with more volumes
+pub fn existing_volume(base: &str) -> Option<String> {
+    (1..=999)
+        .map(|number| volume_path(base, number))
+        .find(|volume| Path::new(volume).exists())
+}
+
+/// First volume of the set that `path` names, by base name or by any of its
+/// volumes; None for single-file archives
+pub fn first_volume(path: &str) -> Option<String> {
+    if !Path::new(path).exists() {
+        return Some(volume_path(path, 1));
+    }
+    let base = volume_base(path)?;
+    read_volume_header(path)
+        .is_ok()
+        .then(|| volume_path(base, 1))
+}
+
+/// Path of volume `number` (1-based) of archive `base`
+pub fn volume_path(base: &str, number: u32) -> String {
+    format!("{}.{:03}", base, number)
+}
+
+/// Parse volume size like 4G, 500M, 64K or plain bytes
+pub fn parse_volume_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M') => (&value[..value.len() - 1], 1u64 << 20),
+        Some('G') => (&value[..value.len() - 1], 1u64 << 30),
+        Some('T') => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+
+    let size = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| {
+            Error::Volume(format!(
+                "Invalid volume size {}, expected e.g. 4G, 500M",
+                value
+            ))
+        })?;
+
+    if size < 1024 {
+        return Err(Error::Volume("Volume size must be at least 1K".to_string()));
+    }
+
+    Ok(size)
+}
+
+fn volume_base(path: &str) -> Option<&str> {
+    let (base, number) = path.rsplit_once('.')?;
+    (number.len() >= 3 && number.chars().all(|c| c.is_ascii_digit())).then_some(base)
+}
+
+fn read_volume_header(path: &str) -> Result<VolumeHeader> {
+    let mut file =
+        File::open(path).map_err(|e| io_context(e, format!("Failed to open volume {}", path)))?;
+    let mut buf = [0u8; VolumeHeader::SIZE];
+    file.read_exact(&mut buf)
+        .map_err(|_| Error::Volume(format!("Volume {} is too small to be a volume", path)))?;
+    VolumeHeader::from_bytes(&buf)
+        .ok_or_else(|| Error::Volume(format!("{} is not an archive volume", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Archive bytes split into three volumes of 100 payload bytes, returns the base path
+    fn volume_set(dir: &TempDir, name: &str, bytes: &[u8]) -> String {
+        let base = dir.path().join(name).to_string_lossy().to_string();
+        let paths = write_volumes(&base, bytes, VolumeHeader::SIZE as u64 + 100).unwrap();
+        assert_eq!(paths.len(), 3);
+        base
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..250u32).map(|i| i as u8).collect()
+    }
+
+    fn is_volume_error(result: Result<ArchiveSource>) -> bool {
+        matches!(result, Err(Error::Volume(_)))
+    }
+
+    #[test]
+    fn volumes_read_back_as_one_stream() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+
+        for path in [base.clone(), volume_path(&base, 2)] {
+            let mut source = ArchiveSource::open(&path).unwrap();
+            let mut bytes = Vec::new();
+            source.read_to_end(&mut bytes).unwrap();
+            assert_eq!(bytes, contents());
+        }
+    }
+
+    #[test]
+    fn missing_volume_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+        std::fs::remove_file(volume_path(&base, 2)).unwrap();
+
+        assert!(is_volume_error(ArchiveSource::open(&base)));
+    }
+
+    #[test]
+    fn reordered_volumes_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+        let swap = dir.path().join("swap").to_string_lossy().to_string();
+        std::fs::rename(volume_path(&base, 1), &swap).unwrap();
+        std::fs::rename(volume_path(&base, 2), volume_path(&base, 1)).unwrap();
+        std::fs::rename(&swap, volume_path(&base, 2)).unwrap();
+
+        assert!(is_volume_error(ArchiveSource::open(&base)));
+    }
+
+    #[test]
+    fn volume_of_another_archive_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+        let other = volume_set(&dir, "b.dar", &contents());
+        std::fs::copy(volume_path(&other, 2), volume_path(&base, 2)).unwrap();
+
+        assert!(is_volume_error(ArchiveSource::open(&base)));
+    }
+
+    #[test]
+    fn truncated_volume_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+        let volume = OpenOptions::new()
+            .write(true)
+            .open(volume_path(&base, 2))
+            .unwrap();
+        volume.set_len(VolumeHeader::SIZE as u64 + 50).unwrap();
+
+        assert!(is_volume_error(ArchiveSource::open(&base)));
+    }
+
+    #[test]
+    fn volume_size_must_leave_room_for_data() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("a.dar").to_string_lossy().to_string();
+
+        let result = write_volumes(&base, &contents(), VolumeHeader::SIZE as u64);
+        assert!(matches!(result, Err(Error::Volume(_))));
+        assert!(existing_volume(&base).is_none());
+    }
+
+    #[test]
+    fn any_volume_names_the_first_one() {
+        let dir = TempDir::new().unwrap();
+        let base = volume_set(&dir, "a.dar", &contents());
+
+        for path in [base.clone(), volume_path(&base, 3)] {
+            assert_eq!(first_volume(&path), Some(volume_path(&base, 1)));
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use ed25519_dalek::SigningKey;
+use std::io::{Read, Write};
+use std::path::Path;
+use x25519_dalek::PublicKey;
+
+use crate::archive::{
+    EntryKeys, EntryMetadata, encode_entry, write_archive_checksum, write_entry, write_index,
+};
+use crate::crypto::{ArchiveKey, new_file_key, new_kdf_params, wrap_file_key};
+use crate::error::{Error, Result, io_context};
+use crate::models::archive::{
+    ArchiveEndRecord, ArchiveHeader, ArchiveIndexEntry, CompressionAlgorithm, IndexTrailer,
+    RecipientStanza, SignatureBlock,
+};
+use crate::progress::{Event, ProgressSink, Silent};
+use crate::recovery::build_section;
+use crate::signature::sign_checksum;
+
+/// Settings of a new archive, see `ArchiveWriter::builder`
+#[derive(Default)]
+pub struct ArchiveWriterBuilder {
+    passphrase: Option<String>,
+    recipients: Vec<PublicKey>,
+    mac_key: Option<[u8; 32]>,
+    base_checksum: Option<[u8; 32]>,
+    recovery: Option<u32>,
+    signing_key: Option<SigningKey>,
+    sink: Option<Box<dyn ProgressSink>>,
+}
+
+impl ArchiveWriterBuilder {
+    /// Encrypt with a key derived from a passphrase
+    pub fn passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    /// Encrypt to X25519 recipients (not together with a passphrase)
+    pub fn recipients(mut self, recipients: Vec<PublicKey>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Key archive and entry checksums (BLAKE3 keyed MACs)
+    pub fn mac_key(mut self, mac_key: [u8; 32]) -> Self {
+        self.mac_key = Some(mac_key);
+        self
+    }
+
+    /// Make an incremental archive of the archive with this checksum
+    pub fn base_checksum(mut self, checksum: [u8; 32]) -> Self {
+        self.base_checksum = Some(checksum);
+        self
+    }
+
+    /// Add a recovery record of `percent` (1-100) of the archive size
+    pub fn recovery(mut self, percent: u32) -> Self {
+        self.recovery = Some(percent);
+        self
+    }
+
+    /// Embed an Ed25519 signature of the archive checksum
+    pub fn signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Report added files and reading progress to `sink` (silent by default)
+    pub fn progress_sink(mut self, sink: Box<dyn ProgressSink>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    pub fn build(self) -> Result<ArchiveWriter> {
+        if self
+            .recovery
+            .is_some_and(|percent| !(1..=100).contains(&percent))
+        {
+            return Err(Error::Recovery(
+                "Recovery size must be between 1% and 100%".to_string(),
+            ));
+        }
+
+        if !self.recipients.is_empty() && self.passphrase.is_some() {
+            return Err(Error::Key(
+                "Encrypt either to recipients or with a passphrase, not both".to_string(),
+            ));
+        }
+
+        let mut header = ArchiveHeader::new(0, 0, 0);
+        let mut stanzas = Vec::new();
+
+        let key = if !self.recipients.is_empty() {
+            // Random file key, readable only by holders of a recipient identity
+            let file_key = new_file_key();
+            for recipient in &self.recipients {
+                stanzas.push(wrap_file_key(&file_key, recipient)?);
+            }
+            header.flags |= ArchiveHeader::FLAG_ENCRYPTED | ArchiveHeader::FLAG_RECIPIENTS;
+            Some(ArchiveKey::from_bytes(&file_key))
+        } else if let Some(passphrase) = &self.passphrase {
+            let kdf = new_kdf_params();
+            let key = ArchiveKey::derive(passphrase, &kdf)?;
+            header.flags |= ArchiveHeader::FLAG_ENCRYPTED;
+            header.kdf = Some(kdf);
+            Some(key)
+        } else {
+            None
+        };
+
+        if self.mac_key.is_some() {
+            header.flags |= ArchiveHeader::FLAG_MAC;
+        }
+        if let Some(checksum) = self.base_checksum {
+            header.flags |= ArchiveHeader::FLAG_INCREMENTAL;
+            header.base_checksum = Some(checksum);
+        }
+
+        Ok(ArchiveWriter {
+            header,
+            stanzas,
+            key,
+            mac_key: self.mac_key,
+            signing_key: self.signing_key,
+            recovery: self.recovery,
+            sink: self.sink.unwrap_or_else(|| Box::new(Silent)),
+            data: Vec::new(),
+            entries: Vec::new(),
+        })
+    }
+}
+
+/// Builds a new archive in memory: add entries, then `finish` writes index,
+/// end record, checksums and the optional signature and recovery record
+pub struct ArchiveWriter {
+    header: ArchiveHeader,
+    stanzas: Vec<RecipientStanza>,
+    key: Option<ArchiveKey>,
+    mac_key: Option<[u8; 32]>,
+    signing_key: Option<SigningKey>,
+    recovery: Option<u32>,
+    sink: Box<dyn ProgressSink>,
+    /// Data section, entry offsets are relative to its start
+    data: Vec<u8>,
+    entries: Vec<ArchiveIndexEntry>,
+}
+
+impl ArchiveWriter {
+    pub fn builder() -> ArchiveWriterBuilder {
+        ArchiveWriterBuilder::default()
+    }
+
+    /// Add file on disk as entry `name`, with its metadata, compressed with
+    /// `compression` or an algorithm picked by its extension if None
+    pub fn add_path(
+        &mut self,
+        path: &Path,
+        name: &str,
+        compression: Option<CompressionAlgorithm>,
+    ) -> Result<()> {
+        let current_offset = self.data.len() as u64;
+        let keys = EntryKeys {
+            cipher: self.key.as_ref(),
+            mac: self.mac_key.as_ref(),
+        };
+        let entry = write_entry(
+            path,
+            name,
+            &mut self.data,
+            current_offset,
+            keys,
+            compression,
+            self.sink.as_mut(),
+        )?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Add everything `reader` yields as entry `name`
+    pub fn add_reader<R: Read>(
+        &mut self,
+        name: &str,
+        mut reader: R,
+        entry_metadata: EntryMetadata,
+    ) -> Result<()> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| io_context(e, format!("Failed to read data of {}", name)))?;
+        self.add_bytes(name, &data, entry_metadata)
+    }
+
+    /// Add `data` as entry `name`
+    pub fn add_bytes(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        entry_metadata: EntryMetadata,
+    ) -> Result<()> {
+        let current_offset = self.data.len() as u64;
+        let keys = EntryKeys {
+            cipher: self.key.as_ref(),
+            mac: self.mac_key.as_ref(),
+        };
+        self.sink.event(&Event::FileStarted {
+            name,
+            source: None,
+            size: data.len() as u64,
+        });
+        let entry = match encode_entry(
+            name,
+            data,
+            &entry_metadata,
+            &mut self.data,
+            current_offset,
+            keys,
+        ) {
+            Ok(entry) => entry,
+            Err(error) => {
+                self.sink.event(&Event::Error {
+                    name,
+                    error: &error,
+                });
+                return Err(error);
+            }
+        };
+        self.sink.event(&Event::FileFinished {
+            name,
+            source: None,
+            size: entry.uncompressed_size,
+            stored_size: entry.compressed_size,
+            algorithm: entry.compression_algorithm,
+        });
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Report that `name` of `size` bytes was left out, unchanged since the base archive
+    pub fn skip(&mut self, name: &str, size: u64) {
+        self.sink.event(&Event::Skipped { name, size });
+    }
+
+    /// Record that `name` was deleted since the base archive (incremental archives)
+    pub fn add_tombstone(&mut self, name: &str) {
+        self.entries
+            .push(ArchiveIndexEntry::tombstone(name.to_string()));
+    }
+
+    /// Entries added so far
+    pub fn entries(&self) -> &[ArchiveIndexEntry] {
+        &self.entries
+    }
+
+    /// Write header, data, index and end record, returning the complete archive
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut archive_bytes: Vec<u8> = Vec::new();
+        self.header.write_to(&mut archive_bytes)?;
+
+        // Recipient section sits between header and data
+        if !self.stanzas.is_empty() {
+            RecipientStanza::write_section(&self.stanzas, &mut archive_bytes)?;
+        }
+
+        let data_section_start = archive_bytes.len() as u64;
+        archive_bytes.append(&mut self.data);
+
+        let index_section_start = archive_bytes.len() as u64;
+        let file_count = self.entries.len() as u32;
+
+        let mut index_bytes: Vec<u8> = Vec::new();
+        index_bytes.write_all(&file_count.to_be_bytes())?;
+        for entry in &self.entries {
+            entry.write_to(&mut index_bytes)?;
+        }
+        write_index(&index_bytes, &mut archive_bytes, self.key.as_ref())?;
+        // First generation, nothing to link back to
+        IndexTrailer::new(0, 0).write_to(&mut archive_bytes)?;
+
+        let index_length = archive_bytes.len() as u64 - index_section_start;
+        let end_record_offset = archive_bytes.len();
+
+        // Recovery section gets spliced in between index and end record once the checksum is final
+        let mut end_record = ArchiveEndRecord::new(index_section_start, index_length);
+        if self.recovery.is_some() {
+            end_record.recovery_offset = end_record_offset as u64;
+        }
+        end_record.write_to(&mut archive_bytes)?;
+
+        // Offsets and file count go into the header before the checksum is calculated
+        archive_bytes[8..16].copy_from_slice(&data_section_start.to_be_bytes());
+        archive_bytes[16..24].copy_from_slice(&index_section_start.to_be_bytes());
+        archive_bytes[24..28].copy_from_slice(&file_count.to_be_bytes());
+        archive_bytes[ArchiveHeader::RECOVERY_OFFSET..ArchiveHeader::RECOVERY_OFFSET + 8]
+            .copy_from_slice(&end_record.recovery_offset.to_be_bytes());
+
+        let archive_hash =
+            write_archive_checksum(&mut archive_bytes, end_record_offset, self.mac_key.as_ref());
+
+        // Signature covers the final checksum and is excluded from it
+        if let Some(ref key) = self.signing_key {
+            let block = sign_checksum(key, &archive_hash);
+            archive_bytes[SignatureBlock::OFFSET..SignatureBlock::OFFSET + SignatureBlock::SIZE]
+                .copy_from_slice(&block.to_bytes());
+        }
+
+        if let Some(percent) = self.recovery {
+            let section = build_section(&archive_bytes[..end_record_offset], percent)?;
+            archive_bytes.splice(end_record_offset..end_record_offset, section);
+        }
+
+        Ok(archive_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use crate::archive::EntryMetadata;
+    use crate::codec::{Codec, register_codec};
+    use crate::reader::{AccessOptions, ArchiveReader};
+
+    /// Codec flipping every bit, so stored data never equals the input
+    struct Invert(u8);
+
+    impl Invert {
+        fn copy(input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+            let mut data = Vec::new();
+            input.read_to_end(&mut data)?;
+            data.iter_mut().for_each(|byte| *byte = !*byte);
+            output.write_all(&data)
+        }
+    }
+
+    impl Codec for Invert {
+        fn id(&self) -> u8 {
+            self.0
+        }
+
+        fn name(&self) -> &str {
+            "invert"
+        }
+
+        fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+            Self::copy(input, output)
+        }
+
+        fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+            Self::copy(input, output)
+        }
+    }
+
+    const CONTENTS: &[u8] = b"contents of an entry written with a custom codec";
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn custom_codec_round_trips() {
+        register_codec(Arc::new(Invert(200))).unwrap();
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source.txt");
+        std::fs::write(&source, CONTENTS).unwrap();
+
+        let algorithm = CompressionAlgorithm::Custom(200);
+        let mut writer = ArchiveWriter::builder().build().unwrap();
+        writer
+            .add_path(&source, "path.txt", Some(algorithm))
+            .unwrap();
+        let entry_metadata = EntryMetadata {
+            compression: Some(algorithm),
+            ..EntryMetadata::default()
+        };
+        writer
+            .add_bytes("bytes.txt", CONTENTS, entry_metadata)
+            .unwrap();
+        let bytes = writer.finish().unwrap();
+        assert!(!contains(&bytes, CONTENTS));
+
+        let path = dir.path().join("a.dar").to_string_lossy().to_string();
+        std::fs::write(&path, bytes).unwrap();
+        let reader = ArchiveReader::open(&path, "test", &AccessOptions::default()).unwrap();
+        for name in ["path.txt", "bytes.txt"] {
+            let entry = reader.find(name).unwrap();
+            assert_eq!(entry.compression_algorithm, algorithm);
+            assert_eq!(reader.open_entry(entry).unwrap(), CONTENTS);
+        }
+    }
+
+    #[test]
+    fn codec_id_is_taken_once() {
+        register_codec(Arc::new(Invert(201))).unwrap();
+        assert!(matches!(
+            register_codec(Arc::new(Invert(201))),
+            Err(Error::CodecIdTaken { id: 201, .. })
+        ));
+        assert!(matches!(
+            register_codec(Arc::new(Invert(2))),
+            Err(Error::CodecIdTaken { id: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn unregistered_codec_is_unavailable() {
+        let entry_metadata = EntryMetadata {
+            compression: Some(CompressionAlgorithm::Custom(202)),
+            ..EntryMetadata::default()
+        };
+        let mut writer = ArchiveWriter::builder().build().unwrap();
+        assert!(matches!(
+            writer.add_bytes("a.txt", CONTENTS, entry_metadata),
+            Err(Error::CodecUnavailable { id: 202 })
+        ));
+    }
+
+    #[test]
+    fn recipients_and_passphrase_are_exclusive() {
+        let recipient = PublicKey::from(&crate::crypto::new_identity());
+        let result = ArchiveWriter::builder()
+            .passphrase("secret")
+            .recipients(vec![recipient])
+            .build();
+        assert!(matches!(result, Err(Error::Key(_))));
+    }
+}